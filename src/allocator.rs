@@ -0,0 +1,418 @@
+//! A [`GlobalAlloc`](core::alloc::GlobalAlloc) for `no_std` programs, that gets its memory from
+//! the memory scheme.
+//!
+//! Small allocations are served from slabs: pages that are split into blocks of one power of two
+//! size class, with a header at the start of the page. Larger allocations get whole pages of
+//! their own. Pages are returned to the [`PageSource`](crate::allocator::PageSource) once they are
+//! no longer used, except that every size class keeps one empty slab around, until
+//! [`Allocator::trim`](crate::allocator::Allocator::trim) is called.
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOCATOR: Allocator = Allocator::new(FmapPages::new());
+//!
+//! // Before the first allocation, with the fd of the memory scheme:
+//! ALLOCATOR.source().set_memory_fd(memory_fd);
+//! ```
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    call,
+    data::Map,
+    flag::MapFlags,
+    sync::{Futex, Mutex, SysFutex},
+    PAGE_SIZE,
+};
+
+/// Where an [`Allocator`] gets its pages from.
+///
+/// # Safety
+///
+/// `alloc_pages` must return `PAGE_SIZE` aligned memory, that is readable, writable and not used
+/// by anything else until it is passed to `free_pages`.
+pub unsafe trait PageSource {
+    /// Allocate `count` contiguous pages, or return `None` if out of memory.
+    fn alloc_pages(&self, count: usize) -> Option<NonNull<u8>>;
+    /// Free pages allocated by `alloc_pages`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` and `count` must be exactly what was passed to and returned from one `alloc_pages`
+    /// call, and the pages must not be used afterwards.
+    unsafe fn free_pages(&self, ptr: NonNull<u8>, count: usize);
+}
+
+/// Anonymous memory from `fmap` on the memory scheme ([`GlobalSchemes::Memory`]).
+///
+/// [`GlobalSchemes::Memory`]: crate::data::GlobalSchemes::Memory
+#[derive(Debug)]
+pub struct FmapPages {
+    fd: AtomicUsize,
+}
+
+impl FmapPages {
+    /// Create a page source without a memory scheme fd; every allocation fails until
+    /// [`FmapPages::set_memory_fd`] is called.
+    pub const fn new() -> Self {
+        Self::with_memory_fd(usize::MAX)
+    }
+    pub const fn with_memory_fd(fd: usize) -> Self {
+        Self {
+            fd: AtomicUsize::new(fd),
+        }
+    }
+    pub fn set_memory_fd(&self, fd: usize) {
+        self.fd.store(fd, Ordering::Relaxed);
+    }
+    pub fn memory_fd(&self) -> usize {
+        self.fd.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for FmapPages {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl PageSource for FmapPages {
+    fn alloc_pages(&self, count: usize) -> Option<NonNull<u8>> {
+        let map = Map {
+            offset: 0,
+            size: count.checked_mul(PAGE_SIZE)?,
+            flags: MapFlags::MAP_PRIVATE | MapFlags::PROT_READ | MapFlags::PROT_WRITE,
+            address: 0,
+        };
+        let addr = unsafe { call::fmap(self.memory_fd(), &map) }.ok()?;
+        NonNull::new(addr as *mut u8)
+    }
+    unsafe fn free_pages(&self, ptr: NonNull<u8>, count: usize) {
+        let _ = call::funmap(ptr.as_ptr() as usize, count * PAGE_SIZE);
+    }
+}
+
+const MIN_CLASS_SHIFT: u32 = 4;
+const MAX_CLASS_SHIFT: u32 = 10;
+const CLASSES: usize = (MAX_CLASS_SHIFT - MIN_CLASS_SHIFT + 1) as usize;
+
+/// Header at the start of every slab page.
+#[repr(C)]
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    /// Blocks that were freed.
+    free: *mut Block,
+    /// Offset of the first block that was never allocated.
+    bump: usize,
+    /// Blocks currently allocated.
+    used: usize,
+    /// Whether the slab is in its class list, which contains all slabs that are not full.
+    listed: bool,
+}
+
+struct Block {
+    next: *mut Block,
+}
+
+struct State {
+    /// Slabs with free blocks, per size class.
+    partial: [*mut Slab; CLASSES],
+}
+
+// The slabs are only accessed with the mutex held.
+unsafe impl Send for State {}
+
+/// A slab allocator over a [`PageSource`].
+pub struct Allocator<P: PageSource = FmapPages, F: Futex = SysFutex> {
+    source: P,
+    state: Mutex<State, F>,
+}
+
+impl<P: PageSource, F: Futex> Allocator<P, F> {
+    pub const fn new(source: P) -> Self {
+        Self {
+            source,
+            state: Mutex::new(State {
+                partial: [ptr::null_mut(); CLASSES],
+            }),
+        }
+    }
+    pub fn source(&self) -> &P {
+        &self.source
+    }
+    /// Return the empty slabs that are kept for reuse to the page source.
+    pub fn trim(&self) {
+        let mut state = self.state.lock();
+        for class in 0..CLASSES {
+            let mut slab = state.partial[class];
+            while !slab.is_null() {
+                unsafe {
+                    let next = (*slab).next;
+                    if (*slab).used == 0 {
+                        unlink(&mut state.partial[class], slab);
+                        self.source
+                            .free_pages(NonNull::new_unchecked(slab.cast()), 1);
+                    }
+                    slab = next;
+                }
+            }
+        }
+    }
+
+    unsafe fn alloc_small(&self, class: usize) -> *mut u8 {
+        let size = class_size(class);
+        let mut state = self.state.lock();
+        let mut slab = state.partial[class];
+        if slab.is_null() {
+            let Some(page) = self.source.alloc_pages(1) else {
+                return ptr::null_mut();
+            };
+            slab = page.as_ptr().cast();
+            slab.write(Slab {
+                next: ptr::null_mut(),
+                prev: ptr::null_mut(),
+                free: ptr::null_mut(),
+                bump: mem::size_of::<Slab>().next_multiple_of(size),
+                used: 0,
+                listed: false,
+            });
+            link(&mut state.partial[class], slab);
+        }
+
+        let s = &mut *slab;
+        let block = if s.free.is_null() {
+            let block = slab.cast::<u8>().add(s.bump);
+            s.bump += size;
+            block
+        } else {
+            let block = s.free;
+            s.free = (*block).next;
+            block.cast()
+        };
+        s.used += 1;
+        if s.free.is_null() && s.bump + size > PAGE_SIZE {
+            unlink(&mut state.partial[class], slab);
+        }
+        block
+    }
+    unsafe fn dealloc_small(&self, ptr: *mut u8, class: usize) {
+        let slab = (ptr as usize & !(PAGE_SIZE - 1)) as *mut Slab;
+        let mut state = self.state.lock();
+        let s = &mut *slab;
+        let block = ptr.cast::<Block>();
+        (*block).next = s.free;
+        s.free = block;
+        s.used -= 1;
+
+        if !s.listed {
+            link(&mut state.partial[class], slab);
+        } else if s.used == 0 && (!s.next.is_null() || !s.prev.is_null()) {
+            // Keep the slab if it is the last one in its class, to avoid mapping and unmapping a
+            // page for every allocation when a single block is allocated and freed repeatedly.
+            unlink(&mut state.partial[class], slab);
+            self.source
+                .free_pages(NonNull::new_unchecked(slab.cast()), 1);
+        }
+    }
+}
+
+unsafe impl<P: PageSource, F: Futex> GlobalAlloc for Allocator<P, F> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match size_class(layout) {
+            Some(class) => self.alloc_small(class),
+            None if layout.align() > PAGE_SIZE => ptr::null_mut(),
+            None => self
+                .source
+                .alloc_pages(layout.size().div_ceil(PAGE_SIZE))
+                .map_or(ptr::null_mut(), NonNull::as_ptr),
+        }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match size_class(layout) {
+            Some(class) => self.dealloc_small(ptr, class),
+            None => self.source.free_pages(
+                NonNull::new_unchecked(ptr),
+                layout.size().div_ceil(PAGE_SIZE),
+            ),
+        }
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let same = match (size_class(layout), size_class(new_layout)) {
+            (Some(old), Some(new)) => old == new,
+            (None, None) => layout.size().div_ceil(PAGE_SIZE) == new_size.div_ceil(PAGE_SIZE),
+            _ => false,
+        };
+        if same {
+            return ptr;
+        }
+        let new = self.alloc(new_layout);
+        if !new.is_null() {
+            ptr::copy_nonoverlapping(ptr, new, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new
+    }
+}
+
+/// The size class for `layout`, or `None` if it needs whole pages.
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(1 << MIN_CLASS_SHIFT);
+    let shift = size.next_power_of_two().trailing_zeros();
+    (shift <= MAX_CLASS_SHIFT).then(|| (shift - MIN_CLASS_SHIFT) as usize)
+}
+fn class_size(class: usize) -> usize {
+    1 << (class as u32 + MIN_CLASS_SHIFT)
+}
+
+unsafe fn link(head: &mut *mut Slab, slab: *mut Slab) {
+    (*slab).prev = ptr::null_mut();
+    (*slab).next = *head;
+    if !head.is_null() {
+        (**head).prev = slab;
+    }
+    *head = slab;
+    (*slab).listed = true;
+}
+unsafe fn unlink(head: &mut *mut Slab, slab: *mut Slab) {
+    let Slab { next, prev, .. } = *slab;
+    if prev.is_null() {
+        *head = next;
+    } else {
+        (*prev).next = next;
+    }
+    if !next.is_null() {
+        (*next).prev = prev;
+    }
+    (*slab).listed = false;
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        alloc::{GlobalAlloc, Layout},
+        cell::Cell,
+        ptr::NonNull,
+    };
+    use std::{alloc, collections::BTreeSet, rc::Rc, vec::Vec};
+
+    use super::{Allocator, FmapPages, PageSource};
+    use crate::{backend::*, call, flag::*, PAGE_SIZE};
+
+    /// Pages from the host allocator, checking that every free matches an allocation.
+    #[derive(Default)]
+    struct HostPages {
+        live: std::sync::Mutex<BTreeSet<(usize, usize)>>,
+        allocs: Cell<usize>,
+    }
+    unsafe impl Sync for HostPages {}
+
+    unsafe impl PageSource for HostPages {
+        fn alloc_pages(&self, count: usize) -> Option<NonNull<u8>> {
+            let layout = Layout::from_size_align(count * PAGE_SIZE, PAGE_SIZE).unwrap();
+            let ptr = NonNull::new(unsafe { alloc::alloc(layout) })?;
+            self.live
+                .lock()
+                .unwrap()
+                .insert((ptr.as_ptr() as usize, count));
+            self.allocs.set(self.allocs.get() + 1);
+            Some(ptr)
+        }
+        unsafe fn free_pages(&self, ptr: NonNull<u8>, count: usize) {
+            assert!(self
+                .live
+                .lock()
+                .unwrap()
+                .remove(&(ptr.as_ptr() as usize, count)));
+            let layout = Layout::from_size_align(count * PAGE_SIZE, PAGE_SIZE).unwrap();
+            alloc::dealloc(ptr.as_ptr(), layout);
+        }
+    }
+
+    #[test]
+    fn slabs_and_pages() {
+        let allocator = Allocator::<_>::new(HostPages::default());
+        let mut rng = 0x2545_f491_4f6c_dd1d_u64;
+        let mut live = Vec::new();
+
+        for i in 0..2000 {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            if rng.is_multiple_of(3) && !live.is_empty() {
+                let (ptr, layout, fill): (*mut u8, Layout, u8) =
+                    live.swap_remove(rng as usize % live.len());
+                let data = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+                assert!(data.iter().all(|&b| b == fill));
+                unsafe { allocator.dealloc(ptr, layout) };
+                continue;
+            }
+            let size = [1, 8, 24, 100, 1000, 1024, 3000, 9000][rng as usize % 8];
+            let align = [1, 8, 64, 512][(rng >> 8) as usize % 4];
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = unsafe { allocator.alloc(layout) };
+            assert!(!ptr.is_null());
+            assert!((ptr as usize).is_multiple_of(align));
+            unsafe { ptr.write_bytes(i as u8, size) };
+            live.push((ptr, layout, i as u8));
+        }
+        for (ptr, layout, _) in live {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+        assert!(!allocator.source().live.lock().unwrap().is_empty());
+        allocator.trim();
+        assert!(allocator.source().live.lock().unwrap().is_empty());
+
+        // A single block allocated and freed repeatedly reuses its slab.
+        let allocs = allocator.source().allocs.get();
+        let layout = Layout::new::<u64>();
+        for _ in 0..10 {
+            unsafe { allocator.dealloc(allocator.alloc(layout), layout) };
+        }
+        assert_eq!(allocator.source().allocs.get(), allocs + 1);
+        assert_eq!(
+            unsafe { allocator.alloc(Layout::from_size_align(8, 2 * PAGE_SIZE).unwrap()) },
+            core::ptr::null_mut()
+        );
+    }
+
+    #[test]
+    fn fmap_pages() {
+        let kernel = Rc::new(FakeKernel::new());
+        kernel.add_file("memory:", b"");
+
+        with_backend(kernel.clone(), || {
+            let fd = call::openat_into(0, 3, "memory:", O_RDWR, 0).unwrap();
+            let allocator = Allocator::<_>::new(FmapPages::new());
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            assert!(unsafe { allocator.alloc(layout) }.is_null());
+
+            allocator.source().set_memory_fd(fd);
+            let small = unsafe { allocator.alloc(layout) };
+            let large = Layout::from_size_align(3 * PAGE_SIZE, 8).unwrap();
+            let big = unsafe { allocator.alloc(large) };
+            assert!(!small.is_null() && !big.is_null());
+            assert_eq!(kernel.mapped_pages().len(), 4);
+
+            unsafe {
+                allocator.dealloc(big, large);
+                allocator.dealloc(small, layout);
+            }
+            allocator.trim();
+            assert!(kernel.mapped_pages().is_empty());
+            call::close(fd).unwrap();
+        });
+
+        let fmap = kernel
+            .records()
+            .into_iter()
+            .find(|r| r.number == crate::SYS_FMAP);
+        assert!(fmap.is_some());
+    }
+}
@@ -0,0 +1,516 @@
+//! With the `backend` feature (and in this crate's own tests), every `syscallN` function first
+//! looks for a [`Backend`](crate::backend::Backend) installed on the current thread with
+//! [`with_backend`](crate::backend::with_backend), and only falls back to the real kernel when
+//! there is none. On hosts other than Redox, there is no real kernel to fall back to, and system
+//! calls without a backend fail with `ENOSYS`.
+//!
+//! [`FakeKernel`](crate::backend::FakeKernel) is an in-memory backend that records every system
+//! call, and implements enough of the file system calls for simple clients to run unmodified in
+//! `cargo test`.
+
+use std::{
+    alloc::{self, Layout},
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    mem,
+    rc::Rc,
+    slice,
+    string::String,
+    vec::Vec,
+};
+
+use crate::{
+    data::{Map, Stat, TimeSpec},
+    error::*,
+    flag::*,
+    number::*,
+    PAGE_SIZE,
+};
+
+/// Something that handles system calls in place of the kernel.
+pub trait Backend {
+    /// Handle the system call `number`. Arguments not used by the `syscallN` function that was
+    /// called are zero.
+    ///
+    /// # Safety
+    ///
+    /// Pointer arguments are only guaranteed to be valid in the same way the real kernel would
+    /// require for that system call.
+    unsafe fn syscall(&self, number: usize, args: [usize; 6]) -> Result<usize>;
+}
+
+std::thread_local! {
+    static BACKEND: RefCell<Option<Rc<dyn Backend>>> = const { RefCell::new(None) };
+}
+
+/// Run `f`, sending every system call made on this thread to `backend`.
+///
+/// The previous backend, if any, is restored when `f` returns or unwinds.
+pub fn with_backend<R>(backend: Rc<dyn Backend>, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Rc<dyn Backend>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            BACKEND.with(|b| *b.borrow_mut() = self.0.take());
+        }
+    }
+
+    let _restore = Restore(BACKEND.with(|b| b.borrow_mut().replace(backend)));
+    f()
+}
+
+fn current() -> Option<Rc<dyn Backend>> {
+    // Cloned so that the backend can itself make system calls.
+    BACKEND.with(|b| b.borrow().clone())
+}
+
+macro_rules! syscall {
+    ($($name:ident($($arg:ident),*) => $args:expr;)+) => {
+        $(
+            /// # Safety
+            ///
+            /// The arguments must be valid for the system call `a`, as for the real kernel.
+            pub unsafe fn $name(a: usize, $($arg: usize),*) -> Result<usize> {
+                match current() {
+                    Some(backend) => backend.syscall(a, $args),
+                    #[cfg(target_os = "redox")]
+                    None => crate::arch::$name(a, $($arg),*),
+                    #[cfg(not(target_os = "redox"))]
+                    None => Err(Error::new(ENOSYS)),
+                }
+            }
+        )+
+    };
+}
+
+syscall! {
+    syscall0() => [0; 6];
+    syscall1(b) => [b, 0, 0, 0, 0, 0];
+    syscall2(b, c) => [b, c, 0, 0, 0, 0];
+    syscall3(b, c, d) => [b, c, d, 0, 0, 0];
+    syscall4(b, c, d, e) => [b, c, d, e, 0, 0];
+    syscall5(b, c, d, e, f) => [b, c, d, e, f, 0];
+    syscall6(b, c, d, e, f, g) => [b, c, d, e, f, g];
+}
+
+/// A system call handled by a [`FakeKernel`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub number: usize,
+    pub args: [usize; 6],
+    pub result: Result<usize>,
+}
+
+/// An in-memory kernel with a flat namespace of regular files.
+///
+/// Paths passed to `SYS_OPENAT_INTO` are looked up verbatim, and the directory fd is ignored.
+/// The clocks start at zero and only advance when something calls `SYS_NANOSLEEP`, which can be
+/// made to fail with `EINTR` using [`FakeKernel::interrupt_sleeps`]. Unsupported
+/// system calls fail with `ENOSYS`, and are recorded like any other.
+///
+/// `SYS_FMAP` copies the file into newly allocated host memory, so every mapping behaves like
+/// `MAP_PRIVATE`. Protection changes are recorded but not enforced, and unmapping or protecting
+/// pages that are not mapped fails with `EINVAL`, to catch double unmaps.
+#[derive(Default)]
+pub struct FakeKernel {
+    files: RefCell<BTreeMap<String, Rc<RefCell<Vec<u8>>>>>,
+    fds: RefCell<BTreeMap<usize, Rc<RefCell<Description>>>>,
+    records: RefCell<Vec<Record>>,
+    /// Mapped pages by address.
+    pages: RefCell<BTreeMap<usize, (Rc<Region>, MapFlags)>>,
+    /// Nanoseconds since boot.
+    clock: Cell<u128>,
+    /// Nanoseconds between the Unix epoch and boot.
+    realtime_offset: Cell<u128>,
+    /// Number of upcoming `SYS_NANOSLEEP` calls to interrupt.
+    interrupts: Cell<usize>,
+}
+
+struct Description {
+    path: String,
+    data: Rc<RefCell<Vec<u8>>>,
+    offset: usize,
+    flags: usize,
+}
+
+/// Host memory backing one `SYS_FMAP` call, freed once all of its pages are unmapped.
+struct Region {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+impl FakeKernel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Create or replace the file at `path`.
+    pub fn add_file(&self, path: &str, contents: &[u8]) {
+        self.files
+            .borrow_mut()
+            .insert(path.into(), Rc::new(RefCell::new(contents.to_vec())));
+    }
+    /// Current contents of the file at `path`.
+    pub fn file(&self, path: &str) -> Option<Vec<u8>> {
+        self.files
+            .borrow()
+            .get(path)
+            .map(|data| data.borrow().clone())
+    }
+    /// All file descriptors that are currently open, in ascending order.
+    pub fn open_fds(&self) -> Vec<usize> {
+        self.fds.borrow().keys().copied().collect()
+    }
+    /// Every system call made so far, in order.
+    pub fn records(&self) -> Vec<Record> {
+        self.records.borrow().clone()
+    }
+    /// Addresses of all pages that are currently mapped, in ascending order.
+    pub fn mapped_pages(&self) -> Vec<usize> {
+        self.pages.borrow().keys().copied().collect()
+    }
+    pub fn set_realtime(&self, since_epoch: TimeSpec) {
+        self.realtime_offset
+            .set(since_epoch.to_nanos().saturating_sub(self.clock.get()));
+    }
+
+    /// Interrupt the next `count` calls to `SYS_NANOSLEEP` halfway, making them fail with `EINTR`
+    /// after writing the remaining time.
+    pub fn interrupt_sleeps(&self, count: usize) {
+        self.interrupts.set(count);
+    }
+
+    fn description(&self, fd: usize) -> Result<Rc<RefCell<Description>>> {
+        self.fds.borrow().get(&fd).cloned().ok_or(Error::new(EBADF))
+    }
+    fn install(&self, fd: usize, description: Rc<RefCell<Description>>) -> Result<usize> {
+        // Like dup2, silently replaces whatever was at `fd`.
+        self.fds.borrow_mut().insert(fd, description);
+        Ok(fd)
+    }
+
+    unsafe fn openat_into(&self, path: &str, flags: usize, out: usize) -> Result<usize> {
+        let existing = self.files.borrow().get(path).cloned();
+        let data = match existing {
+            Some(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => {
+                return Err(Error::new(EEXIST))
+            }
+            Some(_) if flags & O_DIRECTORY != 0 => return Err(Error::new(ENOTDIR)),
+            Some(data) => data,
+            None if flags & O_CREAT != 0 => {
+                let data = Rc::new(RefCell::new(Vec::new()));
+                self.files
+                    .borrow_mut()
+                    .insert(path.into(), Rc::clone(&data));
+                data
+            }
+            None => return Err(Error::new(ENOENT)),
+        };
+        if flags & O_TRUNC != 0 && flags & O_WRONLY != 0 {
+            data.borrow_mut().clear();
+        }
+        self.install(
+            out,
+            Rc::new(RefCell::new(Description {
+                path: path.into(),
+                data,
+                offset: 0,
+                flags,
+            })),
+        )
+    }
+    fn read(&self, fd: usize, buf: &mut [u8], offset: Option<usize>) -> Result<usize> {
+        let description = self.description(fd)?;
+        let mut description = description.borrow_mut();
+        if description.flags & O_RDONLY == 0 {
+            return Err(Error::new(EBADF));
+        }
+        let pos = offset.unwrap_or(description.offset);
+        let count = {
+            let data = description.data.borrow();
+            let src = data.get(pos..).unwrap_or(&[]);
+            let count = src.len().min(buf.len());
+            buf[..count].copy_from_slice(&src[..count]);
+            count
+        };
+        if offset.is_none() {
+            description.offset += count;
+        }
+        Ok(count)
+    }
+    fn write(&self, fd: usize, buf: &[u8], offset: Option<usize>, append: bool) -> Result<usize> {
+        let description = self.description(fd)?;
+        let mut description = description.borrow_mut();
+        if description.flags & O_WRONLY == 0 {
+            return Err(Error::new(EBADF));
+        }
+        let pos = {
+            let mut data = description.data.borrow_mut();
+            let pos = if append || description.flags & O_APPEND != 0 {
+                data.len()
+            } else {
+                offset.unwrap_or(description.offset)
+            };
+            let end = pos.checked_add(buf.len()).ok_or(Error::new(EFBIG))?;
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[pos..end].copy_from_slice(buf);
+            pos
+        };
+        if offset.is_none() {
+            description.offset = pos + buf.len();
+        }
+        Ok(buf.len())
+    }
+    fn lseek(&self, fd: usize, offset: isize, whence: usize) -> Result<usize> {
+        let description = self.description(fd)?;
+        let mut description = description.borrow_mut();
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => description.offset,
+            SEEK_END => description.data.borrow().len(),
+            _ => return Err(Error::new(EINVAL)),
+        };
+        description.offset = base.checked_add_signed(offset).ok_or(Error::new(EINVAL))?;
+        Ok(description.offset)
+    }
+    fn fstat(&self, fd: usize, stat: &mut Stat) -> Result<usize> {
+        let description = self.description(fd)?;
+        let len = description.borrow().data.borrow().len();
+        *stat = Stat {
+            st_mode: MODE_FILE | 0o644,
+            st_nlink: 1,
+            st_size: len as u64,
+            st_blksize: 4096,
+            st_blocks: len.div_ceil(512) as u64,
+            ..Stat::default()
+        };
+        Ok(0)
+    }
+    fn fpath(&self, fd: usize, buf: &mut [u8]) -> Result<usize> {
+        let description = self.description(fd)?;
+        let description = description.borrow();
+        let count = description.path.len().min(buf.len());
+        buf[..count].copy_from_slice(&description.path.as_bytes()[..count]);
+        Ok(count)
+    }
+    fn dup(&self, fd: usize, out: usize, buf: &[u8]) -> Result<usize> {
+        let description = self.description(fd)?;
+        if !buf.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+        self.install(out, description)
+    }
+    fn fmap(&self, fd: usize, map: &Map) -> Result<usize> {
+        let description = self.description(fd)?;
+        if map.flags.intersects(MapFlags::MAP_FIXED) {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        if map.size == 0 || !map.offset.is_multiple_of(PAGE_SIZE) {
+            return Err(Error::new(EINVAL));
+        }
+        let size = map
+            .size
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or(Error::new(ENOMEM))?;
+        let layout = Layout::from_size_align(size, PAGE_SIZE).map_err(|_| Error::new(ENOMEM))?;
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(Error::new(ENOMEM));
+        }
+        let region = Rc::new(Region { ptr, layout });
+
+        let description = description.borrow();
+        let data = description.data.borrow();
+        let src = data.get(map.offset..).unwrap_or(&[]);
+        let count = src.len().min(map.size);
+        unsafe { ptr.copy_from_nonoverlapping(src.as_ptr(), count) };
+
+        let mut pages = self.pages.borrow_mut();
+        for page in (ptr as usize..ptr as usize + size).step_by(PAGE_SIZE) {
+            pages.insert(page, (Rc::clone(&region), map.flags));
+        }
+        Ok(ptr as usize)
+    }
+    /// Check that every page in `addr..addr + len` is mapped, and return their addresses.
+    fn mapped_range(&self, addr: usize, len: usize) -> Result<impl Iterator<Item = usize>> {
+        let end = len
+            .checked_next_multiple_of(PAGE_SIZE)
+            .and_then(|len| addr.checked_add(len))
+            .ok_or(Error::new(EINVAL))?;
+        let range = (addr..end).step_by(PAGE_SIZE);
+        let pages = self.pages.borrow();
+        if !addr.is_multiple_of(PAGE_SIZE) || !range.clone().all(|page| pages.contains_key(&page)) {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(range)
+    }
+    fn funmap(&self, addr: usize, len: usize) -> Result<usize> {
+        for page in self.mapped_range(addr, len)? {
+            self.pages.borrow_mut().remove(&page);
+        }
+        Ok(0)
+    }
+    fn mprotect(&self, addr: usize, len: usize, prot: MapFlags) -> Result<usize> {
+        let prot_mask = MapFlags::PROT_READ | MapFlags::PROT_WRITE | MapFlags::PROT_EXEC;
+        for page in self.mapped_range(addr, len)? {
+            if let Some((_, flags)) = self.pages.borrow_mut().get_mut(&page) {
+                *flags = (*flags - prot_mask) | (prot & prot_mask);
+            }
+        }
+        Ok(0)
+    }
+    fn now(&self, clock: usize) -> Result<u128> {
+        match clock {
+            CLOCK_MONOTONIC => Ok(self.clock.get()),
+            CLOCK_REALTIME => Ok(self.clock.get() + self.realtime_offset.get()),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    unsafe fn handle(&self, number: usize, [b, c, d, e, f, g]: [usize; 6]) -> Result<usize> {
+        match number {
+            SYS_OPENAT_INTO => self.openat_into(str_arg(c, d)?, e, g),
+            SYS_CLOSE => self
+                .fds
+                .borrow_mut()
+                .remove(&b)
+                .map(|_| 0)
+                .ok_or(Error::new(EBADF)),
+            SYS_DUP_INTO => self.dup(b, e, slice_arg(c, d)?),
+            SYS_DUP2 => self.dup(b, c, slice_arg(d, e)?),
+            SYS_READ => self.read(b, slice_arg_mut(c, d)?, None),
+            SYS_READ2 => self.read(b, slice_arg_mut(c, d)?, Some(e)),
+            SYS_WRITE => self.write(b, slice_arg(c, d)?, None, false),
+            SYS_WRITE2 => {
+                let append = RwFlags::from_bits_retain(f as u32).contains(RwFlags::APPEND);
+                self.write(b, slice_arg(c, d)?, Some(e), append)
+            }
+            SYS_LSEEK => self.lseek(b, c as isize, d),
+            SYS_FSTAT if d == mem::size_of::<Stat>() => self.fstat(b, &mut *(c as *mut Stat)),
+            SYS_FSTAT => Err(Error::new(EINVAL)),
+            SYS_FPATH => self.fpath(b, slice_arg_mut(c, d)?),
+            SYS_FMAP if d == mem::size_of::<Map>() => self.fmap(b, &*(c as *const Map)),
+            SYS_FMAP => Err(Error::new(EINVAL)),
+            SYS_FUNMAP => self.funmap(b, c),
+            SYS_MPROTECT => self.mprotect(b, c, MapFlags::from_bits_retain(d)),
+            SYS_FSYNC => self.description(b).map(|_| 0),
+            SYS_CLOCK_GETTIME => {
+                *(c as *mut TimeSpec) = TimeSpec::from_nanos(self.now(b)?);
+                Ok(0)
+            }
+            SYS_NANOSLEEP => {
+                let req = &*(b as *const TimeSpec);
+                if req.is_negative() || !req.is_normalized() {
+                    return Err(Error::new(EINVAL));
+                }
+                let mut slept = req.to_nanos();
+                let interrupted = self.interrupts.get() > 0;
+                if interrupted {
+                    self.interrupts.set(self.interrupts.get() - 1);
+                    slept /= 2;
+                }
+                self.clock.set(self.clock.get() + slept);
+                if !interrupted {
+                    return Ok(0);
+                }
+                if c != 0 {
+                    *(c as *mut TimeSpec) = TimeSpec::from_nanos(req.to_nanos() - slept);
+                }
+                Err(Error::new(EINTR))
+            }
+            SYS_YIELD => Ok(0),
+            _ => Err(Error::new(ENOSYS)),
+        }
+    }
+}
+
+impl Backend for FakeKernel {
+    unsafe fn syscall(&self, number: usize, args: [usize; 6]) -> Result<usize> {
+        let result = self.handle(number, args);
+        self.records.borrow_mut().push(Record {
+            number,
+            args,
+            result,
+        });
+        result
+    }
+}
+
+unsafe fn slice_arg<'a>(ptr: usize, len: usize) -> Result<&'a [u8]> {
+    if ptr == 0 {
+        return Err(Error::new(EFAULT));
+    }
+    Ok(slice::from_raw_parts(ptr as *const u8, len))
+}
+unsafe fn slice_arg_mut<'a>(ptr: usize, len: usize) -> Result<&'a mut [u8]> {
+    if ptr == 0 {
+        return Err(Error::new(EFAULT));
+    }
+    Ok(slice::from_raw_parts_mut(ptr as *mut u8, len))
+}
+unsafe fn str_arg<'a>(ptr: usize, len: usize) -> Result<&'a str> {
+    core::str::from_utf8(slice_arg(ptr, len)?).map_err(|_| Error::new(EINVAL))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{with_backend, FakeKernel};
+    use crate::{call, error::*, fd::OwnedFd, flag::*, number::*, Stat};
+
+    #[test]
+    fn open_read_write_close() {
+        let kernel = Rc::new(FakeKernel::new());
+        kernel.add_file("file:/etc/passwd", b"root:x:0:0");
+
+        with_backend(kernel.clone(), || {
+            let fd = call::openat_into(0, 3, "file:/etc/passwd", O_RDWR, 0).unwrap();
+            let fd = unsafe { OwnedFd::<crate::fd::LowerTbl>::from_raw(fd) }.unwrap();
+
+            let mut buf = [0; 4];
+            assert_eq!(fd.as_fd().read(&mut buf), Ok(4));
+            assert_eq!(&buf, b"root");
+            assert_eq!(fd.as_fd().write(b"!"), Ok(1));
+            assert_eq!(fd.as_fd().pread(&mut buf, 0, RwFlags::empty()), Ok(4));
+
+            let mut stat = Stat::default();
+            fd.as_fd().fstat(&mut stat).unwrap();
+            assert_eq!(stat.st_size, 10);
+            assert_eq!(stat.st_mode & MODE_TYPE, MODE_FILE);
+        });
+
+        assert_eq!(kernel.file("file:/etc/passwd").unwrap(), b"root!x:0:0");
+        assert!(kernel.open_fds().is_empty());
+
+        let records = kernel.records();
+        assert_eq!(records.first().unwrap().number, SYS_OPENAT_INTO);
+        assert_eq!(records.last().unwrap().number, SYS_CLOSE);
+    }
+
+    #[test]
+    fn errors() {
+        let kernel = Rc::new(FakeKernel::new());
+
+        with_backend(kernel.clone(), || {
+            assert_eq!(
+                call::openat_into(0, 3, "missing", O_RDONLY, 0),
+                Err(Error::new(ENOENT))
+            );
+            assert_eq!(call::read(3, &mut []), Err(Error::new(EBADF)));
+            assert_eq!(call::fsync(3), Err(Error::new(EBADF)));
+            assert_eq!(call::fcntl(3, F_GETFL, 0), Err(Error::new(ENOSYS)));
+        });
+        assert_eq!(kernel.records().len(), 4);
+
+        // Without a backend, nothing reaches the host kernel.
+        assert_eq!(call::sched_yield(), Err(Error::new(ENOSYS)));
+    }
+}
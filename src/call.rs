@@ -1,18 +1,26 @@
+#[cfg(not(any(feature = "backend", test)))]
+use super::arch::*;
+#[cfg(any(feature = "backend", test))]
+use super::backend::{syscall0, syscall1, syscall2, syscall3, syscall4, syscall5, syscall6};
 use super::{
-    arch::*,
     data::{Map, Stat, StdFsCallMeta, TimeSpec},
-    error::Result,
+    error::{Error, Result, EOVERFLOW},
     flag::*,
     number::*,
 };
 
-use core::mem;
+use core::{mem, ptr, sync::atomic::AtomicU32};
 
 /// Get the current system time
 pub fn clock_gettime(clock: usize, tp: &mut TimeSpec) -> Result<usize> {
     unsafe { syscall2(SYS_CLOCK_GETTIME, clock, tp as *mut TimeSpec as usize) }
 }
 
+/// Close a file descriptor
+pub fn close(fd: usize) -> Result<usize> {
+    unsafe { syscall1(SYS_CLOSE, fd) }
+}
+
 /// Copy and transform a file descriptor into specified fd number
 pub fn dup_into(fd: usize, out: usize, buf: &[u8]) -> Result<usize> {
     unsafe { syscall4(SYS_DUP_INTO, fd, buf.as_ptr() as usize, buf.len(), out) }
@@ -109,6 +117,77 @@ pub unsafe fn futex(
     )
 }
 
+/// Block while `word` contains `expected`, until woken by [`futex_wake`] or [`futex_requeue`]
+///
+/// `timeout` is relative to when the call is made. Fails with `EAGAIN` if `word` did not contain
+/// `expected` to begin with, and with `ETIMEDOUT` if the timeout expired. Callers must be prepared
+/// for spurious wakeups.
+pub fn futex_wait(word: &AtomicU32, expected: u32, timeout: Option<&TimeSpec>) -> Result<()> {
+    unsafe {
+        futex(
+            word.as_ptr().cast(),
+            FutexOp::Wait as usize,
+            expected as i32,
+            timeout.map_or(0, |timeout| timeout as *const TimeSpec as usize),
+            ptr::null_mut(),
+        )
+    }
+    .map(|_| ())
+}
+
+/// Like [`futex_wait`], but for a 64-bit word
+///
+/// The kernel takes the expected value in the `addr2` argument, so this needs 64-bit registers.
+#[cfg(target_pointer_width = "64")]
+pub fn futex_wait64(
+    word: &core::sync::atomic::AtomicU64,
+    expected: u64,
+    timeout: Option<&TimeSpec>,
+) -> Result<()> {
+    unsafe {
+        futex(
+            word.as_ptr().cast(),
+            FutexOp::Wait64 as usize,
+            0,
+            timeout.map_or(0, |timeout| timeout as *const TimeSpec as usize),
+            expected as usize as *mut i32,
+        )
+    }
+    .map(|_| ())
+}
+
+/// Wake up to `count` threads blocked on `word`, returning how many were woken
+pub fn futex_wake(word: &AtomicU32, count: u32) -> Result<usize> {
+    unsafe {
+        futex(
+            word.as_ptr().cast(),
+            FutexOp::Wake as usize,
+            count.min(i32::MAX as u32) as i32,
+            0,
+            ptr::null_mut(),
+        )
+    }
+}
+
+/// Wake up to `wake` threads blocked on `word`, and move up to `requeue` of the remaining ones
+/// to wait on `target` instead
+pub fn futex_requeue(
+    word: &AtomicU32,
+    wake: u32,
+    requeue: usize,
+    target: &AtomicU32,
+) -> Result<usize> {
+    unsafe {
+        futex(
+            word.as_ptr().cast(),
+            FutexOp::Requeue as usize,
+            wake.min(i32::MAX as u32) as i32,
+            requeue,
+            target.as_ptr().cast(),
+        )
+    }
+}
+
 /// Seek to `offset` bytes in a file descriptor
 pub fn lseek(fd: usize, offset: isize, whence: usize) -> Result<usize> {
     unsafe { syscall3(SYS_LSEEK, fd, offset as usize, whence) }
@@ -162,6 +241,54 @@ pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize> {
     unsafe { syscall3(SYS_READ, fd, buf.as_mut_ptr() as usize, buf.len()) }
 }
 
+/// Read from a file descriptor at `offset`, without using or changing its file position
+///
+/// `flags` apply to this call only, e.g. [`RwFlags::NONBLOCK`] makes this read non-blocking even
+/// if the file descriptor was not opened with `O_NONBLOCK`.
+///
+/// The arguments of `SYS_READ2` are passed as follows:
+///
+/// | arch      | number | fd    | buf ptr | buf len | offset | flags |
+/// |-----------|--------|-------|---------|---------|--------|-------|
+/// | `x86_64`  | `rax`  | `rdi` | `rsi`   | `rdx`   | `r10`  | `r8`  |
+/// | `aarch64` | `x8`   | `x0`  | `x1`    | `x2`    | `x3`   | `x4`  |
+/// | `riscv64` | `a7`   | `a0`  | `a1`    | `a2`    | `a3`   | `a4`  |
+/// | `x86`     | `eax`  | `ebx` | `ecx`   | `edx`   | `esi`  | `edi` |
+///
+/// On `x86`, the offset has to fit in a single 32-bit register, and larger offsets fail with
+/// `EOVERFLOW` without entering the kernel.
+pub fn pread(fd: usize, buf: &mut [u8], offset: u64, flags: RwFlags) -> Result<usize> {
+    let offset = usize::try_from(offset).map_err(|_| Error::new(EOVERFLOW))?;
+    unsafe {
+        syscall5(
+            SYS_READ2,
+            fd,
+            buf.as_mut_ptr() as usize,
+            buf.len(),
+            offset,
+            flags.bits() as usize,
+        )
+    }
+}
+
+/// Write a buffer to a file descriptor at `offset`, without using or changing its file position
+///
+/// Registers are assigned the same way as for [`pread`], with `SYS_WRITE2` as the number. Note
+/// that [`RwFlags::APPEND`] makes the kernel ignore `offset` and write at the end of the file.
+pub fn pwrite(fd: usize, buf: &[u8], offset: u64, flags: RwFlags) -> Result<usize> {
+    let offset = usize::try_from(offset).map_err(|_| Error::new(EOVERFLOW))?;
+    unsafe {
+        syscall5(
+            SYS_WRITE2,
+            fd,
+            buf.as_ptr() as usize,
+            buf.len(),
+            offset,
+            flags.bits() as usize,
+        )
+    }
+}
+
 /// Write a buffer to a file descriptor
 ///
 /// The kernel will attempt to write the bytes in `buf` to the file descriptor `fd`, returning
@@ -1,12 +1,15 @@
 use core::{
+    cmp::Ordering,
+    hash::{Hash, Hasher},
     mem,
-    ops::{Deref, DerefMut},
+    ops::{Add, AddAssign, Deref, DerefMut, Range, Sub, SubAssign},
     slice,
+    time::Duration,
 };
 
 use crate::{
-    flag::{EventFlags, MapFlags, PtraceFlags, StdFsCallKind},
-    Error, EINVAL,
+    flag::{EventFlags, MapFlags, PtraceFlags, StdFsCallKind, SEEK_CUR, SEEK_END, SEEK_SET},
+    Error, Result, EINVAL, EOVERFLOW,
 };
 
 #[derive(Copy, Clone, Debug, Default)]
@@ -220,8 +223,70 @@ impl DerefMut for StdFsCallMeta {
     }
 }
 
+/// A POSIX advisory lock, the payload of [`StdFsCallKind::Lock`], [`StdFsCallKind::Unlock`] and
+/// [`StdFsCallKind::GetLock`].
 #[derive(Copy, Clone, Debug, Default, PartialEq)]
 #[repr(C)]
+pub struct Flock {
+    /// [`F_RDLCK`](crate::flag::F_RDLCK), [`F_WRLCK`](crate::flag::F_WRLCK) or
+    /// [`F_UNLCK`](crate::flag::F_UNLCK).
+    pub l_type: i16,
+    /// What `l_start` is relative to, [`SEEK_SET`], [`SEEK_CUR`] or [`SEEK_END`].
+    pub l_whence: i16,
+    pub l_start: i64,
+    /// The number of bytes, where a negative length covers the bytes before `l_start`, and 0
+    /// covers every byte from `l_start` on, however large the file grows.
+    pub l_len: i64,
+    /// The process holding the lock, set by [`StdFsCallKind::GetLock`].
+    pub l_pid: usize,
+}
+
+impl Flock {
+    /// The bytes the lock covers, where `pos` is the file offset for [`SEEK_CUR`] and `size` the
+    /// file size for [`SEEK_END`]. Locks to the end of the file end at `u64::MAX`.
+    ///
+    /// Fails with `EINVAL` for unknown `l_whence` or if the lock would start before 0, and with
+    /// `EOVERFLOW` if it would end past `u64::MAX`.
+    pub fn range(&self, pos: u64, size: u64) -> Result<Range<u64>> {
+        let base = match self.l_whence as usize {
+            SEEK_SET => 0,
+            SEEK_CUR => pos,
+            SEEK_END => size,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        let start = i128::from(base) + i128::from(self.l_start);
+        let (start, end) = match self.l_len {
+            0 => (start, i128::from(u64::MAX)),
+            len if len < 0 => (start + i128::from(len), start),
+            len => (start, start + i128::from(len)),
+        };
+        let offset = |n: i128| {
+            u64::try_from(n).map_err(|_| Error::new(if n < 0 { EINVAL } else { EOVERFLOW }))
+        };
+        Ok(offset(start)?..offset(end)?)
+    }
+}
+
+impl Deref for Flock {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const Flock as *const u8, mem::size_of::<Flock>()) }
+    }
+}
+
+impl DerefMut for Flock {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self as *mut Flock as *mut u8, mem::size_of::<Flock>()) }
+    }
+}
+
+/// A time or duration in seconds and nanoseconds.
+///
+/// `tv_nsec` is normally in `0..1_000_000_000`, also for negative times, so -0.5 seconds is
+/// `{ tv_sec: -1, tv_nsec: 500_000_000 }`. Values outside that range are accepted everywhere,
+/// and compare equal to their [`normalized`](TimeSpec::normalized) form.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
 pub struct TimeSpec {
     pub tv_sec: i64,
     pub tv_nsec: i32,
@@ -241,6 +306,156 @@ impl TimeSpec {
     }
 }
 
+impl TimeSpec {
+    pub const ZERO: Self = Self {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    pub const MIN: Self = Self {
+        tv_sec: i64::MIN,
+        tv_nsec: 0,
+    };
+    pub const MAX: Self = Self {
+        tv_sec: i64::MAX,
+        tv_nsec: NANOS_PER_SEC as i32 - 1,
+    };
+
+    /// The signed number of nanoseconds, which is exact for any `tv_sec` and `tv_nsec`.
+    pub fn total_nanos(&self) -> i128 {
+        i128::from(self.tv_sec) * NANOS_PER_SEC as i128 + i128::from(self.tv_nsec)
+    }
+    /// The normalized `TimeSpec` for `nanos`, or `None` if it does not fit.
+    pub fn from_total_nanos(nanos: i128) -> Option<Self> {
+        let nanos_per_sec = NANOS_PER_SEC as i128;
+        Some(Self {
+            tv_sec: i64::try_from(nanos.div_euclid(nanos_per_sec)).ok()?,
+            tv_nsec: nanos.rem_euclid(nanos_per_sec) as i32,
+        })
+    }
+    pub fn is_normalized(&self) -> bool {
+        (0..NANOS_PER_SEC as i32).contains(&self.tv_nsec)
+    }
+    /// Carry whole seconds out of `tv_nsec`, so that it is in `0..1_000_000_000`. Returns `None`
+    /// if `tv_sec` overflows.
+    pub fn normalized(&self) -> Option<Self> {
+        Self::from_total_nanos(self.total_nanos())
+    }
+    pub fn is_negative(&self) -> bool {
+        self.total_nanos() < 0
+    }
+
+    pub fn checked_add(&self, rhs: TimeSpec) -> Option<Self> {
+        Self::from_total_nanos(self.total_nanos() + rhs.total_nanos())
+    }
+    pub fn checked_sub(&self, rhs: TimeSpec) -> Option<Self> {
+        Self::from_total_nanos(self.total_nanos() - rhs.total_nanos())
+    }
+    pub fn saturating_add(&self, rhs: TimeSpec) -> Self {
+        Self::saturate(self.total_nanos() + rhs.total_nanos())
+    }
+    pub fn saturating_sub(&self, rhs: TimeSpec) -> Self {
+        Self::saturate(self.total_nanos() - rhs.total_nanos())
+    }
+    pub fn checked_add_duration(&self, rhs: Duration) -> Option<Self> {
+        Self::from_total_nanos(self.total_nanos() + rhs.as_nanos() as i128)
+    }
+    pub fn checked_sub_duration(&self, rhs: Duration) -> Option<Self> {
+        Self::from_total_nanos(self.total_nanos() - rhs.as_nanos() as i128)
+    }
+    pub fn saturating_add_duration(&self, rhs: Duration) -> Self {
+        Self::saturate(self.total_nanos() + rhs.as_nanos() as i128)
+    }
+    pub fn saturating_sub_duration(&self, rhs: Duration) -> Self {
+        Self::saturate(self.total_nanos() - rhs.as_nanos() as i128)
+    }
+
+    // Sums and differences of two TimeSpecs or a TimeSpec and a Duration (at most 2^64 seconds)
+    // are all far within the range of i128 nanoseconds, so only the conversion back can overflow.
+    fn saturate(nanos: i128) -> Self {
+        Self::from_total_nanos(nanos).unwrap_or(if nanos < 0 { Self::MIN } else { Self::MAX })
+    }
+}
+
+impl PartialEq for TimeSpec {
+    fn eq(&self, other: &Self) -> bool {
+        self.total_nanos() == other.total_nanos()
+    }
+}
+impl Eq for TimeSpec {}
+
+impl PartialOrd for TimeSpec {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for TimeSpec {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.total_nanos().cmp(&other.total_nanos())
+    }
+}
+
+impl Hash for TimeSpec {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.total_nanos().hash(state);
+    }
+}
+
+macro_rules! timespec_ops {
+    ($($rhs:ty: $add:ident, $sub:ident;)*) => {$(
+        impl Add<$rhs> for TimeSpec {
+            type Output = TimeSpec;
+            fn add(self, rhs: $rhs) -> TimeSpec {
+                self.$add(rhs).expect("overflow when adding to TimeSpec")
+            }
+        }
+        impl AddAssign<$rhs> for TimeSpec {
+            fn add_assign(&mut self, rhs: $rhs) {
+                *self = *self + rhs;
+            }
+        }
+        impl Sub<$rhs> for TimeSpec {
+            type Output = TimeSpec;
+            fn sub(self, rhs: $rhs) -> TimeSpec {
+                self.$sub(rhs).expect("overflow when subtracting from TimeSpec")
+            }
+        }
+        impl SubAssign<$rhs> for TimeSpec {
+            fn sub_assign(&mut self, rhs: $rhs) {
+                *self = *self - rhs;
+            }
+        }
+    )*};
+}
+timespec_ops! {
+    TimeSpec: checked_add, checked_sub;
+    Duration: checked_add_duration, checked_sub_duration;
+}
+
+impl TryFrom<Duration> for TimeSpec {
+    type Error = Error;
+
+    /// Fails with `EOVERFLOW` if the duration is more than `i64::MAX` seconds.
+    fn try_from(duration: Duration) -> Result<Self, Self::Error> {
+        Ok(Self {
+            tv_sec: i64::try_from(duration.as_secs()).map_err(|_| Error::new(EOVERFLOW))?,
+            tv_nsec: duration.subsec_nanos() as i32,
+        })
+    }
+}
+
+impl TryFrom<TimeSpec> for Duration {
+    type Error = Error;
+
+    /// Fails with `EINVAL` if the time is negative.
+    fn try_from(time: TimeSpec) -> Result<Self, Self::Error> {
+        let nanos = u128::try_from(time.total_nanos()).map_err(|_| Error::new(EINVAL))?;
+        Ok(Duration::new(
+            (nanos / NANOS_PER_SEC) as u64,
+            (nanos % NANOS_PER_SEC) as u32,
+        ))
+    }
+}
+
 impl Deref for TimeSpec {
     type Target = [u8];
     fn deref(&self) -> &[u8] {
@@ -537,3 +752,176 @@ impl TryFrom<&[u8]> for NumaMemoryPolicy {
         NumaMemoryPolicy::try_from(u32::from_ne_bytes(buf) as u64)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use super::{Flock, TimeSpec, NANOS_PER_SEC};
+    use crate::{
+        flag::{SEEK_CUR, SEEK_END, SEEK_SET},
+        Error, EINVAL, EOVERFLOW,
+    };
+
+    /// xorshift64, with a fixed seed so failures are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        /// Mostly small values, but also the extremes and unnormalized `tv_nsec`.
+        fn timespec(&mut self) -> TimeSpec {
+            let tv_sec = match self.next() % 4 {
+                0 => self.next() as i64,
+                1 => [i64::MIN, i64::MIN + 1, -1, 0, i64::MAX - 1, i64::MAX]
+                    [self.next() as usize % 6],
+                _ => (self.next() % 2000) as i64 - 1000,
+            };
+            let tv_nsec = match self.next() % 4 {
+                0 => self.next() as i32,
+                _ => (self.next() % NANOS_PER_SEC as u64) as i32,
+            };
+            TimeSpec { tv_sec, tv_nsec }
+        }
+        fn duration(&mut self) -> Duration {
+            match self.next() % 3 {
+                0 => Duration::new(self.next(), (self.next() % NANOS_PER_SEC as u64) as u32),
+                _ => Duration::from_nanos(self.next() % (1 << 40)),
+            }
+        }
+    }
+
+    const CASES: usize = 10_000;
+
+    #[test]
+    fn normalize() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..CASES {
+            let a = rng.timespec();
+            match a.normalized() {
+                Some(n) => {
+                    assert!(n.is_normalized(), "{a:?} -> {n:?}");
+                    assert_eq!(n.total_nanos(), a.total_nanos());
+                    assert_eq!(n, a);
+                }
+                None => assert!(a < TimeSpec::MIN || a > TimeSpec::MAX, "{a:?}"),
+            }
+        }
+        let half = TimeSpec {
+            tv_sec: 0,
+            tv_nsec: -500_000_000,
+        };
+        let n = half.normalized().unwrap();
+        assert_eq!((n.tv_sec, n.tv_nsec), (-1, 500_000_000));
+        assert!(half.is_negative());
+    }
+
+    #[test]
+    fn ordering() {
+        let mut rng = Rng(0x0123_4567_89ab_cdef);
+        for _ in 0..CASES {
+            let (a, b) = (rng.timespec(), rng.timespec());
+            assert_eq!(a.cmp(&b), a.total_nanos().cmp(&b.total_nanos()));
+            assert_eq!(a == b, a.cmp(&b).is_eq());
+            assert_eq!(a.cmp(&b), b.cmp(&a).reverse());
+        }
+        assert!(TimeSpec::MIN < TimeSpec::ZERO && TimeSpec::ZERO < TimeSpec::MAX);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let mut rng = Rng(0xdead_beef_cafe_f00d);
+        for _ in 0..CASES {
+            let (a, b, d) = (rng.timespec(), rng.timespec(), rng.duration());
+
+            if let Some(sum) = a.checked_add(b) {
+                assert!(sum.is_normalized());
+                assert_eq!(sum.checked_sub(b), a.normalized());
+                assert_eq!(a.saturating_add(b), sum);
+            } else {
+                let saturated = a.saturating_add(b);
+                assert!(saturated == TimeSpec::MIN || saturated == TimeSpec::MAX);
+            }
+            if let Some(diff) = a.checked_sub(b) {
+                assert_eq!(diff.checked_add(b), a.normalized());
+                assert_eq!(a.saturating_sub(b), diff);
+            }
+            assert_eq!(a.checked_add(b), b.checked_add(a));
+
+            if let Some(sum) = a.checked_add_duration(d) {
+                assert!(sum >= a);
+                assert_eq!(sum.checked_sub_duration(d), a.normalized());
+                assert_eq!(sum, a.saturating_add_duration(d));
+            } else {
+                assert_eq!(a.saturating_add_duration(d), TimeSpec::MAX);
+            }
+            if a.checked_sub_duration(d).is_none() {
+                assert_eq!(a.saturating_sub_duration(d), TimeSpec::MIN);
+            }
+        }
+    }
+
+    #[test]
+    fn duration_conversions() {
+        let mut rng = Rng(0x5555_aaaa_1234_4321);
+        for _ in 0..CASES {
+            let d = rng.duration();
+            match TimeSpec::try_from(d) {
+                Ok(t) => {
+                    assert!(t.is_normalized());
+                    assert_eq!(Duration::try_from(t), Ok(d));
+                }
+                Err(err) => {
+                    assert_eq!(err, Error::new(EOVERFLOW));
+                    assert!(d.as_secs() > i64::MAX as u64);
+                }
+            }
+
+            let t = rng.timespec();
+            match Duration::try_from(t) {
+                Ok(d) => assert_eq!(d.as_nanos() as i128, t.total_nanos()),
+                Err(err) => {
+                    assert_eq!(err, Error::new(EINVAL));
+                    assert!(t.is_negative());
+                }
+            }
+        }
+
+        let mut t = TimeSpec::try_from(Duration::from_millis(1500)).unwrap();
+        t += Duration::from_millis(600);
+        t -= TimeSpec {
+            tv_sec: 0,
+            tv_nsec: 100_000_000,
+        };
+        assert_eq!(Duration::try_from(t), Ok(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn flock_range() {
+        let flock = |whence: usize, l_start, l_len| Flock {
+            l_whence: whence as i16,
+            l_start,
+            l_len,
+            ..Flock::default()
+        };
+        assert_eq!(flock(SEEK_SET, 10, 5).range(100, 1000), Ok(10..15));
+        assert_eq!(flock(SEEK_CUR, -10, 5).range(100, 1000), Ok(90..95));
+        assert_eq!(flock(SEEK_END, 0, -20).range(100, 1000), Ok(980..1000));
+        assert_eq!(flock(SEEK_SET, 10, 0).range(100, 1000), Ok(10..u64::MAX));
+
+        assert_eq!(
+            flock(SEEK_CUR, -101, 5).range(100, 1000),
+            Err(Error::new(EINVAL))
+        );
+        assert_eq!(flock(SEEK_SET, 5, -6).range(0, 0), Err(Error::new(EINVAL)));
+        assert_eq!(flock(3, 0, 1).range(0, 0), Err(Error::new(EINVAL)));
+        assert_eq!(
+            flock(SEEK_END, i64::MAX, i64::MAX).range(0, u64::MAX),
+            Err(Error::new(EOVERFLOW))
+        );
+    }
+}
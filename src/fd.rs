@@ -0,0 +1,267 @@
+use core::{fmt, marker::PhantomData, mem};
+
+use crate::{
+    call::{self, Call},
+    data::{Map, Stat},
+    error::{Error, Result, EBADF},
+    flag::{CallFlags, RwFlags, UPPER_FDTBL_TAG},
+};
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for super::LowerTbl {}
+    impl Sealed for super::UpperTbl {}
+}
+
+/// The file descriptor table an fd number refers to.
+///
+/// This is sealed, and only implemented by [`LowerTbl`] and [`UpperTbl`].
+pub trait FdTable: private::Sealed {
+    /// Whether fd numbers in this table are tagged with [`UPPER_FDTBL_TAG`].
+    const UPPER: bool;
+
+    fn contains(raw: usize) -> bool {
+        (raw & UPPER_FDTBL_TAG != 0) == Self::UPPER
+    }
+}
+
+/// The regular (POSIX) file descriptor table.
+#[derive(Clone, Copy, Debug)]
+pub enum LowerTbl {}
+
+/// The upper file descriptor table, where fd numbers have [`UPPER_FDTBL_TAG`] set.
+#[derive(Clone, Copy, Debug)]
+pub enum UpperTbl {}
+
+impl FdTable for LowerTbl {
+    const UPPER: bool = false;
+}
+impl FdTable for UpperTbl {
+    const UPPER: bool = true;
+}
+
+/// A file descriptor that is closed with `SYS_CLOSE` when dropped.
+pub struct OwnedFd<T: FdTable = LowerTbl> {
+    fd: usize,
+    _table: PhantomData<T>,
+}
+
+/// A file descriptor borrowed from an [`OwnedFd`], or from somewhere else guaranteed to keep it
+/// open for `'a`.
+pub struct BorrowedFd<'a, T: FdTable = LowerTbl> {
+    fd: usize,
+    _marker: PhantomData<(&'a (), T)>,
+}
+
+impl<T: FdTable> OwnedFd<T> {
+    /// Take ownership of a raw fd number.
+    ///
+    /// Returns `None` if `fd` does not belong to the file table `T`.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be open, and must not be owned or closed by anything else.
+    pub unsafe fn from_raw(fd: usize) -> Option<Self> {
+        T::contains(fd).then_some(Self {
+            fd,
+            _table: PhantomData,
+        })
+    }
+    pub fn as_raw(&self) -> usize {
+        self.fd
+    }
+    /// Release ownership of the fd number, without closing it.
+    pub fn into_raw(self) -> usize {
+        let fd = self.fd;
+        mem::forget(self);
+        fd
+    }
+    pub fn as_fd(&self) -> BorrowedFd<'_, T> {
+        BorrowedFd {
+            fd: self.fd,
+            _marker: PhantomData,
+        }
+    }
+    /// Close the fd, returning any error `SYS_CLOSE` reported instead of ignoring it.
+    pub fn close(self) -> Result<()> {
+        call::close(self.into_raw()).map(|_| ())
+    }
+}
+
+impl<T: FdTable> Drop for OwnedFd<T> {
+    fn drop(&mut self) {
+        let _ = call::close(self.fd);
+    }
+}
+
+impl<T: FdTable> fmt::Debug for OwnedFd<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OwnedFd").field("fd", &self.fd).finish()
+    }
+}
+
+impl<'a, T: FdTable> BorrowedFd<'a, T> {
+    /// Borrow a raw fd number.
+    ///
+    /// Returns `None` if `fd` does not belong to the file table `T`.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must remain open for the whole lifetime `'a`.
+    pub unsafe fn borrow_raw(fd: usize) -> Option<Self> {
+        T::contains(fd).then_some(Self {
+            fd,
+            _marker: PhantomData,
+        })
+    }
+    pub fn as_raw(self) -> usize {
+        self.fd
+    }
+
+    /// Copy and transform this file descriptor into the fd number `out`
+    pub fn dup_into<U: FdTable>(self, out: usize, buf: &[u8]) -> Result<OwnedFd<U>> {
+        check_table::<U>(out)?;
+        call::dup_into(self.fd, out, buf).and_then(owned)
+    }
+    /// Copy and transform this file descriptor into the fd number `newfd`, replacing whatever
+    /// was open there
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may own `newfd`, such as another [`OwnedFd`], since the returned `OwnedFd`
+    /// closes it.
+    pub unsafe fn dup2<U: FdTable>(self, newfd: usize, buf: &[u8]) -> Result<OwnedFd<U>> {
+        check_table::<U>(newfd)?;
+        call::dup2(self.fd, newfd, buf).and_then(owned)
+    }
+    /// Change file ownership
+    pub fn fchown(self, uid: u32, gid: u32) -> Result<usize> {
+        call::fchown(self.fd, uid, gid)
+    }
+    /// Change file descriptor flags
+    pub fn fcntl(self, cmd: usize, arg: usize) -> Result<usize> {
+        call::fcntl(self.fd, cmd, arg)
+    }
+    /// Map this file into memory, see [`call::fmap`].
+    ///
+    /// # Safety
+    ///
+    /// Same as [`call::fmap`]; in particular, [`MAP_FIXED`](crate::flag::MapFlags::MAP_FIXED)
+    /// can replace existing mappings.
+    pub unsafe fn fmap(self, map: &Map) -> Result<usize> {
+        call::fmap(self.fd, map)
+    }
+    /// Retrieve the canonical path of the file
+    pub fn fpath(self, buf: &mut [u8]) -> Result<usize> {
+        call::fpath(self.fd, buf)
+    }
+    /// Create a link to the file
+    pub fn flink<P: AsRef<str>>(self, path: P) -> Result<usize> {
+        call::flink(self.fd, path)
+    }
+    /// Rename the file
+    pub fn frename<P: AsRef<str>>(self, path: P) -> Result<usize> {
+        call::frename(self.fd, path)
+    }
+    /// Get metadata about the file
+    pub fn fstat(self, stat: &mut Stat) -> Result<usize> {
+        call::fstat(self.fd, stat)
+    }
+    /// Sync the file to its underlying medium
+    pub fn fsync(self) -> Result<usize> {
+        call::fsync(self.fd)
+    }
+    /// Seek to `offset` bytes in the file
+    pub fn lseek(self, offset: isize, whence: usize) -> Result<usize> {
+        call::lseek(self.fd, offset, whence)
+    }
+    /// Open a file relative to this one, into the fd number `out`
+    pub fn openat_into<U: FdTable, P: AsRef<str>>(
+        self,
+        out: usize,
+        path: P,
+        flags: usize,
+        fcntl_flags: usize,
+    ) -> Result<OwnedFd<U>> {
+        check_table::<U>(out)?;
+        call::openat_into(self.fd, out, path, flags, fcntl_flags).and_then(owned)
+    }
+    /// Remove a file relative to this one
+    pub fn unlinkat<P: AsRef<str>>(self, path: P, flags: usize) -> Result<usize> {
+        call::unlinkat(self.fd, path, flags)
+    }
+    /// Read from the file into a buffer
+    pub fn read(self, buf: &mut [u8]) -> Result<usize> {
+        call::read(self.fd, buf)
+    }
+    /// Read from the file at `offset`, see [`call::pread`]
+    pub fn pread(self, buf: &mut [u8], offset: u64, flags: RwFlags) -> Result<usize> {
+        call::pread(self.fd, buf, offset, flags)
+    }
+    /// Write a buffer to the file at `offset`, see [`call::pwrite`]
+    pub fn pwrite(self, buf: &[u8], offset: u64, flags: RwFlags) -> Result<usize> {
+        call::pwrite(self.fd, buf, offset, flags)
+    }
+    /// Write a buffer to the file, see [`call::write`]
+    pub fn write(self, buf: &[u8]) -> Result<usize> {
+        call::write(self.fd, buf)
+    }
+}
+
+impl<T: FdTable> Clone for BorrowedFd<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T: FdTable> Copy for BorrowedFd<'_, T> {}
+
+impl<T: FdTable> fmt::Debug for BorrowedFd<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BorrowedFd").field("fd", &self.fd).finish()
+    }
+}
+
+fn check_table<T: FdTable>(raw: usize) -> Result<()> {
+    if T::contains(raw) {
+        Ok(())
+    } else {
+        Err(Error::new(EBADF))
+    }
+}
+
+fn owned<T: FdTable>(raw: usize) -> Result<OwnedFd<T>> {
+    // The kernel returning an fd in the wrong table would be a kernel bug, but don't leak it.
+    if !T::contains(raw) {
+        let _ = call::close(raw);
+        return Err(Error::new(EBADF));
+    }
+    Ok(OwnedFd {
+        fd: raw,
+        _table: PhantomData,
+    })
+}
+
+impl<T: FdTable> Call for BorrowedFd<'_, T> {
+    unsafe fn raw_call(
+        &self,
+        payload_ptr: *const u8,
+        len: usize,
+        flags: CallFlags,
+        metadata: &[u64],
+    ) -> Result<usize> {
+        self.fd.raw_call(payload_ptr, len, flags, metadata)
+    }
+}
+
+impl<T: FdTable> Call for &OwnedFd<T> {
+    unsafe fn raw_call(
+        &self,
+        payload_ptr: *const u8,
+        len: usize,
+        flags: CallFlags,
+        metadata: &[u64],
+    ) -> Result<usize> {
+        self.fd.raw_call(payload_ptr, len, flags, metadata)
+    }
+}
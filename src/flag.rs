@@ -56,14 +56,39 @@ pub const F_SETFD: usize = 2;
 pub const F_GETFL: usize = 3;
 pub const F_SETFL: usize = 4;
 
+/// Lock types in [`Flock::l_type`](crate::data::Flock::l_type).
+pub const F_RDLCK: i16 = 0;
+pub const F_WRLCK: i16 = 1;
+pub const F_UNLCK: i16 = 2;
+
 pub const FUTEX_WAIT: usize = 0;
 pub const FUTEX_WAKE: usize = 1;
 pub const FUTEX_REQUEUE: usize = 2;
 pub const FUTEX_WAIT64: usize = 3;
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(usize)]
+pub enum FutexOp {
+    Wait = FUTEX_WAIT,
+    Wake = FUTEX_WAKE,
+    Requeue = FUTEX_REQUEUE,
+    Wait64 = FUTEX_WAIT64,
+}
+impl FutexOp {
+    pub fn try_from_raw(raw: usize) -> Option<Self> {
+        Some(match raw {
+            FUTEX_WAIT => Self::Wait,
+            FUTEX_WAKE => Self::Wake,
+            FUTEX_REQUEUE => Self::Requeue,
+            FUTEX_WAIT64 => Self::Wait64,
+            _ => return None,
+        })
+    }
+}
+
 // TODO: Split SendFdFlags into caller flags and flags that the scheme receives?
 bitflags::bitflags! {
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
     pub struct SendFdFlags: usize {
         /// If set, the kernel will enforce that the file descriptors are exclusively owned.
         ///
@@ -78,7 +103,7 @@ bitflags::bitflags! {
     }
 }
 bitflags::bitflags! {
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
     pub struct FobtainFdFlags: usize {
         /// If set, the SYS_CALL payload specifies the destination file descriptor slots, otherwise the lowest
         /// available slots will be selected, and placed in the usize pointed to by SYS_CALL
@@ -100,7 +125,7 @@ bitflags::bitflags! {
     }
 }
 bitflags::bitflags! {
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
     pub struct RecvFdFlags: usize {
         /// If set, the SYS_CALL payload specifies the destination file descriptor slots, otherwise the lowest
         /// available slots will be selected, and placed in the usize pointed to by SYS_CALL
@@ -169,6 +194,16 @@ bitflags! {
         const NEEDS_SYNC = 1;
     }
 }
+bitflags! {
+    pub struct MsyncFlags: usize {
+        /// Start writing back, but don't wait for it to finish.
+        const MS_ASYNC = 1;
+        /// Drop cached pages, so the mapping sees changes made by others.
+        const MS_INVALIDATE = 2;
+        /// Write back, and wait for it to finish.
+        const MS_SYNC = 4;
+    }
+}
 
 pub const MODE_TYPE: u16 = 0xF000;
 pub const MODE_DIR: u16 = 0x4000;
@@ -502,8 +537,13 @@ pub enum StdFsCallKind {
     // 9 reserved in fscall RFC
     // Unlinkat = 10,
     Relpathat = 11,
+    /// Set the [`Flock`](crate::data::Flock) in the payload, waiting for conflicting locks to be
+    /// released if `arg1` is nonzero, like `F_SETLKW`.
     Lock = 12,
+    /// Release the range of the [`Flock`](crate::data::Flock) in the payload.
     Unlock = 13,
+    /// Replace the [`Flock`](crate::data::Flock) in the payload with the first lock that
+    /// conflicts with it, or set its type to [`F_UNLCK`] if none does.
     GetLock = 14,
 }
 
@@ -4,22 +4,29 @@
 #[cfg(test)]
 extern crate core;
 
+#[cfg(any(feature = "alloc", test))]
+extern crate alloc;
+
 pub use self::{arch::*, data::*, error::*, flag::*, io::*, number::*};
 
 #[cfg(target_arch = "aarch64")]
 #[path = "arch/aarch64.rs"]
+#[cfg_attr(any(feature = "backend", test), allow(dead_code))]
 mod arch;
 
 #[cfg(target_arch = "riscv64")]
 #[path = "arch/riscv64.rs"]
+#[cfg_attr(any(feature = "backend", test), allow(dead_code))]
 mod arch;
 
 #[cfg(target_arch = "x86")]
 #[path = "arch/x86.rs"]
+#[cfg_attr(any(feature = "backend", test), allow(dead_code))]
 mod arch;
 
 #[cfg(target_arch = "x86_64")]
 #[path = "arch/x86_64.rs"]
+#[cfg_attr(any(feature = "backend", test), allow(dead_code))]
 mod arch;
 
 /// Function definitions
@@ -29,6 +36,26 @@ pub mod call;
 #[cfg(feature = "userspace")]
 pub use call::*;
 
+/// Pluggable system call backend, so code using this crate can be tested on other hosts
+#[cfg(all(feature = "userspace", any(feature = "backend", test)))]
+pub mod backend;
+
+// Shadow the inline assembly syscallN functions from arch
+#[cfg(all(feature = "userspace", any(feature = "backend", test)))]
+pub use backend::{syscall0, syscall1, syscall2, syscall3, syscall4, syscall5, syscall6};
+
+/// Slab allocator over pages from the memory scheme
+#[cfg(all(feature = "userspace", any(feature = "allocator", test)))]
+pub mod allocator;
+
+/// Owned and borrowed file descriptors
+#[cfg(feature = "userspace")]
+pub mod fd;
+
+/// Memory mappings that are unmapped when dropped
+#[cfg(feature = "userspace")]
+pub mod mapping;
+
 /// Complex structures that are used for some system calls
 pub mod data;
 
@@ -46,8 +73,19 @@ pub mod io;
 /// Call numbers used by each system call
 pub mod number;
 
+/// Human readable formatting of system calls, for tracing
+pub mod trace;
+
 /// ABI for shared memory based signals
 pub mod sigabi;
 
+/// Futex-based synchronization primitives
+#[cfg(feature = "userspace")]
+pub mod sync;
+
+/// Monotonic and realtime clocks
+#[cfg(feature = "userspace")]
+pub mod time;
+
 /// V2 scheme format
 pub mod schemev2;
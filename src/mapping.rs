@@ -0,0 +1,208 @@
+use core::{fmt, slice};
+
+use crate::{
+    call,
+    data::Map,
+    error::{Error, Result, EINVAL},
+    flag::MapFlags,
+    PAGE_SIZE,
+};
+
+const PROT_MASK: MapFlags = MapFlags::PROT_READ
+    .union(MapFlags::PROT_WRITE)
+    .union(MapFlags::PROT_EXEC);
+
+/// A memory mapping that is unmapped with `SYS_FUNMAP` when dropped.
+///
+/// The mapping covers `len()` bytes starting at `as_ptr()`, but the pages it owns extend to the
+/// next page boundary, and those are what gets unmapped.
+pub struct Mapping {
+    addr: usize,
+    len: usize,
+    flags: MapFlags,
+}
+
+impl Mapping {
+    /// Map `fd` into memory, see [`call::fmap`].
+    ///
+    /// # Safety
+    ///
+    /// Same as [`call::fmap`]. In particular, `map` must not use
+    /// [`MAP_FIXED`](MapFlags::MAP_FIXED) to replace memory that is used by something else.
+    pub unsafe fn map(fd: usize, map: &Map) -> Result<Self> {
+        let addr = call::fmap(fd, map)?;
+        Ok(Self {
+            addr,
+            len: map.size,
+            flags: map.flags,
+        })
+    }
+    /// Take ownership of an existing mapping.
+    ///
+    /// # Safety
+    ///
+    /// `addr..addr + len` must be mapped with `flags`, and must not be owned or unmapped by
+    /// anything else. `addr` must be page aligned.
+    pub unsafe fn from_raw(addr: usize, len: usize, flags: MapFlags) -> Self {
+        Self { addr, len, flags }
+    }
+    /// Release ownership of the mapping without unmapping it, returning its address and length.
+    pub fn into_raw(self) -> (usize, usize) {
+        let raw = (self.addr, self.len);
+        core::mem::forget(self);
+        raw
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.addr as *const u8
+    }
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.addr as *mut u8
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// The flags the mapping was created with, with the current protection.
+    pub fn flags(&self) -> MapFlags {
+        self.flags
+    }
+
+    /// The mapped memory, or `None` unless it is mapped with `PROT_READ`.
+    pub fn as_slice(&self) -> Option<&[u8]> {
+        self.flags
+            .contains(MapFlags::PROT_READ)
+            .then(|| unsafe { slice::from_raw_parts(self.as_ptr(), self.len) })
+    }
+    /// The mapped memory, or `None` unless it is mapped with both `PROT_READ` and `PROT_WRITE`.
+    pub fn as_mut_slice(&mut self) -> Option<&mut [u8]> {
+        self.flags
+            .contains(MapFlags::PROT_READ | MapFlags::PROT_WRITE)
+            .then(|| unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.len) })
+    }
+
+    /// Change the protection of the whole mapping, see [`call::mprotect`]. Only the `PROT_*`
+    /// bits of `prot` are used.
+    pub fn protect(&mut self, prot: MapFlags) -> Result<()> {
+        let prot = prot & PROT_MASK;
+        if self.len != 0 {
+            unsafe { call::mprotect(self.addr, self.pages_len(), prot)? };
+        }
+        self.flags = (self.flags - PROT_MASK) | prot;
+        Ok(())
+    }
+    /// Shorten the mapping to `len` bytes, unmapping the pages after it. Does nothing if `len`
+    /// is not less than the current length.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len >= self.len {
+            return Ok(());
+        }
+        let keep = len.next_multiple_of(PAGE_SIZE);
+        let end = self.pages_len();
+        if keep < end {
+            unsafe { call::funmap(self.addr + keep, end - keep)? };
+        }
+        self.len = len;
+        Ok(())
+    }
+    /// Split the mapping in two at `at`, returning the part from `at` onwards.
+    ///
+    /// Fails with `EINVAL` if `at` is not page aligned or is past the end of the mapping.
+    pub fn split_off(&mut self, at: usize) -> Result<Self> {
+        if !at.is_multiple_of(PAGE_SIZE) || at > self.len {
+            return Err(Error::new(EINVAL));
+        }
+        let tail = Self {
+            addr: self.addr + at,
+            len: self.len - at,
+            flags: self.flags,
+        };
+        self.len = at;
+        Ok(tail)
+    }
+
+    fn pages_len(&self) -> usize {
+        self.len.next_multiple_of(PAGE_SIZE)
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            let _ = unsafe { call::funmap(self.addr, self.pages_len()) };
+        }
+    }
+}
+
+impl fmt::Debug for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mapping")
+            .field("addr", &format_args!("{:#x}", self.addr))
+            .field("len", &self.len)
+            .field("flags", &self.flags)
+            .finish()
+    }
+}
+
+// Owning a mapping is like owning a Box<[u8]>.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::Mapping;
+    use crate::{backend::*, call, data::Map, error::*, flag::*, number::*, PAGE_SIZE};
+
+    #[test]
+    fn map_protect_split() {
+        let kernel = Rc::new(FakeKernel::new());
+        let mut contents = vec![0; 3 * PAGE_SIZE];
+        contents[..5].copy_from_slice(b"hello");
+        contents[2 * PAGE_SIZE] = 42;
+        kernel.add_file("file:data", &contents);
+
+        with_backend(kernel.clone(), || {
+            let fd = call::openat_into(0, 3, "file:data", O_RDWR, 0).unwrap();
+            let map = Map {
+                offset: 0,
+                size: 3 * PAGE_SIZE,
+                flags: MapFlags::PROT_READ | MapFlags::MAP_PRIVATE,
+                address: 0,
+            };
+            let mut mapping = unsafe { Mapping::map(fd, &map) }.unwrap();
+            assert_eq!(&mapping.as_slice().unwrap()[..5], b"hello");
+            assert!(mapping.as_mut_slice().is_none());
+
+            mapping
+                .protect(MapFlags::PROT_READ | MapFlags::PROT_WRITE)
+                .unwrap();
+            assert!(mapping.flags().contains(MapFlags::MAP_PRIVATE));
+            mapping.as_mut_slice().unwrap()[0] = b'j';
+            assert_eq!(&mapping.as_slice().unwrap()[..5], b"jello");
+
+            assert_eq!(mapping.split_off(1).unwrap_err(), Error::new(EINVAL));
+            let tail = mapping.split_off(2 * PAGE_SIZE).unwrap();
+            assert_eq!(tail.len(), PAGE_SIZE);
+            assert_eq!(tail.as_slice().unwrap()[0], 42);
+            drop(tail);
+            assert_eq!(kernel.mapped_pages().len(), 2);
+
+            mapping.truncate(10).unwrap();
+            assert_eq!(mapping.len(), 10);
+            assert_eq!(kernel.mapped_pages().len(), 1);
+            drop(mapping);
+            call::close(fd).unwrap();
+        });
+
+        assert!(kernel.mapped_pages().is_empty());
+        // Private mappings never change the file.
+        assert_eq!(kernel.file("file:data").unwrap(), contents);
+        let unmaps = kernel.records();
+        let unmaps = unmaps.iter().filter(|r| r.number == SYS_FUNMAP);
+        assert!(unmaps.map(|r| r.result).all(|r| r.is_ok()));
+    }
+}
@@ -46,3 +46,215 @@ pub const SYS_FUTEX: usize = 240;
 pub const SYS_MPROTECT: usize = 125;
 pub const SYS_NANOSLEEP: usize = 162;
 pub const SYS_YIELD: usize = 158;
+
+/// The class encoded in the [`SYS_CLASS`] bits of a system call number
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SyscallClass {
+    /// [`SYS_CLASS_PATH`], the call resolves a path relative to the fd in its first argument
+    Path,
+    /// [`SYS_CLASS_FILE`], the call operates on the fd in its first argument
+    File,
+}
+
+/// The kind of buffer encoded in the [`SYS_ARG`] bits of a system call number
+///
+/// The buffer is always passed as a pointer in the second argument and a length in the third.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SyscallArg {
+    /// [`SYS_ARG_SLICE`], read by the kernel
+    Slice,
+    /// [`SYS_ARG_MSLICE`], written by the kernel
+    MutSlice,
+    /// [`SYS_ARG_PATH`], a UTF-8 path read by the kernel
+    Path,
+}
+
+/// Every known system call number
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum SyscallNumber {
+    OpenatInto = SYS_OPENAT_INTO,
+    Unlinkat = SYS_UNLINKAT,
+
+    Close = SYS_CLOSE,
+    DupInto = SYS_DUP_INTO,
+    Dup2 = SYS_DUP2,
+    Read = SYS_READ,
+    Read2 = SYS_READ2,
+    Write = SYS_WRITE,
+    Write2 = SYS_WRITE2,
+    Lseek = SYS_LSEEK,
+    Fchown = SYS_FCHOWN,
+    Fcntl = SYS_FCNTL,
+    Fevent = SYS_FEVENT,
+
+    Call = SYS_CALL,
+
+    Fmap = SYS_FMAP,
+    Funmap = SYS_FUNMAP,
+    Mremap = SYS_MREMAP,
+
+    Flink = SYS_FLINK,
+    Fpath = SYS_FPATH,
+    Frename = SYS_FRENAME,
+    Fstat = SYS_FSTAT,
+    Fsync = SYS_FSYNC,
+
+    ClockGettime = SYS_CLOCK_GETTIME,
+    Futex = SYS_FUTEX,
+    Mprotect = SYS_MPROTECT,
+    Nanosleep = SYS_NANOSLEEP,
+    Yield = SYS_YIELD,
+}
+
+impl SyscallNumber {
+    pub const ALL: &[Self] = &[
+        Self::OpenatInto,
+        Self::Unlinkat,
+        Self::Close,
+        Self::DupInto,
+        Self::Dup2,
+        Self::Read,
+        Self::Read2,
+        Self::Write,
+        Self::Write2,
+        Self::Lseek,
+        Self::Fchown,
+        Self::Fcntl,
+        Self::Fevent,
+        Self::Call,
+        Self::Fmap,
+        Self::Funmap,
+        Self::Mremap,
+        Self::Flink,
+        Self::Fpath,
+        Self::Frename,
+        Self::Fstat,
+        Self::Fsync,
+        Self::ClockGettime,
+        Self::Futex,
+        Self::Mprotect,
+        Self::Nanosleep,
+        Self::Yield,
+    ];
+
+    pub fn try_from_raw(raw: usize) -> Option<Self> {
+        Self::ALL.iter().copied().find(|number| number.raw() == raw)
+    }
+    pub const fn raw(self) -> usize {
+        self as usize
+    }
+    /// The name of the system call, as used by the wrappers in `call`
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::OpenatInto => "openat_into",
+            Self::Unlinkat => "unlinkat",
+            Self::Close => "close",
+            Self::DupInto => "dup_into",
+            Self::Dup2 => "dup2",
+            Self::Read => "read",
+            Self::Read2 => "read2",
+            Self::Write => "write",
+            Self::Write2 => "write2",
+            Self::Lseek => "lseek",
+            Self::Fchown => "fchown",
+            Self::Fcntl => "fcntl",
+            Self::Fevent => "fevent",
+            Self::Call => "call",
+            Self::Fmap => "fmap",
+            Self::Funmap => "funmap",
+            Self::Mremap => "mremap",
+            Self::Flink => "flink",
+            Self::Fpath => "fpath",
+            Self::Frename => "frename",
+            Self::Fstat => "fstat",
+            Self::Fsync => "fsync",
+            Self::ClockGettime => "clock_gettime",
+            Self::Futex => "futex",
+            Self::Mprotect => "mprotect",
+            Self::Nanosleep => "nanosleep",
+            Self::Yield => "yield",
+        }
+    }
+    pub const fn class(self) -> Option<SyscallClass> {
+        match self.raw() & SYS_CLASS {
+            SYS_CLASS_PATH => Some(SyscallClass::Path),
+            SYS_CLASS_FILE => Some(SyscallClass::File),
+            _ => None,
+        }
+    }
+    pub const fn arg(self) -> Option<SyscallArg> {
+        // SYS_CALL sets both SYS_ARG_SLICE and SYS_ARG_MSLICE, which reads as SYS_ARG_PATH. Its
+        // payload is both read and written.
+        if let Self::Call = self {
+            return Some(SyscallArg::MutSlice);
+        }
+        // Path-class calls carry no SYS_ARG bits, but all of them take a path.
+        if let Some(SyscallClass::Path) = self.class() {
+            return Some(SyscallArg::Path);
+        }
+        match self.raw() & SYS_ARG {
+            SYS_ARG_SLICE => Some(SyscallArg::Slice),
+            SYS_ARG_MSLICE => Some(SyscallArg::MutSlice),
+            SYS_ARG_PATH => Some(SyscallArg::Path),
+            _ => None,
+        }
+    }
+    /// Whether a successful result is a new file descriptor ([`SYS_RET_FILE`])
+    pub const fn returns_fd(self) -> bool {
+        self.raw() & SYS_RET == SYS_RET_FILE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let all = [
+            SYS_OPENAT_INTO,
+            SYS_UNLINKAT,
+            SYS_CLOSE,
+            SYS_DUP_INTO,
+            SYS_DUP2,
+            SYS_READ,
+            SYS_READ2,
+            SYS_WRITE,
+            SYS_WRITE2,
+            SYS_LSEEK,
+            SYS_FCHOWN,
+            SYS_FCNTL,
+            SYS_FEVENT,
+            SYS_CALL,
+            SYS_FMAP,
+            SYS_FUNMAP,
+            SYS_MREMAP,
+            SYS_FLINK,
+            SYS_FPATH,
+            SYS_FRENAME,
+            SYS_FSTAT,
+            SYS_FSYNC,
+            SYS_CLOCK_GETTIME,
+            SYS_FUTEX,
+            SYS_MPROTECT,
+            SYS_NANOSLEEP,
+            SYS_YIELD,
+        ];
+        assert_eq!(all.len(), SyscallNumber::ALL.len());
+        for raw in all {
+            let number = SyscallNumber::try_from_raw(raw).unwrap();
+            assert_eq!(number.raw(), raw);
+        }
+        assert_eq!(SyscallNumber::try_from_raw(0), None);
+
+        assert_eq!(SyscallNumber::Read.arg(), Some(SyscallArg::MutSlice));
+        assert_eq!(SyscallNumber::Flink.arg(), Some(SyscallArg::Path));
+        assert_eq!(SyscallNumber::OpenatInto.arg(), Some(SyscallArg::Path));
+        assert_eq!(SyscallNumber::Unlinkat.arg(), Some(SyscallArg::Path));
+        assert_eq!(SyscallNumber::Lseek.arg(), None);
+        assert_eq!(SyscallNumber::OpenatInto.class(), Some(SyscallClass::Path));
+        assert!(SyscallNumber::OpenatInto.returns_fd());
+        assert!(!SyscallNumber::Read.returns_fd());
+    }
+}
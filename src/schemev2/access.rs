@@ -0,0 +1,117 @@
+use super::CallerCtx;
+use crate::{
+    error::*,
+    flag::{MODE_PERM, MODE_SETGID, MODE_SETUID, O_ACCMODE, O_RDONLY, O_WRONLY},
+};
+
+/// Unix-style permission checks, for files with permissions `mode` owned by `uid` and `gid`.
+impl CallerCtx {
+    /// Whether the caller is root, which passes every check.
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+    /// The owner, group or other permission bits of `mode` that apply to the caller, as
+    /// read (`0o4`), write (`0o2`) and execute (`0o1`).
+    pub fn perm(&self, mode: u16, uid: u32, gid: u32) -> u16 {
+        let shift = if self.uid == uid {
+            6
+        } else if self.gid == gid {
+            3
+        } else {
+            0
+        };
+        (mode & MODE_PERM) >> shift & 0o7
+    }
+    /// Check that the caller may open the file for the access in `flags & O_ACCMODE`.
+    ///
+    /// Fails with `EACCES` otherwise.
+    pub fn check_access(&self, mode: u16, uid: u32, gid: u32, flags: usize) -> Result<()> {
+        let flags = flags & O_ACCMODE;
+        let mut wanted = 0;
+        if flags & O_RDONLY == O_RDONLY {
+            wanted |= 0o4;
+        }
+        if flags & O_WRONLY == O_WRONLY {
+            wanted |= 0o2;
+        }
+        if self.is_root() || self.perm(mode, uid, gid) & wanted == wanted {
+            Ok(())
+        } else {
+            Err(Error::new(EACCES))
+        }
+    }
+    /// The mode to store when the caller changes the permissions of the file to `new_mode`.
+    /// The set-group-ID bit is dropped unless the caller is in the file's group.
+    ///
+    /// Fails with `EPERM` unless the caller owns the file.
+    pub fn check_chmod(&self, uid: u32, gid: u32, new_mode: u16) -> Result<u16> {
+        if !self.is_root() && self.uid != uid {
+            return Err(Error::new(EPERM));
+        }
+        let mut mode = new_mode & MODE_PERM;
+        if !self.is_root() && self.gid != gid {
+            mode &= !MODE_SETGID;
+        }
+        Ok(mode)
+    }
+    /// The permissions of the file after the caller writes to it or changes its owner, which
+    /// drops the set-user-ID and set-group-ID bits unless the caller is root.
+    pub fn drop_setid(&self, mode: u16) -> u16 {
+        match self.is_root() {
+            true => mode,
+            false => mode & !(MODE_SETUID | MODE_SETGID),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::*,
+        flag::{MODE_FILE, MODE_SETGID, MODE_SETUID, O_RDONLY, O_RDWR, O_STAT, O_WRONLY},
+        schemev2::CallerCtx,
+    };
+
+    fn ctx(uid: u32, gid: u32) -> CallerCtx {
+        CallerCtx { pid: 1, uid, gid }
+    }
+
+    #[test]
+    fn access() {
+        let mode = MODE_FILE | 0o640;
+        let (owner, group, other) = (ctx(1000, 100), ctx(1001, 100), ctx(1002, 200));
+        assert_eq!(owner.perm(mode, 1000, 100), 0o6);
+        assert_eq!(group.perm(mode, 1000, 100), 0o4);
+        assert_eq!(other.perm(mode, 1000, 100), 0);
+
+        assert_eq!(owner.check_access(mode, 1000, 100, O_RDWR), Ok(()));
+        assert_eq!(group.check_access(mode, 1000, 100, O_RDONLY), Ok(()));
+        assert_eq!(
+            group.check_access(mode, 1000, 100, O_WRONLY),
+            Err(Error::new(EACCES))
+        );
+        assert_eq!(
+            other.check_access(mode, 1000, 100, O_RDONLY),
+            Err(Error::new(EACCES))
+        );
+        // Flags outside O_ACCMODE are ignored, and no access is always allowed.
+        assert_eq!(other.check_access(mode, 1000, 100, O_STAT), Ok(()));
+        assert_eq!(ctx(0, 0).check_access(0, 1000, 100, O_RDWR), Ok(()));
+    }
+
+    #[test]
+    fn setid() {
+        let (owner, stranger) = (ctx(1000, 200), ctx(1001, 100));
+        let mode = MODE_SETUID | MODE_SETGID | 0o755;
+        assert_eq!(owner.check_chmod(1000, 100, mode), Ok(MODE_SETUID | 0o755));
+        assert_eq!(ctx(1000, 100).check_chmod(1000, 100, mode), Ok(mode));
+        assert_eq!(ctx(0, 0).check_chmod(1000, 100, mode), Ok(mode));
+        assert_eq!(
+            stranger.check_chmod(1000, 100, mode),
+            Err(Error::new(EPERM))
+        );
+
+        assert_eq!(stranger.drop_setid(MODE_FILE | mode), MODE_FILE | 0o755);
+        assert_eq!(ctx(0, 0).drop_setid(mode), mode);
+    }
+}
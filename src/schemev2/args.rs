@@ -0,0 +1,454 @@
+use core::{mem, slice, str};
+
+use super::Request;
+use crate::{
+    data::{Stat, StatVfs, StdFsCallMeta, TimeSpec},
+    dirent::DirentBuf,
+    error::*,
+    flag::{EventFlags, MapFlags, MremapFlags, MsyncFlags, MunmapFlags, RecvFdFlags, SendFdFlags},
+};
+
+/// A [`Request`] with its buffers turned into the arguments of the matching
+/// [`SchemeSync`](super::SchemeSync) and [`SchemeAsync`](super::SchemeAsync) methods.
+pub(super) enum Op<'a> {
+    OpenAt {
+        dirfd: usize,
+        path: &'a str,
+        flags: usize,
+        fcntl_flags: u32,
+    },
+    UnlinkAt {
+        dirfd: usize,
+        path: &'a str,
+        flags: usize,
+    },
+    Dup {
+        old_id: usize,
+        buf: &'a [u8],
+    },
+
+    Read {
+        id: usize,
+        buf: &'a mut [u8],
+        offset: u64,
+        fcntl_flags: u32,
+    },
+    Write {
+        id: usize,
+        buf: &'a [u8],
+        offset: u64,
+        fcntl_flags: u32,
+    },
+    Fsize {
+        id: usize,
+    },
+    Fchmod {
+        id: usize,
+        mode: u16,
+    },
+    Fchown {
+        id: usize,
+        uid: u32,
+        gid: u32,
+    },
+    Fcntl {
+        id: usize,
+        cmd: usize,
+        arg: usize,
+    },
+    Fevent {
+        id: usize,
+        flags: EventFlags,
+    },
+    Sendfd {
+        id: usize,
+        flags: SendFdFlags,
+        arg: u64,
+        num_fds: usize,
+    },
+    Recvfd {
+        id: usize,
+        flags: RecvFdFlags,
+        num_fds: usize,
+    },
+    Fpath {
+        id: usize,
+        buf: &'a mut [u8],
+    },
+    Flink {
+        id: usize,
+        path: &'a str,
+    },
+    Frename {
+        id: usize,
+        path: &'a str,
+    },
+    Fstat {
+        id: usize,
+        stat: &'a mut Stat,
+    },
+    Fstatvfs {
+        id: usize,
+        stat: &'a mut StatVfs,
+    },
+    Fsync {
+        id: usize,
+    },
+    Ftruncate {
+        id: usize,
+        len: u64,
+    },
+    Futimens {
+        id: usize,
+        times: &'a [TimeSpec],
+    },
+    Getdents {
+        id: usize,
+        buf: DirentBuf<&'a mut [u8]>,
+        opaque_offset: u64,
+    },
+
+    MmapPrep {
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MapFlags,
+    },
+    Munmap {
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MunmapFlags,
+    },
+    RequestMmap {
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MapFlags,
+    },
+    Mremap {
+        id: usize,
+        offset: u64,
+        old_size: usize,
+        new_size: usize,
+        flags: MremapFlags,
+    },
+    Msync {
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MsyncFlags,
+    },
+
+    Call {
+        id: usize,
+        payload: &'a mut [u8],
+        metadata: &'a [u64],
+    },
+    CallWithIds {
+        ids: &'a [usize],
+        payload: &'a mut [u8],
+        metadata: &'a [u64],
+    },
+    StdFsCall {
+        id: usize,
+        payload: &'a mut [u8],
+        meta: StdFsCallMeta,
+    },
+
+    Close {
+        id: usize,
+    },
+    CloseMsg {
+        id: usize,
+    },
+    Detach {
+        id: usize,
+    },
+    Cancel,
+}
+
+impl Op<'_> {
+    /// Check and borrow the buffers of `request`.
+    ///
+    /// Fails with `EFAULT` for null buffers, with `EINVAL` for misaligned or wrongly sized ones
+    /// and paths that are not UTF-8.
+    ///
+    /// # Safety
+    ///
+    /// The pointers in `request` must be valid for as long as the returned `Op` is used.
+    pub(super) unsafe fn from_request(request: Request) -> Result<Self> {
+        Ok(match request {
+            Request::OpenAt {
+                dirfd,
+                path,
+                path_len,
+                flags,
+                fcntl_flags,
+            } => Op::OpenAt {
+                dirfd,
+                path: str_arg(path, path_len)?,
+                flags,
+                fcntl_flags,
+            },
+            Request::UnlinkAt {
+                dirfd,
+                path,
+                path_len,
+                flags,
+            } => Op::UnlinkAt {
+                dirfd,
+                path: str_arg(path, path_len)?,
+                flags,
+            },
+            Request::Dup { old_id, buf, len } => Op::Dup {
+                old_id,
+                buf: slice_arg(buf, len)?,
+            },
+
+            Request::Read {
+                id,
+                buf,
+                len,
+                offset,
+                fcntl_flags,
+            } => Op::Read {
+                id,
+                buf: slice_arg_mut(buf, len)?,
+                offset,
+                fcntl_flags,
+            },
+            Request::Write {
+                id,
+                buf,
+                len,
+                offset,
+                fcntl_flags,
+            } => Op::Write {
+                id,
+                buf: slice_arg(buf, len)?,
+                offset,
+                fcntl_flags,
+            },
+            Request::Fsize { id } => Op::Fsize { id },
+            Request::Fchmod { id, mode } => Op::Fchmod { id, mode },
+            Request::Fchown { id, uid, gid } => Op::Fchown { id, uid, gid },
+            Request::Fcntl { id, cmd, arg } => Op::Fcntl { id, cmd, arg },
+            Request::Fevent { id, flags } => Op::Fevent { id, flags },
+            Request::Sendfd {
+                id,
+                flags,
+                arg,
+                num_fds,
+            } => Op::Sendfd {
+                id,
+                flags,
+                arg,
+                num_fds,
+            },
+            Request::Recvfd { id, flags, num_fds } => Op::Recvfd { id, flags, num_fds },
+            Request::Fpath { id, buf, len } => Op::Fpath {
+                id,
+                buf: slice_arg_mut(buf, len)?,
+            },
+            Request::Flink { id, path, path_len } => Op::Flink {
+                id,
+                path: str_arg(path, path_len)?,
+            },
+            Request::Frename { id, path, path_len } => Op::Frename {
+                id,
+                path: str_arg(path, path_len)?,
+            },
+            Request::Fstat { id, buf, len } => Op::Fstat {
+                id,
+                stat: struct_arg_mut(buf, len)?,
+            },
+            Request::Fstatvfs { id, buf, len } => Op::Fstatvfs {
+                id,
+                stat: struct_arg_mut(buf, len)?,
+            },
+            Request::Fsync { id } => Op::Fsync { id },
+            Request::Ftruncate { id, len } => Op::Ftruncate { id, len },
+            Request::Futimens { id, buf, len } => Op::Futimens {
+                id,
+                times: times_arg(buf, len)?,
+            },
+            Request::Getdents {
+                id,
+                buf,
+                len,
+                header_size,
+                opaque_offset,
+            } => Op::Getdents {
+                id,
+                buf: DirentBuf::new(slice_arg_mut(buf, len)?, header_size)
+                    .ok_or(Error::new(EINVAL))?,
+                opaque_offset,
+            },
+
+            Request::MmapPrep {
+                id,
+                offset,
+                size,
+                flags,
+            } => Op::MmapPrep {
+                id,
+                offset,
+                size,
+                flags,
+            },
+            Request::Munmap {
+                id,
+                offset,
+                size,
+                flags,
+            } => Op::Munmap {
+                id,
+                offset,
+                size,
+                flags,
+            },
+            Request::RequestMmap {
+                id,
+                offset,
+                size,
+                flags,
+            } => Op::RequestMmap {
+                id,
+                offset,
+                size,
+                flags,
+            },
+            Request::Mremap {
+                id,
+                offset,
+                old_size,
+                new_size,
+                flags,
+            } => Op::Mremap {
+                id,
+                offset,
+                old_size,
+                new_size,
+                flags,
+            },
+            Request::Msync {
+                id,
+                offset,
+                size,
+                flags,
+            } => Op::Msync {
+                id,
+                offset,
+                size,
+                flags,
+            },
+
+            Request::Call {
+                id,
+                payload,
+                payload_len,
+                metadata,
+                metadata_len,
+            } => Op::Call {
+                id,
+                payload: slice_arg_mut(payload, payload_len)?,
+                metadata: array_arg(metadata, metadata_len)?,
+            },
+            Request::CallWithIds {
+                ids,
+                ids_len,
+                payload,
+                payload_len,
+                metadata,
+                metadata_len,
+            } => Op::CallWithIds {
+                ids: ids_arg(ids, ids_len)?,
+                payload: slice_arg_mut(payload, payload_len)?,
+                metadata: array_arg(metadata, metadata_len)?,
+            },
+            Request::StdFsCall {
+                id,
+                payload,
+                payload_len,
+                metadata,
+                metadata_len,
+            } => Op::StdFsCall {
+                id,
+                payload: slice_arg_mut(payload, payload_len)?,
+                meta: std_fs_meta_arg(metadata, metadata_len)?,
+            },
+
+            Request::Close { id } => Op::Close { id },
+            Request::CloseMsg { id } => Op::CloseMsg { id },
+            Request::Detach { id } => Op::Detach { id },
+            Request::Cancel => Op::Cancel,
+        })
+    }
+}
+
+unsafe fn slice_arg<'a>(ptr: usize, len: usize) -> Result<&'a [u8]> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    if ptr == 0 {
+        return Err(Error::new(EFAULT));
+    }
+    Ok(slice::from_raw_parts(ptr as *const u8, len))
+}
+unsafe fn slice_arg_mut<'a>(ptr: usize, len: usize) -> Result<&'a mut [u8]> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    if ptr == 0 {
+        return Err(Error::new(EFAULT));
+    }
+    Ok(slice::from_raw_parts_mut(ptr as *mut u8, len))
+}
+unsafe fn str_arg<'a>(ptr: usize, len: usize) -> Result<&'a str> {
+    str::from_utf8(slice_arg(ptr, len)?).map_err(|_| Error::new(EINVAL))
+}
+/// `count` values of `T` at `ptr`.
+unsafe fn array_arg<'a, T>(ptr: usize, count: usize) -> Result<&'a [T]> {
+    if count == 0 {
+        return Ok(&[]);
+    }
+    if ptr == 0 {
+        return Err(Error::new(EFAULT));
+    }
+    if !ptr.is_multiple_of(mem::align_of::<T>()) {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(slice::from_raw_parts(ptr as *const T, count))
+}
+/// The ids in the `len` bytes at `ptr`, like [`Sqe::ids`](super::Sqe::ids).
+unsafe fn ids_arg<'a>(ptr: usize, len: usize) -> Result<&'a [usize]> {
+    if !len.is_multiple_of(mem::size_of::<usize>()) {
+        return Err(Error::new(EINVAL));
+    }
+    array_arg(ptr, len / mem::size_of::<usize>())
+}
+/// The `StdFsCallMeta` in the `count` words at `ptr`.
+unsafe fn std_fs_meta_arg(ptr: usize, count: usize) -> Result<StdFsCallMeta> {
+    let words = array_arg::<u64>(ptr, count)?;
+    if mem::size_of_val(words) != mem::size_of::<StdFsCallMeta>() {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(words.as_ptr().cast::<StdFsCallMeta>().read())
+}
+/// The `TimeSpec`s in the `len` bytes at `ptr`.
+unsafe fn times_arg<'a>(ptr: usize, len: usize) -> Result<&'a [TimeSpec]> {
+    if !len.is_multiple_of(mem::size_of::<TimeSpec>()) {
+        return Err(Error::new(EINVAL));
+    }
+    array_arg(ptr, len / mem::size_of::<TimeSpec>())
+}
+unsafe fn struct_arg_mut<'a, T>(ptr: usize, len: usize) -> Result<&'a mut T> {
+    if len != mem::size_of::<T>() || !ptr.is_multiple_of(mem::align_of::<T>()) {
+        return Err(Error::new(EINVAL));
+    }
+    if ptr == 0 {
+        return Err(Error::new(EFAULT));
+    }
+    Ok(&mut *(ptr as *mut T))
+}
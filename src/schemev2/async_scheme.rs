@@ -0,0 +1,682 @@
+use core::future::Future;
+
+use super::{CallerCtx, OpenResult};
+use crate::{
+    data::{Stat, StatVfs, StdFsCallMeta, TimeSpec},
+    dirent::DirentBuf,
+    error::*,
+    flag::{EventFlags, MapFlags, MremapFlags, MsyncFlags, MunmapFlags, RecvFdFlags, SendFdFlags},
+};
+
+/// A scheme that can have many requests in flight, and completes them in any order.
+///
+/// This mirrors [`SchemeSync`](super::SchemeSync), but takes `&self` and returns futures, which
+/// `AsyncDriver` (with the `alloc` feature) polls until they complete. A future is dropped
+/// without being polled to completion if its request is cancelled.
+#[allow(unused_variables)]
+pub trait SchemeAsync {
+    fn openat(
+        &self,
+        dirfd: usize,
+        path: &str,
+        flags: usize,
+        fcntl_flags: u32,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<OpenResult>> {
+        async { Err(Error::new(ENOENT)) }
+    }
+    fn unlinkat(
+        &self,
+        dirfd: usize,
+        path: &str,
+        flags: usize,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<()>> {
+        async { Err(Error::new(ENOENT)) }
+    }
+    fn dup(
+        &self,
+        old_id: usize,
+        buf: &[u8],
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<OpenResult>> {
+        async { Err(Error::new(EBADF)) }
+    }
+
+    fn read(
+        &self,
+        id: usize,
+        buf: &mut [u8],
+        offset: u64,
+        fcntl_flags: u32,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<usize>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn write(
+        &self,
+        id: usize,
+        buf: &[u8],
+        offset: u64,
+        fcntl_flags: u32,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<usize>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn fsize(&self, id: usize, ctx: CallerCtx) -> impl Future<Output = Result<u64>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn fchmod(&self, id: usize, new_mode: u16, ctx: CallerCtx) -> impl Future<Output = Result<()>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn fchown(
+        &self,
+        id: usize,
+        new_uid: u32,
+        new_gid: u32,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<()>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn fcntl(
+        &self,
+        id: usize,
+        cmd: usize,
+        arg: usize,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<usize>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn fevent(
+        &self,
+        id: usize,
+        flags: EventFlags,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<EventFlags>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    /// `num_fds` fds are being sent to `id`, which the scheme takes with
+    /// [`Cqe::obtain_fd`](super::Cqe::obtain_fd) before answering.
+    fn sendfd(
+        &self,
+        id: usize,
+        flags: SendFdFlags,
+        arg: u64,
+        num_fds: usize,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<usize>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    /// Up to `num_fds` fds are being received from `id`.
+    fn recvfd(
+        &self,
+        id: usize,
+        flags: RecvFdFlags,
+        num_fds: usize,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<OpenResult>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn fpath(
+        &self,
+        id: usize,
+        buf: &mut [u8],
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<usize>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn flink(&self, id: usize, path: &str, ctx: CallerCtx) -> impl Future<Output = Result<()>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn frename(&self, id: usize, path: &str, ctx: CallerCtx) -> impl Future<Output = Result<()>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn fstat(
+        &self,
+        id: usize,
+        stat: &mut Stat,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<()>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn fstatvfs(
+        &self,
+        id: usize,
+        stat: &mut StatVfs,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<()>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn fsync(&self, id: usize, ctx: CallerCtx) -> impl Future<Output = Result<()>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn ftruncate(&self, id: usize, len: u64, ctx: CallerCtx) -> impl Future<Output = Result<()>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn futimens(
+        &self,
+        id: usize,
+        times: &[TimeSpec],
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<()>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn getdents<'buf>(
+        &self,
+        id: usize,
+        buf: DirentBuf<&'buf mut [u8]>,
+        opaque_offset: u64,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<DirentBuf<&'buf mut [u8]>>> {
+        async { Err(Error::new(EBADF)) }
+    }
+
+    fn mmap_prep(
+        &self,
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MapFlags,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<usize>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn munmap(
+        &self,
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MunmapFlags,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<()>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn request_mmap(
+        &self,
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MapFlags,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<usize>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    /// A mapping at `offset` is being resized from `old_size` to `new_size` bytes, returning
+    /// the address of the scheme's memory backing it.
+    fn mremap(
+        &self,
+        id: usize,
+        offset: u64,
+        old_size: usize,
+        new_size: usize,
+        flags: MremapFlags,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<usize>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn msync(
+        &self,
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MsyncFlags,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<()>> {
+        async { Err(Error::new(EBADF)) }
+    }
+
+    fn call(
+        &self,
+        id: usize,
+        payload: &mut [u8],
+        metadata: &[u64],
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<usize>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn call_with_ids(
+        &self,
+        ids: &[usize],
+        payload: &mut [u8],
+        metadata: &[u64],
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<usize>> {
+        async { Err(Error::new(EBADF)) }
+    }
+    fn std_fs_call(
+        &self,
+        id: usize,
+        payload: &mut [u8],
+        meta: StdFsCallMeta,
+        ctx: CallerCtx,
+    ) -> impl Future<Output = Result<usize>> {
+        async { Err(Error::new(EBADF)) }
+    }
+
+    /// The last fd referring to `id` was closed. This is never answered, so unlike the other
+    /// methods it runs to completion immediately.
+    fn on_close(&self, id: usize) {}
+    fn on_detach(&self, id: usize, ctx: CallerCtx) -> impl Future<Output = Result<()>> {
+        async { Ok(()) }
+    }
+}
+
+#[cfg(any(feature = "alloc", test))]
+pub use self::driver::AsyncDriver;
+
+#[cfg(any(feature = "alloc", test))]
+mod driver {
+    use alloc::{boxed::Box, collections::BTreeMap};
+    use core::{
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use super::SchemeAsync;
+    use crate::{
+        error::*,
+        schemev2::{
+            args::Op,
+            sync_scheme::{reply_cqe, Reply},
+            CallerCtx, Cqe, Request, Sqe, SqeFlags,
+        },
+    };
+
+    type Handler<'s> = Pin<Box<dyn Future<Output = Result<Reply>> + 's>>;
+
+    struct Pending<'s> {
+        sqe: Sqe,
+        future: Handler<'s>,
+    }
+
+    /// Tracks the requests a [`SchemeAsync`] is working on, and turns them into [`Cqe`]s as
+    /// they complete.
+    ///
+    /// The driver does not depend on any executor: [`AsyncDriver::poll`] polls every pending
+    /// request with the waker it is given, and should be called again when that waker is woken.
+    pub struct AsyncDriver<'s, S: SchemeAsync + ?Sized> {
+        scheme: &'s S,
+        /// Requests by submission order, so they are polled fairly.
+        pending: BTreeMap<u64, Pending<'s>>,
+        /// The pending request for each tag that needs a response.
+        tags: BTreeMap<u32, u64>,
+        next: u64,
+    }
+
+    impl<'s, S: SchemeAsync + ?Sized> AsyncDriver<'s, S> {
+        pub fn new(scheme: &'s S) -> Self {
+            Self {
+                scheme,
+                pending: BTreeMap::new(),
+                tags: BTreeMap::new(),
+                next: 0,
+            }
+        }
+        pub fn scheme(&self) -> &'s S {
+            self.scheme
+        }
+        /// Number of requests that have not completed yet.
+        pub fn len(&self) -> usize {
+            self.pending.len()
+        }
+        pub fn is_empty(&self) -> bool {
+            self.pending.is_empty()
+        }
+        pub fn is_pending(&self, tag: u32) -> bool {
+            self.tags.contains_key(&tag)
+        }
+
+        /// Start handling `sqe`, polling it once. Returns the response if it completed right
+        /// away.
+        ///
+        /// [`Request::Cancel`] drops the pending request with the same tag, which is then never
+        /// answered. A request reusing the tag of a pending one fails with `EBUSY`.
+        ///
+        /// # Safety
+        ///
+        /// The pointers in `sqe.args` must stay valid until the request completes or is
+        /// cancelled, as they do for requests read from the kernel.
+        pub unsafe fn submit(&mut self, sqe: &Sqe, cx: &mut Context<'_>) -> Option<Cqe> {
+            let oneway = sqe.sqe_flags.contains(SqeFlags::ONEWAY);
+            let request = match Request::decode(sqe) {
+                Ok(request) => request,
+                Err(err) => return reply_cqe(sqe, Err(err)),
+            };
+            if let Request::Cancel = request {
+                if let Some(key) = self.tags.remove(&sqe.tag) {
+                    self.pending.remove(&key);
+                }
+                return None;
+            }
+            if !oneway && self.tags.contains_key(&sqe.tag) {
+                return reply_cqe(sqe, Err(Error::new(EBUSY)));
+            }
+            let mut future = match self.start(request, CallerCtx::from_sqe(sqe)) {
+                Ok(future) => future,
+                Err(err) => return reply_cqe(sqe, Err(err)),
+            };
+            if let Poll::Ready(reply) = future.as_mut().poll(cx) {
+                return reply_cqe(sqe, reply);
+            }
+
+            let key = self.next;
+            self.next += 1;
+            if !oneway {
+                self.tags.insert(sqe.tag, key);
+            }
+            self.pending.insert(key, Pending { sqe: *sqe, future });
+            None
+        }
+
+        /// Poll every pending request, and call `complete` with the response of each one that
+        /// completed.
+        pub fn poll(&mut self, cx: &mut Context<'_>, mut complete: impl FnMut(Cqe)) {
+            self.pending.retain(|_, pending| {
+                let Poll::Ready(reply) = pending.future.as_mut().poll(cx) else {
+                    return true;
+                };
+                if !pending.sqe.sqe_flags.contains(SqeFlags::ONEWAY) {
+                    self.tags.remove(&pending.sqe.tag);
+                }
+                if let Some(cqe) = reply_cqe(&pending.sqe, reply) {
+                    complete(cqe);
+                }
+                false
+            });
+        }
+
+        unsafe fn start(&self, request: Request, ctx: CallerCtx) -> Result<Handler<'s>> {
+            let scheme = self.scheme;
+
+            fn boxed<'s, T: 's>(
+                future: impl Future<Output = Result<T>> + 's,
+                reply: impl FnOnce(T) -> Reply + 's,
+            ) -> Handler<'s> {
+                Box::pin(async move { future.await.map(reply) })
+            }
+            let done = |()| Reply::Regular(0);
+
+            Ok(match Op::from_request(request)? {
+                Op::OpenAt {
+                    dirfd,
+                    path,
+                    flags,
+                    fcntl_flags,
+                } => boxed(
+                    scheme.openat(dirfd, path, flags, fcntl_flags, ctx),
+                    Reply::Open,
+                ),
+                Op::UnlinkAt { dirfd, path, flags } => {
+                    boxed(scheme.unlinkat(dirfd, path, flags, ctx), done)
+                }
+                Op::Dup { old_id, buf } => boxed(scheme.dup(old_id, buf, ctx), Reply::Open),
+
+                Op::Read {
+                    id,
+                    buf,
+                    offset,
+                    fcntl_flags,
+                } => boxed(
+                    scheme.read(id, buf, offset, fcntl_flags, ctx),
+                    Reply::Regular,
+                ),
+                Op::Write {
+                    id,
+                    buf,
+                    offset,
+                    fcntl_flags,
+                } => boxed(
+                    scheme.write(id, buf, offset, fcntl_flags, ctx),
+                    Reply::Regular,
+                ),
+                Op::Fsize { id } => {
+                    let future = scheme.fsize(id, ctx);
+                    Box::pin(async move {
+                        let size = future.await?;
+                        let size = usize::try_from(size).map_err(|_| Error::new(EOVERFLOW))?;
+                        Ok(Reply::Regular(size))
+                    })
+                }
+                Op::Fchmod { id, mode } => boxed(scheme.fchmod(id, mode, ctx), done),
+                Op::Fchown { id, uid, gid } => boxed(scheme.fchown(id, uid, gid, ctx), done),
+                Op::Fcntl { id, cmd, arg } => {
+                    boxed(scheme.fcntl(id, cmd, arg, ctx), Reply::Regular)
+                }
+                Op::Fevent { id, flags } => boxed(scheme.fevent(id, flags, ctx), |flags| {
+                    Reply::Regular(flags.bits())
+                }),
+                Op::Sendfd {
+                    id,
+                    flags,
+                    arg,
+                    num_fds,
+                } => boxed(scheme.sendfd(id, flags, arg, num_fds, ctx), Reply::Regular),
+                Op::Recvfd { id, flags, num_fds } => {
+                    boxed(scheme.recvfd(id, flags, num_fds, ctx), Reply::Open)
+                }
+                Op::Fpath { id, buf } => boxed(scheme.fpath(id, buf, ctx), Reply::Regular),
+                Op::Flink { id, path } => boxed(scheme.flink(id, path, ctx), done),
+                Op::Frename { id, path } => boxed(scheme.frename(id, path, ctx), done),
+                Op::Fstat { id, stat } => boxed(scheme.fstat(id, stat, ctx), done),
+                Op::Fstatvfs { id, stat } => boxed(scheme.fstatvfs(id, stat, ctx), done),
+                Op::Fsync { id } => boxed(scheme.fsync(id, ctx), done),
+                Op::Ftruncate { id, len } => boxed(scheme.ftruncate(id, len, ctx), done),
+                Op::Futimens { id, times } => boxed(scheme.futimens(id, times, ctx), done),
+                Op::Getdents {
+                    id,
+                    buf,
+                    opaque_offset,
+                } => boxed(scheme.getdents(id, buf, opaque_offset, ctx), |buf| {
+                    Reply::Regular(buf.finalize())
+                }),
+
+                Op::MmapPrep {
+                    id,
+                    offset,
+                    size,
+                    flags,
+                } => boxed(
+                    scheme.mmap_prep(id, offset, size, flags, ctx),
+                    Reply::Regular,
+                ),
+                Op::Munmap {
+                    id,
+                    offset,
+                    size,
+                    flags,
+                } => boxed(scheme.munmap(id, offset, size, flags, ctx), done),
+                Op::RequestMmap {
+                    id,
+                    offset,
+                    size,
+                    flags,
+                } => boxed(
+                    scheme.request_mmap(id, offset, size, flags, ctx),
+                    Reply::ProvideMmap,
+                ),
+                Op::Mremap {
+                    id,
+                    offset,
+                    old_size,
+                    new_size,
+                    flags,
+                } => boxed(
+                    scheme.mremap(id, offset, old_size, new_size, flags, ctx),
+                    Reply::Regular,
+                ),
+                Op::Msync {
+                    id,
+                    offset,
+                    size,
+                    flags,
+                } => boxed(scheme.msync(id, offset, size, flags, ctx), done),
+                Op::Call {
+                    id,
+                    payload,
+                    metadata,
+                } => boxed(scheme.call(id, payload, metadata, ctx), Reply::Regular),
+                Op::CallWithIds {
+                    ids,
+                    payload,
+                    metadata,
+                } => boxed(
+                    scheme.call_with_ids(ids, payload, metadata, ctx),
+                    Reply::Regular,
+                ),
+                Op::StdFsCall { id, payload, meta } => {
+                    boxed(scheme.std_fs_call(id, payload, meta, ctx), Reply::Regular)
+                }
+
+                Op::Close { id } => {
+                    scheme.on_close(id);
+                    Box::pin(async { Ok(Reply::Regular(0)) })
+                }
+                Op::CloseMsg { id } => {
+                    scheme.on_close(id);
+                    Box::pin(async { Ok(Reply::None) })
+                }
+                Op::Detach { id } => boxed(scheme.on_detach(id, ctx), done),
+                // Handled by `submit`.
+                Op::Cancel => Box::pin(async { Ok(Reply::None) }),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        cell::{Cell, RefCell},
+        future::poll_fn,
+        task::{Context, Poll, Waker},
+    };
+    use std::{collections::BTreeSet, vec::Vec};
+
+    use super::{AsyncDriver, SchemeAsync};
+    use crate::{
+        error::*,
+        flag::RecvFdFlags,
+        schemev2::{CallerCtx, Cqe, CqeOpcode, Opcode, OpenResult, Request, Sqe, SqeFlags},
+    };
+
+    /// Reads of file `id` complete once `id` is made ready.
+    #[derive(Default)]
+    struct Slow {
+        ready: RefCell<BTreeSet<usize>>,
+        dropped: Cell<usize>,
+    }
+
+    struct CountDrop<'a>(&'a Cell<usize>);
+    impl Drop for CountDrop<'_> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    impl SchemeAsync for Slow {
+        async fn read(
+            &self,
+            id: usize,
+            buf: &mut [u8],
+            _offset: u64,
+            _fcntl_flags: u32,
+            _ctx: CallerCtx,
+        ) -> Result<usize> {
+            let _guard = CountDrop(&self.dropped);
+            poll_fn(|_| match self.ready.borrow().contains(&id) {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            })
+            .await;
+            buf.fill(id as u8);
+            Ok(buf.len())
+        }
+        async fn recvfd(
+            &self,
+            _id: usize,
+            _flags: RecvFdFlags,
+            _num_fds: usize,
+            _ctx: CallerCtx,
+        ) -> Result<OpenResult> {
+            Ok(OpenResult::OtherScheme { fd: 3 })
+        }
+    }
+
+    fn read(tag: u32, id: u64, buf: &mut [u8]) -> Sqe {
+        Sqe {
+            opcode: Opcode::Read as u8,
+            tag,
+            args: [id, buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0, 0],
+            ..Sqe::default()
+        }
+    }
+
+    #[test]
+    fn out_of_order_and_cancel() {
+        let scheme = Slow::default();
+        let mut driver = AsyncDriver::new(&scheme);
+        let mut cx = Context::from_waker(Waker::noop());
+        let (mut one, mut two, mut three) = ([0; 2], [0; 3], [0; 4]);
+
+        unsafe {
+            assert!(driver.submit(&read(1, 1, &mut one), &mut cx).is_none());
+            assert!(driver.submit(&read(2, 2, &mut two), &mut cx).is_none());
+            assert!(driver.submit(&read(3, 3, &mut three), &mut cx).is_none());
+            let busy = driver.submit(&read(1, 9, &mut []), &mut cx).unwrap();
+            assert_eq!(Error::demux(busy.result as usize), Err(Error::new(EBUSY)));
+        }
+        assert_eq!(driver.len(), 3);
+
+        let mut done: Vec<Cqe> = Vec::new();
+        driver.poll(&mut cx, |cqe| done.push(cqe));
+        assert!(done.is_empty());
+
+        scheme.ready.borrow_mut().insert(2);
+        driver.poll(&mut cx, |cqe| done.push(cqe));
+        assert_eq!(done.len(), 1);
+        assert_eq!((done[0].tag, done[0].result), (2, 3));
+        assert_eq!(done[0].flags, CqeOpcode::RespondRegular as u8);
+        assert_eq!(two, [2; 3]);
+
+        let cancel = Sqe {
+            opcode: Opcode::Cancel as u8,
+            sqe_flags: SqeFlags::ONEWAY,
+            tag: 3,
+            ..Sqe::default()
+        };
+        assert!(unsafe { driver.submit(&cancel, &mut cx) }.is_none());
+        assert!(!driver.is_pending(3));
+        assert_eq!(scheme.dropped.get(), 2);
+
+        scheme.ready.borrow_mut().extend([1, 3]);
+        driver.poll(&mut cx, |cqe| done.push(cqe));
+        assert_eq!(done.iter().map(|cqe| cqe.tag).collect::<Vec<_>>(), [2, 1]);
+        assert_eq!(three, [0; 4]);
+        assert!(driver.is_empty());
+
+        // Completes immediately, and falls back to the default method.
+        let mut fsync = read(4, 1, &mut []);
+        fsync.opcode = Opcode::Fsync as u8;
+        fsync.args = [1, 0, 0, 0, 0, 0];
+        let cqe = unsafe { driver.submit(&fsync, &mut cx) }.unwrap();
+        assert_eq!(Error::demux(cqe.result as usize), Err(Error::new(EBADF)));
+        let call = Request::call_with_ids(&[1, 2], &mut [], &[]).unwrap();
+        let cqe = unsafe { driver.submit(&call.encode(5, &CallerCtx::default()), &mut cx) };
+        assert_eq!(
+            Error::demux(cqe.unwrap().result as usize),
+            Err(Error::new(EBADF))
+        );
+
+        let mut recvfd = read(6, 1, &mut []);
+        recvfd.opcode = Opcode::Recvfd as u8;
+        recvfd.args = [1, 0, 1, 0, 0, 0];
+        let cqe = unsafe { driver.submit(&recvfd, &mut cx) }.unwrap();
+        assert_eq!(cqe.flags, CqeOpcode::RespondWithFd as u8);
+        assert_eq!(cqe.result, 3);
+        let mut sendfd = recvfd;
+        sendfd.opcode = Opcode::Sendfd as u8;
+        let cqe = unsafe { driver.submit(&sendfd, &mut cx) }.unwrap();
+        assert_eq!(Error::demux(cqe.result as usize), Err(Error::new(EBADF)));
+    }
+}
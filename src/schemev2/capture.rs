@@ -0,0 +1,604 @@
+use alloc::{vec, vec::Vec};
+use core::{mem, slice, time::Duration};
+
+use super::{Clock, Cqe, Handler, Request, Response, Sqe, SqeFlags};
+use crate::error::*;
+
+/// The start of every capture.
+pub const CAPTURE_MAGIC: [u8; 8] = *b"SCHMCAP\0";
+/// The version of the capture format written by [`CaptureWriter`]. Readers accept this and
+/// older versions.
+pub const CAPTURE_VERSION: u32 = 1;
+
+/// A buffer an [`Sqe`] referred to, as captured.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CapturedBuffer {
+    /// The argument of the `Sqe` that holds the address of the buffer.
+    pub arg: u8,
+    /// The contents before the request was handled.
+    pub before: Vec<u8>,
+    /// The contents after, if the scheme may write to the buffer.
+    pub after: Option<Vec<u8>>,
+}
+
+/// A request and what the scheme answered it with, as captured.
+#[derive(Clone, Debug)]
+pub struct CapturedRequest {
+    /// When the request was handled, on the clock of the capture.
+    pub time: Duration,
+    pub sqe: Sqe,
+    pub buffers: Vec<CapturedBuffer>,
+    /// The `Cqe`s the scheme pushed while handling the request, including fevents.
+    pub cqes: Vec<Cqe>,
+}
+
+/// The buffers `sqe` refers to, as the argument holding the address, the length in bytes, and
+/// whether the scheme writes to it. Requests that don't decode refer to none.
+fn buffer_args(sqe: &Sqe) -> Vec<(u8, usize, bool)> {
+    let words = |count: usize| count.saturating_mul(mem::size_of::<u64>());
+    match Request::decode(sqe) {
+        Ok(
+            Request::Dup { len, .. } | Request::Write { len, .. } | Request::Futimens { len, .. },
+        ) => vec![(1, len, false)],
+        Ok(
+            Request::Read { len, .. }
+            | Request::Fpath { len, .. }
+            | Request::Fstat { len, .. }
+            | Request::Fstatvfs { len, .. }
+            | Request::Getdents { len, .. },
+        ) => vec![(1, len, true)],
+        Ok(
+            Request::Frename { path_len, .. }
+            | Request::Flink { path_len, .. }
+            | Request::OpenAt { path_len, .. }
+            | Request::UnlinkAt { path_len, .. },
+        ) => vec![(1, path_len, false)],
+        Ok(
+            Request::Call {
+                payload_len,
+                metadata_len,
+                ..
+            }
+            | Request::StdFsCall {
+                payload_len,
+                metadata_len,
+                ..
+            },
+        ) => vec![(1, payload_len, true), (3, words(metadata_len), false)],
+        Ok(Request::CallWithIds {
+            ids_len,
+            payload_len,
+            metadata_len,
+            ..
+        }) => vec![
+            (0, ids_len, false),
+            (2, payload_len, true),
+            (4, words(metadata_len), false),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+/// Encodes [`CapturedRequest`]s in the capture format.
+///
+/// A capture is [`CAPTURE_MAGIC`] and the version as a `u32`, followed by the records. All
+/// integers are little-endian. Each record is its length as a `u32`, followed by:
+///
+/// - The time in nanoseconds as a `u64`.
+/// - The `Sqe`: the opcode and flags as `u8`s, the priority as a `u16`, the tag as a `u32`, and
+///   the six arguments and the caller as `u64`s.
+/// - The number of buffers as a `u8`, and for each the argument and whether the scheme writes
+///   to it as `u8`s, its length as a `u32`, its contents before, and its contents after if
+///   written.
+/// - The number of `Cqe`s as a `u8`, and for each the flags, the extra bytes, the tag as a
+///   `u32` and the result as a `u64`.
+///
+/// Fields may be added to the end of records without a new version, and are skipped by older
+/// readers.
+pub struct CaptureWriter {
+    bytes: Vec<u8>,
+}
+
+impl CaptureWriter {
+    /// A writer that starts with the header.
+    pub fn new() -> Self {
+        let mut bytes = CAPTURE_MAGIC.to_vec();
+        bytes.extend_from_slice(&CAPTURE_VERSION.to_le_bytes());
+        Self { bytes }
+    }
+    /// The capture so far.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+    /// Take the capture so far, such as to write it to a file, leaving the writer empty. The
+    /// chunks taken add up to one capture.
+    pub fn take(&mut self) -> Vec<u8> {
+        mem::take(&mut self.bytes)
+    }
+
+    /// Append `record`. Only the first 255 buffers and `Cqe`s are kept.
+    ///
+    /// Fails with `EINVAL` if a buffer's contents after differ in length from those before, and
+    /// with `EOVERFLOW` if the record is longer than `u32::MAX` bytes. Nothing is appended then.
+    pub fn record(&mut self, record: &CapturedRequest) -> Result<()> {
+        let mut out = Vec::new();
+        let nanos = u64::try_from(record.time.as_nanos()).unwrap_or(u64::MAX);
+        out.extend_from_slice(&nanos.to_le_bytes());
+
+        let sqe = &record.sqe;
+        out.extend_from_slice(&[sqe.opcode, sqe.sqe_flags.bits()]);
+        out.extend_from_slice(&sqe.priority.to_le_bytes());
+        out.extend_from_slice(&sqe.tag.to_le_bytes());
+        for word in sqe.args.iter().chain([&sqe.caller]) {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+
+        let buffers = &record.buffers[..record.buffers.len().min(255)];
+        out.push(buffers.len() as u8);
+        for buffer in buffers {
+            // Both have the length of the buffer.
+            if let Some(after) = &buffer.after {
+                if after.len() != buffer.before.len() {
+                    return Err(Error::new(EINVAL));
+                }
+            }
+            let len = u32::try_from(buffer.before.len()).map_err(|_| Error::new(EOVERFLOW))?;
+            out.extend_from_slice(&[buffer.arg, u8::from(buffer.after.is_some())]);
+            out.extend_from_slice(&len.to_le_bytes());
+            out.extend_from_slice(&buffer.before);
+            if let Some(after) = &buffer.after {
+                out.extend_from_slice(after);
+            }
+        }
+
+        let cqes = &record.cqes[..record.cqes.len().min(255)];
+        out.push(cqes.len() as u8);
+        for cqe in cqes {
+            out.push(cqe.flags);
+            out.extend_from_slice(&cqe.extra_raw);
+            out.extend_from_slice(&cqe.tag.to_le_bytes());
+            out.extend_from_slice(&cqe.result.to_le_bytes());
+        }
+
+        let len = u32::try_from(out.len()).map_err(|_| Error::new(EOVERFLOW))?;
+        self.bytes.extend_from_slice(&len.to_le_bytes());
+        self.bytes.extend_from_slice(&out);
+        Ok(())
+    }
+}
+
+impl Default for CaptureWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Bytes being decoded, failing with `EINVAL` when they run out.
+struct Bytes<'a>(&'a [u8]);
+
+impl<'a> Bytes<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(Error::new(EINVAL));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    fn u32(&mut self) -> Result<u32> {
+        self.array().map(u32::from_le_bytes)
+    }
+    fn u64(&mut self) -> Result<u64> {
+        self.array().map(u64::from_le_bytes)
+    }
+}
+
+/// Decodes the records of a capture written by [`CaptureWriter`].
+///
+/// After a record fails to decode, such as the last one of a capture that was cut short, the
+/// iterator ends.
+pub struct CaptureReader<'a> {
+    version: u32,
+    bytes: &'a [u8],
+}
+
+impl<'a> CaptureReader<'a> {
+    /// Fails with `EINVAL` if `bytes` is not a capture, and with `EOPNOTSUPP` if it is newer
+    /// than [`CAPTURE_VERSION`].
+    pub fn new(bytes: &'a [u8]) -> Result<Self> {
+        let mut bytes = Bytes(bytes);
+        if bytes.array().ok() != Some(CAPTURE_MAGIC) {
+            return Err(Error::new(EINVAL));
+        }
+        let version = bytes.u32()?;
+        if version == 0 || version > CAPTURE_VERSION {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        Ok(Self {
+            version,
+            bytes: bytes.0,
+        })
+    }
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    fn decode(record: &mut Bytes) -> Result<CapturedRequest> {
+        let time = Duration::from_nanos(record.u64()?);
+        let [opcode, flags] = record.array()?;
+        let mut sqe = Sqe {
+            opcode,
+            sqe_flags: SqeFlags::from_bits_retain(flags),
+            priority: u16::from_le_bytes(record.array()?),
+            tag: record.u32()?,
+            ..Sqe::default()
+        };
+        for arg in &mut sqe.args {
+            *arg = record.u64()?;
+        }
+        sqe.caller = record.u64()?;
+
+        let buffers = (0..record.u8()?)
+            .map(|_| {
+                let [arg, written] = record.array()?;
+                let len = record.u32()? as usize;
+                Ok(CapturedBuffer {
+                    arg,
+                    before: record.take(len)?.to_vec(),
+                    after: match written {
+                        0 => None,
+                        _ => Some(record.take(len)?.to_vec()),
+                    },
+                })
+            })
+            .collect::<Result<_>>()?;
+        let cqes = (0..record.u8()?)
+            .map(|_| {
+                Ok(Cqe {
+                    flags: record.u8()?,
+                    extra_raw: record.array()?,
+                    tag: record.u32()?,
+                    result: record.u64()?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(CapturedRequest {
+            time,
+            sqe,
+            buffers,
+            cqes,
+        })
+    }
+}
+
+impl Iterator for CaptureReader<'_> {
+    type Item = Result<CapturedRequest>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        let mut bytes = Bytes(self.bytes);
+        let record = bytes.u32().and_then(|len| bytes.take(len as usize));
+        // Anything after the fields this version knows of is skipped.
+        let result = record.and_then(|record| Self::decode(&mut Bytes(record)));
+        self.bytes = match result {
+            Ok(_) => bytes.0,
+            Err(_) => &[],
+        };
+        Some(result)
+    }
+}
+
+/// Records every request passed to the inner handler, along with the buffers it refers to and
+/// the `Cqe`s pushed in response, with a [`CaptureWriter`].
+///
+/// Requests that can't be recorded, such as those with buffers too long for the format, are
+/// still handled, and counted by [`CaptureLayer::dropped`].
+pub struct CaptureLayer<H, C> {
+    handler: H,
+    clock: C,
+    writer: CaptureWriter,
+    dropped: usize,
+}
+
+impl<H: Handler, C: Clock> CaptureLayer<H, C> {
+    pub fn new(handler: H, clock: C) -> Self {
+        Self {
+            handler,
+            clock,
+            writer: CaptureWriter::new(),
+            dropped: 0,
+        }
+    }
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+    pub fn into_handler(self) -> H {
+        self.handler
+    }
+    pub fn writer(&self) -> &CaptureWriter {
+        &self.writer
+    }
+    pub fn writer_mut(&mut self) -> &mut CaptureWriter {
+        &mut self.writer
+    }
+    /// The number of requests that were handled but left out of the capture.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+impl<H: Handler, C: Clock> Handler for CaptureLayer<H, C> {
+    unsafe fn handle(&mut self, sqe: &Sqe, out: &mut Vec<Cqe>) {
+        let time = self.clock.now();
+        // Empty buffers and those at null addresses are left out, and replayed as they were.
+        // Only addresses are kept across the call, since the handler may write to the buffers.
+        let args: Vec<_> = buffer_args(sqe)
+            .into_iter()
+            .map(|(arg, len, written)| (arg, sqe.args[usize::from(arg)] as usize, len, written))
+            .filter(|&(_, ptr, len, _)| len != 0 && ptr != 0)
+            .collect();
+        let mut buffers: Vec<CapturedBuffer> = args
+            .iter()
+            .map(|&(arg, ptr, len, _)| CapturedBuffer {
+                arg,
+                before: slice::from_raw_parts(ptr as *const u8, len).to_vec(),
+                after: None,
+            })
+            .collect();
+
+        let start = out.len();
+        self.handler.handle(sqe, out);
+
+        for (buffer, (_, ptr, len, written)) in buffers.iter_mut().zip(args) {
+            buffer.after = written.then(|| slice::from_raw_parts(ptr as *const u8, len).to_vec());
+        }
+        let record = CapturedRequest {
+            time,
+            sqe: *sqe,
+            buffers,
+            cqes: out[start..].to_vec(),
+        };
+        if self.writer.record(&record).is_err() {
+            self.dropped += 1;
+        }
+    }
+}
+
+/// A request that was answered differently when replayed by [`replay`].
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    /// The index of the request in the capture.
+    pub index: usize,
+    pub captured: CapturedRequest,
+    /// The `Cqe`s pushed when replayed.
+    pub cqes: Vec<Cqe>,
+    /// The buffers the scheme writes to, as written when replayed.
+    pub buffers: Vec<CapturedBuffer>,
+}
+
+/// Whether two `Cqe`s answer alike. Addresses in the scheme, which differ between runs, are
+/// not compared.
+fn same_cqe(captured: &Cqe, replayed: &Cqe) -> bool {
+    match (captured.decode(), replayed.decode()) {
+        (Ok(Response::ProvideMmap { tag, .. }), Ok(Response::ProvideMmap { tag: other, .. })) => {
+            tag == other
+        }
+        (
+            Ok(Response::WithMultipleFds { tag, count, .. }),
+            Ok(Response::WithMultipleFds {
+                tag: other,
+                count: other_count,
+                ..
+            }),
+        ) => (tag, count) == (other, other_count),
+        _ => captured == replayed,
+    }
+}
+
+/// Send the requests of `capture` to `handler`, with buffers restored to their captured
+/// contents, and return the requests where the `Cqe`s or the buffers the scheme writes to
+/// differ from the capture. The handler should start out in the state it was captured in, such
+/// as a newly started scheme.
+///
+/// Fails with the error of the first record that does not decode, and with `EINVAL` if the
+/// buffers of a record do not match its `Sqe`.
+///
+/// # Safety
+///
+/// Requests that [`Request::decode`] rejects are sent as they were captured, so `handler` must
+/// not use their arguments as addresses, like [`handle_sync`](super::handle_sync) does.
+pub unsafe fn replay<H: Handler + ?Sized>(
+    handler: &mut H,
+    capture: CaptureReader,
+) -> Result<Vec<Divergence>> {
+    let mut divergences = Vec::new();
+    for (index, captured) in capture.enumerate() {
+        let captured = captured?;
+        let mut sqe = captured.sqe;
+        // A corrupt capture must not make the handler overrun the buffers.
+        let args: Vec<_> = buffer_args(&sqe)
+            .into_iter()
+            .filter(|&(arg, len, _)| len != 0 && sqe.args[usize::from(arg)] != 0)
+            .map(|(arg, len, _)| (arg, len))
+            .collect();
+        let restored: Vec<_> = captured
+            .buffers
+            .iter()
+            .map(|buffer| (buffer.arg, buffer.before.len()))
+            .collect();
+        if args != restored {
+            return Err(Error::new(EINVAL));
+        }
+        // Word aligned, for metadata and structs.
+        let mut memory: Vec<Vec<u64>> = captured
+            .buffers
+            .iter()
+            .map(|buffer| {
+                let mut words = vec![0; buffer.before.len().div_ceil(8)];
+                let bytes = words.as_mut_ptr().cast::<u8>();
+                bytes.copy_from_nonoverlapping(buffer.before.as_ptr(), buffer.before.len());
+                words
+            })
+            .collect();
+        for (buffer, words) in captured.buffers.iter().zip(&mut memory) {
+            if let Some(arg) = sqe.args.get_mut(usize::from(buffer.arg)) {
+                *arg = words.as_mut_ptr() as u64;
+            }
+        }
+
+        let mut cqes = Vec::new();
+        handler.handle(&sqe, &mut cqes);
+
+        let buffers: Vec<CapturedBuffer> = captured
+            .buffers
+            .iter()
+            .zip(&memory)
+            .filter(|(buffer, _)| buffer.after.is_some())
+            .map(|(buffer, words)| CapturedBuffer {
+                arg: buffer.arg,
+                before: buffer.before.clone(),
+                after: Some(
+                    slice::from_raw_parts(words.as_ptr().cast::<u8>(), buffer.before.len())
+                        .to_vec(),
+                ),
+            })
+            .collect();
+        let same = cqes.len() == captured.cqes.len()
+            && captured.cqes.iter().zip(&cqes).all(|(a, b)| same_cqe(a, b))
+            && captured
+                .buffers
+                .iter()
+                .filter(|buffer| buffer.after.is_some())
+                .zip(&buffers)
+                .all(|(captured, replayed)| captured.after == replayed.after);
+        if !same {
+            divergences.push(Divergence {
+                index,
+                captured,
+                cqes,
+                buffers,
+            });
+        }
+    }
+    Ok(divergences)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+    use std::vec::Vec;
+
+    use super::{replay, CaptureLayer, CaptureReader, CaptureWriter, CAPTURE_VERSION};
+    use crate::{
+        error::*,
+        flag::{O_CREAT, O_RDWR, SEEK_SET},
+        schemev2::{CallerCtx, Clock, Fault, FaultLayer, Loopback, Opcode, Tmpfs},
+    };
+
+    /// A clock that moves by a millisecond per reading.
+    struct Ticks(Duration);
+
+    impl Clock for Ticks {
+        fn now(&mut self) -> Duration {
+            self.0 += Duration::from_millis(1);
+            self.0
+        }
+        fn sleep(&mut self, duration: Duration) {
+            self.0 += duration;
+        }
+    }
+
+    /// Open, write, read and close a file.
+    fn capture() -> Vec<u8> {
+        let layer = CaptureLayer::new(Tmpfs::new("tmp"), Ticks(Duration::ZERO));
+        let ctx = CallerCtx {
+            pid: 1,
+            uid: 1000,
+            gid: 1000,
+        };
+        let mut fs = Loopback::new(layer, ctx);
+        let fd = fs.open("file", O_RDWR | O_CREAT | 0o644).unwrap();
+        fs.write(fd, b"hello").unwrap();
+        fs.lseek(fd, 0, SEEK_SET).unwrap();
+        fs.read(fd, &mut [0; 8]).unwrap();
+        fs.close(fd).unwrap();
+        fs.handler_mut().writer_mut().take()
+    }
+
+    #[test]
+    fn format() {
+        let bytes = capture();
+        let records: Vec<_> = CaptureReader::new(&bytes)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(records.len(), 5);
+        let read = &records[2];
+        assert_eq!(read.time, Duration::from_millis(3));
+        assert_eq!(Opcode::try_from_raw(read.sqe.opcode), Some(Opcode::Read));
+        assert_eq!(read.buffers[0].before, [0; 8]);
+        assert_eq!(read.buffers[0].after.as_deref(), Some(&b"hello\0\0\0"[..]));
+        assert_eq!(read.cqes[0].result, 5);
+        assert_eq!(records[0].buffers[0].before, b"file");
+        // Tmpfs asks for `Detach`, and `CloseMsg` is not answered.
+        assert_eq!(
+            Opcode::try_from_raw(records[3].sqe.opcode),
+            Some(Opcode::Detach)
+        );
+        assert!(records[4].cqes.is_empty());
+
+        let mut writer = CaptureWriter::new();
+        for record in &records {
+            writer.record(record).unwrap();
+        }
+        assert_eq!(writer.bytes(), bytes);
+        let mut short = read.clone();
+        short.buffers[0].after = Some(b"hello".to_vec());
+        assert_eq!(writer.record(&short), Err(Error::new(EINVAL)));
+        assert_eq!(writer.bytes(), bytes);
+
+        let cut = CaptureReader::new(&bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(
+            cut.map(|record| record.map(|_| ())).collect::<Vec<_>>(),
+            [Ok(()), Ok(()), Ok(()), Ok(()), Err(Error::new(EINVAL))]
+        );
+        assert_eq!(
+            CaptureReader::new(b"not a capture").err(),
+            Some(Error::new(EINVAL))
+        );
+        let mut newer = bytes.clone();
+        newer[8..12].copy_from_slice(&(CAPTURE_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            CaptureReader::new(&newer).err(),
+            Some(Error::new(EOPNOTSUPP))
+        );
+    }
+
+    #[test]
+    fn replay_divergence() {
+        let bytes = capture();
+        let capture = || CaptureReader::new(&bytes).unwrap();
+        let mut fresh = Tmpfs::new("tmp");
+        assert!(unsafe { replay(&mut fresh, capture()) }.unwrap().is_empty());
+
+        let mut faulty = FaultLayer::new(Tmpfs::new("tmp"), Ticks(Duration::ZERO), 0);
+        faulty.inject(Some(Opcode::Read), Fault::Error(EIO), 1);
+        let divergences = unsafe { replay(&mut faulty, capture()) }.unwrap();
+        assert_eq!(divergences.len(), 1);
+        let read = &divergences[0];
+        assert_eq!(read.index, 2);
+        assert_eq!(read.cqes[0].result, Error::mux(Err(Error::new(EIO))) as u64);
+        assert_eq!(read.buffers[0].after.as_deref(), Some(&[0; 8][..]));
+    }
+}
@@ -0,0 +1,362 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::Debug;
+
+use super::{Handler, Loopback};
+use crate::{
+    data::Stat,
+    dirent::DirentIter,
+    error::*,
+    flag::{
+        AT_REMOVEDIR, MODE_DIR, MODE_TYPE, O_CREAT, O_DIRECTORY, O_EXCL, O_RDONLY, O_RDWR, O_TRUNC,
+        O_WRONLY, SEEK_END, SEEK_SET,
+    },
+};
+
+/// A step of [`check_conformance`] where the scheme did not answer as expected.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Nonconformance {
+    /// The check, which is also the name of its directory.
+    pub check: &'static str,
+    pub step: &'static str,
+    /// The expected and actual results, formatted with `Debug`.
+    pub expected: String,
+    pub got: String,
+}
+
+type Outcome<T> = core::result::Result<T, Nonconformance>;
+type Check<H> = fn(&mut Loopback<H>, usize) -> Outcome<()>;
+
+/// Check that a filesystem scheme answers like POSIX in the edge cases schemes tend to disagree
+/// on, returning the first failed step of each check.
+///
+/// The requests are sent through `fs`, as its caller, to make files in `dirfd`. Each check runs
+/// in a new directory named after it, which is removed if the check passes. The checks cover:
+///
+/// - `O_CREAT`, `O_EXCL`, `O_TRUNC` and `O_DIRECTORY`, and opening paths through files.
+/// - `EISDIR` and `ENOTDIR` from opening, reading and removing directories and files, and
+///   `ENOTEMPTY`.
+/// - `EBADF` for reads and writes the access mode does not allow.
+/// - File sizes, holes and truncation, as seen through `lseek` with `SEEK_END`. Regular files
+///   must be opened as [`NewFdFlags::POSITIONED`](super::NewFdFlags::POSITIONED).
+/// - `Fpath` truncation, where a short buffer gets the start of the path.
+/// - `Getdents` continuing from the `next_opaque_id` of the last entry, across several calls,
+///   with `EINVAL` if the buffer cannot hold one entry. `.` and `..` are allowed but not needed.
+///
+/// Any [`Handler`] can be checked, so a scheme daemon can be run against the transport it uses.
+pub fn check_conformance<H: Handler>(fs: &mut Loopback<H>, dirfd: usize) -> Vec<Nonconformance> {
+    let checks: [(&'static str, Check<H>); 6] = [
+        ("open_flags", open_flags),
+        ("directories", directories),
+        ("access_mode", access_mode),
+        ("seek_end", seek_end),
+        ("fpath", fpath),
+        ("getdents", getdents),
+    ];
+    let mut failures = Vec::new();
+    for (name, check) in checks {
+        let dir_flags = O_RDONLY | O_CREAT | O_EXCL | O_DIRECTORY | 0o755;
+        let result = ok("create directory", fs.openat(dirfd, name, dir_flags)).and_then(|dir| {
+            check(fs, dir)?;
+            ok("close directory", fs.close(dir))?;
+            ok("remove directory", fs.unlinkat(dirfd, name, AT_REMOVEDIR))
+        });
+        if let Err(failure) = result {
+            failures.push(Nonconformance {
+                check: name,
+                ..failure
+            });
+        }
+    }
+    failures
+}
+
+fn expect<T: Debug + PartialEq>(step: &'static str, got: T, expected: T) -> Outcome<()> {
+    if got == expected {
+        return Ok(());
+    }
+    Err(Nonconformance {
+        check: "",
+        step,
+        expected: format!("{expected:?}"),
+        got: format!("{got:?}"),
+    })
+}
+fn ok<T>(step: &'static str, got: Result<T>) -> Outcome<T> {
+    got.map_err(|err| Nonconformance {
+        check: "",
+        step,
+        expected: "Ok(_)".to_string(),
+        got: format!("Err({err:?})"),
+    })
+}
+fn err<T: Debug>(step: &'static str, got: Result<T>, errno: i32) -> Outcome<()> {
+    match got {
+        Err(err) if err.errno == errno => Ok(()),
+        got => Err(Nonconformance {
+            check: "",
+            step,
+            expected: format!("Err({:?})", Error::new(errno)),
+            got: format!("{got:?}"),
+        }),
+    }
+}
+
+fn create<H: Handler>(fs: &mut Loopback<H>, dir: usize, name: &str, data: &[u8]) -> Outcome<()> {
+    let fd = ok(
+        "create",
+        fs.openat(dir, name, O_WRONLY | O_CREAT | O_EXCL | 0o644),
+    )?;
+    expect("write", fs.write(fd, data), Ok(data.len()))?;
+    ok("close", fs.close(fd))
+}
+
+fn open_flags<H: Handler>(fs: &mut Loopback<H>, dir: usize) -> Outcome<()> {
+    create(fs, dir, "file", b"hello")?;
+    let flags = O_RDWR | O_CREAT | O_EXCL | 0o644;
+    err("O_EXCL on a file", fs.openat(dir, "file", flags), EEXIST)?;
+    err("missing file", fs.openat(dir, "missing", O_RDONLY), ENOENT)?;
+    let flags = O_RDONLY | O_DIRECTORY;
+    err(
+        "O_DIRECTORY on a file",
+        fs.openat(dir, "file", flags),
+        ENOTDIR,
+    )?;
+    err(
+        "path through a file",
+        fs.openat(dir, "file/x", O_RDONLY),
+        ENOTDIR,
+    )?;
+
+    let fd = ok(
+        "O_CREAT on a file",
+        fs.openat(dir, "file", O_RDWR | O_CREAT | 0o644),
+    )?;
+    expect("size after O_CREAT", fs.fsize(fd), Ok(5))?;
+    ok("close", fs.close(fd))?;
+    let fd = ok("O_TRUNC", fs.openat(dir, "file", O_RDWR | O_TRUNC))?;
+    expect("size after O_TRUNC", fs.fsize(fd), Ok(0))?;
+    ok("close", fs.close(fd))?;
+    ok("unlink", fs.unlinkat(dir, "file", 0))
+}
+
+fn directories<H: Handler>(fs: &mut Loopback<H>, dir: usize) -> Outcome<()> {
+    let flags = O_RDONLY | O_CREAT | O_EXCL | O_DIRECTORY | 0o755;
+    let sub = ok("create directory", fs.openat(dir, "dir", flags))?;
+    let mut stat = Stat::default();
+    ok("fstat", fs.fstat(sub, &mut stat))?;
+    expect("directory type", stat.st_mode & MODE_TYPE, MODE_DIR)?;
+    err("read directory", fs.read(sub, &mut [0; 8]), EISDIR)?;
+    ok("close", fs.close(sub))?;
+    err(
+        "O_WRONLY directory",
+        fs.openat(dir, "dir", O_WRONLY),
+        EISDIR,
+    )?;
+    err("O_RDWR directory", fs.openat(dir, "dir", O_RDWR), EISDIR)?;
+
+    create(fs, dir, "dir/file", b"")?;
+    err("unlink directory", fs.unlinkat(dir, "dir", 0), EISDIR)?;
+    err(
+        "rmdir file",
+        fs.unlinkat(dir, "dir/file", AT_REMOVEDIR),
+        ENOTDIR,
+    )?;
+    err(
+        "rmdir nonempty",
+        fs.unlinkat(dir, "dir", AT_REMOVEDIR),
+        ENOTEMPTY,
+    )?;
+    err("unlink missing", fs.unlinkat(dir, "missing", 0), ENOENT)?;
+    ok("unlink", fs.unlinkat(dir, "dir/file", 0))?;
+    ok("rmdir", fs.unlinkat(dir, "dir", AT_REMOVEDIR))?;
+    err("removed directory", fs.openat(dir, "dir", O_RDONLY), ENOENT)
+}
+
+fn access_mode<H: Handler>(fs: &mut Loopback<H>, dir: usize) -> Outcome<()> {
+    create(fs, dir, "file", b"data")?;
+    let fd = ok("O_WRONLY", fs.openat(dir, "file", O_WRONLY))?;
+    err("read O_WRONLY", fs.read(fd, &mut [0; 4]), EBADF)?;
+    ok("close", fs.close(fd))?;
+    let fd = ok("O_RDONLY", fs.openat(dir, "file", O_RDONLY))?;
+    err("write O_RDONLY", fs.write(fd, b"more"), EBADF)?;
+    ok("close", fs.close(fd))?;
+    ok("unlink", fs.unlinkat(dir, "file", 0))
+}
+
+fn seek_end<H: Handler>(fs: &mut Loopback<H>, dir: usize) -> Outcome<()> {
+    let fd = ok(
+        "create",
+        fs.openat(dir, "file", O_RDWR | O_CREAT | O_EXCL | 0o644),
+    )?;
+    expect("write", fs.write(fd, b"hello"), Ok(5))?;
+    expect("SEEK_END", fs.lseek(fd, 0, SEEK_END), Ok(5))?;
+    expect("write at end", fs.write(fd, b"!"), Ok(1))?;
+    expect("size after writing", fs.fsize(fd), Ok(6))?;
+    expect("SEEK_END back", fs.lseek(fd, -2, SEEK_END), Ok(4))?;
+    let mut buf = [0xff; 8];
+    let count = ok("read to end", fs.read(fd, &mut buf))?;
+    expect("read to end", &buf[..count], b"o!")?;
+    expect("read at end", fs.read(fd, &mut buf), Ok(0))?;
+    err("SEEK_END before start", fs.lseek(fd, -7, SEEK_END), EINVAL)?;
+
+    expect("SEEK_END past end", fs.lseek(fd, 2, SEEK_END), Ok(8))?;
+    expect("write past end", fs.write(fd, b"x"), Ok(1))?;
+    expect("size with hole", fs.fsize(fd), Ok(9))?;
+    expect("SEEK_SET", fs.lseek(fd, 5, SEEK_SET), Ok(5))?;
+    let count = ok("read hole", fs.read(fd, &mut buf))?;
+    expect("read hole", &buf[..count], b"!\0\0x")?;
+
+    ok("ftruncate", fs.ftruncate(fd, 3))?;
+    expect("size after ftruncate", fs.fsize(fd), Ok(3))?;
+    let mut stat = Stat::default();
+    ok("fstat", fs.fstat(fd, &mut stat))?;
+    expect("st_size after ftruncate", stat.st_size, 3)?;
+    expect("SEEK_END after ftruncate", fs.lseek(fd, 0, SEEK_END), Ok(3))?;
+    ok("close", fs.close(fd))?;
+    ok("unlink", fs.unlinkat(dir, "file", 0))
+}
+
+fn fpath<H: Handler>(fs: &mut Loopback<H>, dir: usize) -> Outcome<()> {
+    create(fs, dir, "file", b"")?;
+    let fd = ok("open", fs.openat(dir, "file", O_RDONLY))?;
+    let mut full = [0; 4096];
+    let len = ok("fpath", fs.fpath(fd, &mut full))?;
+    let full = &full[..len];
+    expect("path ends with name", full.ends_with(b"/file"), true)?;
+    for cut in [0, 1, len / 2, len - 1] {
+        let mut buf = [0; 4096];
+        let count = fs.fpath(fd, &mut buf[..cut]);
+        expect(
+            "truncated fpath",
+            count.map(|count| &buf[..count]),
+            Ok(&full[..cut]),
+        )?;
+    }
+    ok("close", fs.close(fd))?;
+    ok("unlink", fs.unlinkat(dir, "file", 0))
+}
+
+fn getdents<H: Handler>(fs: &mut Loopback<H>, dir: usize) -> Outcome<()> {
+    let mut names: Vec<String> = (0..10).map(|i| format!("entry-{i:02}")).collect();
+    for name in &names {
+        create(fs, dir, name, b"")?;
+    }
+    let fd = ok("open", fs.openat(dir, &names[0], O_RDONLY))?;
+    err(
+        "getdents on a file",
+        fs.getdents(fd, &mut [0; 256], 0),
+        ENOTDIR,
+    )?;
+    ok("close", fs.close(fd))?;
+    let fd = ok(
+        "open directory",
+        fs.openat(dir, ".", O_RDONLY | O_DIRECTORY),
+    )?;
+    err("buffer too small", fs.getdents(fd, &mut [0; 8], 0), EINVAL)?;
+
+    let mut listed = Vec::new();
+    let mut opaque_offset = 0;
+    let mut calls = 0;
+    loop {
+        // Room for two entries at a time.
+        let mut buf = [0; 80];
+        let len = ok("getdents", fs.getdents(fd, &mut buf, opaque_offset))?;
+        if len == 0 {
+            break;
+        }
+        for entry in DirentIter::new(&buf[..len]) {
+            let (header, name) = ok("valid entries", entry.map_err(|_| Error::new(EINVAL)))?;
+            // Names are followed by a NUL, and padding up to the alignment.
+            let name = name.split(|&b| b == 0).next().unwrap_or(&[]);
+            opaque_offset = header.next_opaque_id;
+            if name != b"." && name != b".." {
+                listed.push(String::from_utf8_lossy(name).into_owned());
+            }
+        }
+        calls += 1;
+        expect("getdents finishes", calls <= names.len() + 2, true)?;
+    }
+    listed.sort();
+    expect("every entry once", &listed, &names)?;
+    ok("close", fs.close(fd))?;
+
+    for name in names.drain(..) {
+        ok("unlink", fs.unlinkat(dir, &name, 0))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::{check_conformance, Nonconformance};
+    use crate::{
+        error::*,
+        flag::{O_DIRECTORY, O_RDONLY},
+        schemev2::{CallerCtx, Cqe, Handler, Loopback, Opcode, Sqe, Tmpfs},
+    };
+
+    fn ctx() -> CallerCtx {
+        CallerCtx {
+            pid: 1,
+            uid: 1000,
+            gid: 1000,
+        }
+    }
+
+    #[test]
+    fn tmpfs() {
+        let mut fs = Loopback::new(Tmpfs::new("tmp"), ctx());
+        let dir = fs.open("", O_RDONLY | O_DIRECTORY).unwrap();
+        assert_eq!(check_conformance(&mut fs, dir), []);
+        let mut buf = [0; 64];
+        assert_eq!(fs.getdents(dir, &mut buf, 0), Ok(0));
+    }
+
+    /// A tmpfs that fails `Fpath` with `ERANGE` for short buffers, and answers `Getdents` from
+    /// the start every time.
+    struct Broken(Tmpfs);
+
+    impl Handler for Broken {
+        unsafe fn handle(&mut self, sqe: &Sqe, out: &mut Vec<Cqe>) {
+            let mut sqe = *sqe;
+            match Opcode::try_from_raw(sqe.opcode) {
+                Some(Opcode::Fpath) if sqe.args[2] < 64 => {
+                    return out.push(Cqe::respond(sqe.tag, Err(Error::new(ERANGE))))
+                }
+                Some(Opcode::Getdents) => sqe.args[4] = 0,
+                _ => (),
+            }
+            self.0.handle(&sqe, out)
+        }
+    }
+
+    #[test]
+    fn failures() {
+        let mut fs = Loopback::new(Broken(Tmpfs::new("tmp")), ctx());
+        let dir = fs.open("", O_RDONLY | O_DIRECTORY).unwrap();
+        let failures = check_conformance(&mut fs, dir);
+        assert_eq!(
+            failures,
+            [
+                Nonconformance {
+                    check: "fpath",
+                    step: "truncated fpath",
+                    expected: "Ok([])".into(),
+                    got: "Err(Math result not representable)".into(),
+                },
+                Nonconformance {
+                    check: "getdents",
+                    step: "getdents finishes",
+                    expected: "true".into(),
+                    got: "false".into(),
+                },
+            ]
+        );
+    }
+}
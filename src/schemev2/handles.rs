@@ -0,0 +1,124 @@
+use alloc::collections::BTreeMap;
+
+use super::CallerCtx;
+use crate::error::*;
+
+struct Entry<T> {
+    ctx: CallerCtx,
+    handle: T,
+}
+
+/// A scheme's open file descriptions, by the ids it answers opens with, along with the caller
+/// that opened each. Ids start at 1 and are not reused.
+///
+/// Lookups of ids that are not open fail with `EBADF`, as schemes answer them.
+pub struct HandleTable<T> {
+    entries: BTreeMap<usize, Entry<T>>,
+    next: usize,
+}
+
+impl<T> HandleTable<T> {
+    pub fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            next: 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Add `handle`, opened by `ctx`, returning its id.
+    pub fn insert(&mut self, handle: T, ctx: &CallerCtx) -> usize {
+        let id = self.next;
+        self.next += 1;
+        self.entries.insert(id, Entry { ctx: *ctx, handle });
+        id
+    }
+    pub fn get(&self, id: usize) -> Result<&T> {
+        self.entry(id).map(|entry| &entry.handle)
+    }
+    pub fn get_mut(&mut self, id: usize) -> Result<&mut T> {
+        self.entries
+            .get_mut(&id)
+            .map(|entry| &mut entry.handle)
+            .ok_or(Error::new(EBADF))
+    }
+    /// The caller that opened `id`, or duplicated it into `id`.
+    pub fn opener(&self, id: usize) -> Result<&CallerCtx> {
+        self.entry(id).map(|entry| &entry.ctx)
+    }
+    fn entry(&self, id: usize) -> Result<&Entry<T>> {
+        self.entries.get(&id).ok_or(Error::new(EBADF))
+    }
+    /// Remove `id`, such as for [`Request::Close`](super::Request::Close), returning its handle.
+    pub fn close(&mut self, id: usize) -> Result<T> {
+        self.entries
+            .remove(&id)
+            .map(|entry| entry.handle)
+            .ok_or(Error::new(EBADF))
+    }
+    /// The open ids and their handles, in the order they were opened.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.entries.iter().map(|(&id, entry)| (id, &entry.handle))
+    }
+}
+
+impl<T: Clone> HandleTable<T> {
+    /// Add a copy of `id`, for [`Request::Dup`](super::Request::Dup) by `ctx`, returning the
+    /// new id.
+    pub fn dup(&mut self, id: usize, ctx: &CallerCtx) -> Result<usize> {
+        let handle = self.get(id)?.clone();
+        Ok(self.insert(handle, ctx))
+    }
+}
+
+impl<T> Default for HandleTable<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::HandleTable;
+    use crate::{error::*, schemev2::CallerCtx};
+
+    #[test]
+    fn table() {
+        let opener = CallerCtx {
+            pid: 2,
+            uid: 1000,
+            gid: 100,
+        };
+        let other = CallerCtx { pid: 3, ..opener };
+        let mut table = HandleTable::new();
+        let a = table.insert("a", &opener);
+        let b = table.insert("b", &opener);
+        assert_eq!((a, b), (1, 2));
+        assert_eq!(table.get(b), Ok(&"b"));
+        *table.get_mut(b).unwrap() = "c";
+
+        let dup = table.dup(b, &other).unwrap();
+        assert_eq!(table.get(dup), Ok(&"c"));
+        assert_eq!(table.opener(b), Ok(&opener));
+        assert_eq!(table.opener(dup), Ok(&other));
+        assert_eq!(table.dup(7, &other), Err(Error::new(EBADF)));
+
+        assert_eq!(table.close(a), Ok("a"));
+        assert_eq!(table.close(a), Err(Error::new(EBADF)));
+        assert_eq!(table.get(a), Err(Error::new(EBADF)));
+        assert_eq!(table.insert("d", &opener), 4);
+        assert_eq!(
+            table.iter().collect::<Vec<_>>(),
+            [(2, &"c"), (3, &"c"), (4, &"d")]
+        );
+        assert_eq!(table.len(), 3);
+    }
+}
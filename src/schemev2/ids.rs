@@ -0,0 +1,124 @@
+use core::{mem, slice::ChunksExact};
+
+use super::{Sqe, SqeFlags};
+use crate::error::*;
+
+/// The ids of a [`SqeFlags::MULTIPLE_IDS`] request, see [`Sqe::ids`].
+#[derive(Clone, Debug)]
+pub struct Ids<'a> {
+    chunks: ChunksExact<'a, u8>,
+}
+
+impl Iterator for Ids<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let chunk = self.chunks.next()?;
+        Some(usize::from_ne_bytes(chunk.try_into().unwrap()))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+impl ExactSizeIterator for Ids<'_> {}
+
+impl Sqe {
+    /// The ids of a [`SqeFlags::MULTIPLE_IDS`] request, read from `view`, memory which includes
+    /// the id buffer at `args[0]`, of `args[1]` bytes.
+    ///
+    /// Fails with `EINVAL` if the flag is not set, or the buffer is not an aligned array of
+    /// `usize`, and with `EFAULT` if it is not within `view`.
+    pub fn ids<'a>(&self, view: &'a [u8]) -> Result<Ids<'a>> {
+        let [addr, len, ..] = self.args;
+        let (addr, len) = match (usize::try_from(addr), usize::try_from(len)) {
+            (Ok(addr), Ok(len)) if self.sqe_flags.contains(SqeFlags::MULTIPLE_IDS) => (addr, len),
+            _ => return Err(Error::new(EINVAL)),
+        };
+        if !addr.is_multiple_of(mem::align_of::<usize>())
+            || !len.is_multiple_of(mem::size_of::<usize>())
+        {
+            return Err(Error::new(EINVAL));
+        }
+        let start = addr.checked_sub(view.as_ptr() as usize);
+        let buf = start
+            .and_then(|start| view.get(start..start.checked_add(len)?))
+            .ok_or(Error::new(EFAULT))?;
+        Ok(Ids {
+            chunks: buf.chunks_exact(mem::size_of::<usize>()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{mem, slice};
+    use std::vec::Vec;
+
+    use crate::{
+        error::*,
+        schemev2::{CallerCtx, Request, Sqe, SqeFlags},
+    };
+
+    fn bytes(ids: &[usize]) -> &[u8] {
+        unsafe { slice::from_raw_parts(ids.as_ptr().cast(), mem::size_of_val(ids)) }
+    }
+
+    #[test]
+    fn call_with_ids() {
+        let ids = [3, 5, 8];
+        let mut payload = [0; 4];
+        let metadata = [1, 2];
+        let request = Request::call_with_ids(&ids, &mut payload, &metadata).unwrap();
+        let sqe = request.encode(1, &CallerCtx::default());
+        assert!(sqe.sqe_flags.contains(SqeFlags::MULTIPLE_IDS));
+        assert_eq!(Request::decode(&sqe), Ok(request));
+        let Request::CallWithIds {
+            payload_len,
+            metadata_len,
+            ..
+        } = request
+        else {
+            unreachable!()
+        };
+        assert_eq!((payload_len, metadata_len), (4, 2));
+
+        // Through a view of just the ids, and of more memory around them.
+        let view = bytes(&ids);
+        assert_eq!(sqe.ids(view).unwrap().collect::<Vec<_>>(), ids);
+        let around = [0, 3, 5, 8, 0];
+        let mut moved = sqe;
+        moved.args[0] = around[1..].as_ptr() as u64;
+        let ids = moved.ids(bytes(&around)).unwrap();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids.collect::<Vec<_>>(), [3, 5, 8]);
+    }
+
+    #[test]
+    fn invalid() {
+        let ids = [1_usize, 2];
+        let view = bytes(&ids);
+        let sqe = Sqe {
+            sqe_flags: SqeFlags::MULTIPLE_IDS,
+            args: [ids.as_ptr() as u64, view.len() as u64, 0, 0, 0, 0],
+            ..Sqe::default()
+        };
+        assert!(sqe.ids(view).is_ok());
+
+        let mut no_flag = sqe;
+        no_flag.sqe_flags = SqeFlags::empty();
+        assert_eq!(no_flag.ids(view).err(), Some(Error::new(EINVAL)));
+        let mut unaligned = sqe;
+        unaligned.args[0] += 1;
+        assert_eq!(unaligned.ids(view).err(), Some(Error::new(EINVAL)));
+        let mut partial = sqe;
+        partial.args[1] -= 1;
+        assert_eq!(partial.ids(view).err(), Some(Error::new(EINVAL)));
+        let mut outside = sqe;
+        let size = mem::size_of::<usize>() as u64;
+        outside.args[1] += size;
+        assert_eq!(outside.ids(view).err(), Some(Error::new(EFAULT)));
+        outside.args[0] -= size;
+        outside.args[1] = size;
+        assert_eq!(outside.ids(view).err(), Some(Error::new(EFAULT)));
+    }
+}
@@ -0,0 +1,423 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use core::time::Duration;
+
+use super::{reply_cqe, CallerCtx, Cqe, Handler, Opcode, Request, Response, Sqe};
+use crate::error::*;
+
+/// A monotonic clock, for the layers that measure or wait.
+pub trait Clock {
+    /// The time since some fixed point.
+    fn now(&mut self) -> Duration;
+    fn sleep(&mut self, duration: Duration);
+}
+
+/// `CLOCK_MONOTONIC`, sleeping with `nanosleep`.
+#[cfg(feature = "userspace")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MonotonicClock;
+
+#[cfg(feature = "userspace")]
+impl Clock for MonotonicClock {
+    fn now(&mut self) -> Duration {
+        crate::time::Instant::now()
+            .ok()
+            .and_then(|now| Duration::try_from(now.to_timespec()).ok())
+            .unwrap_or_default()
+    }
+    fn sleep(&mut self, duration: Duration) {
+        let _ = crate::time::sleep(duration);
+    }
+}
+
+/// Whether the scheme must see the request whatever it is, since it releases resources.
+fn is_release(sqe: &Sqe) -> bool {
+    matches!(
+        Opcode::try_from_raw(sqe.opcode),
+        Some(Opcode::Close | Opcode::CloseMsg | Opcode::Detach | Opcode::Cancel)
+    )
+}
+
+/// The response to `sqe` among `cqes`, skipping fevents.
+fn response_to(sqe: &Sqe, cqes: &[Cqe]) -> Option<Result<Response>> {
+    cqes.iter()
+        .map(Cqe::decode)
+        .find(|response| match response {
+            Ok(response) => response.tag() == Some(sqe.tag),
+            Err(_) => true,
+        })
+}
+
+/// A request and the response to it, as passed to the function of a [`LogLayer`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Exchange {
+    pub ctx: CallerCtx,
+    /// The request, or why it could not be decoded.
+    pub request: Result<Request>,
+    /// The response, or `None` if the request was not answered right away, like
+    /// [`Request::is_oneway`] requests.
+    pub response: Option<Result<Response>>,
+}
+
+/// Passes every request and its response to `log` once the inner handler has answered it.
+pub struct LogLayer<H, F> {
+    handler: H,
+    log: F,
+}
+
+impl<H: Handler, F: FnMut(&Exchange)> LogLayer<H, F> {
+    pub fn new(handler: H, log: F) -> Self {
+        Self { handler, log }
+    }
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+    pub fn into_handler(self) -> H {
+        self.handler
+    }
+}
+
+impl<H: Handler, F: FnMut(&Exchange)> Handler for LogLayer<H, F> {
+    unsafe fn handle(&mut self, sqe: &Sqe, out: &mut Vec<Cqe>) {
+        let start = out.len();
+        self.handler.handle(sqe, out);
+        (self.log)(&Exchange {
+            ctx: CallerCtx::from_sqe(sqe),
+            request: Request::decode(sqe),
+            response: response_to(sqe, &out[start..]),
+        });
+    }
+}
+
+/// Fails requests with `EACCES` unless the caller's uid or gid is allowed, which none are to
+/// begin with.
+///
+/// Closes and cancellations are always passed on, so the inner handler can release what they
+/// refer to.
+pub struct AuthLayer<H> {
+    handler: H,
+    uids: BTreeSet<u32>,
+    gids: BTreeSet<u32>,
+}
+
+impl<H: Handler> AuthLayer<H> {
+    pub fn new(handler: H) -> Self {
+        Self {
+            handler,
+            uids: BTreeSet::new(),
+            gids: BTreeSet::new(),
+        }
+    }
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+    pub fn into_handler(self) -> H {
+        self.handler
+    }
+
+    pub fn allow_uid(&mut self, uid: u32) {
+        self.uids.insert(uid);
+    }
+    pub fn allow_gid(&mut self, gid: u32) {
+        self.gids.insert(gid);
+    }
+    pub fn is_allowed(&self, ctx: &CallerCtx) -> bool {
+        self.uids.contains(&ctx.uid) || self.gids.contains(&ctx.gid)
+    }
+}
+
+impl<H: Handler> Handler for AuthLayer<H> {
+    unsafe fn handle(&mut self, sqe: &Sqe, out: &mut Vec<Cqe>) {
+        if is_release(sqe) || self.is_allowed(&CallerCtx::from_sqe(sqe)) {
+            self.handler.handle(sqe, out);
+        } else {
+            out.extend(reply_cqe(sqe, Err(Error::new(EACCES))));
+        }
+    }
+}
+
+/// What a [`FaultLayer`] does to a request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Fault {
+    /// Fail with the errno, such as `EIO`, `EAGAIN` or `EINTR`, instead of passing it on.
+    Error(i32),
+    /// Wait before passing it on.
+    Delay(Duration),
+}
+
+struct FaultRule {
+    opcode: Option<Opcode>,
+    fault: Fault,
+    one_in: u32,
+}
+
+/// Injects faults into random requests, for testing how clients cope with them.
+///
+/// The faults come from a generator seeded at creation, so a run can be repeated. Closes and
+/// cancellations are never faulted.
+pub struct FaultLayer<H, C> {
+    handler: H,
+    clock: C,
+    rules: Vec<FaultRule>,
+    state: u64,
+}
+
+impl<H: Handler, C: Clock> FaultLayer<H, C> {
+    pub fn new(handler: H, clock: C, seed: u64) -> Self {
+        Self {
+            handler,
+            clock,
+            rules: Vec::new(),
+            state: seed,
+        }
+    }
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+    pub fn into_handler(self) -> H {
+        self.handler
+    }
+
+    /// Apply `fault` to one in `one_in` requests with `opcode`, or with any opcode if `None`.
+    ///
+    /// Rules are tried in the order they were added, so a request can be delayed by several
+    /// and then failed by the first error that applies.
+    pub fn inject(&mut self, opcode: Option<Opcode>, fault: Fault, one_in: u32) {
+        self.rules.push(FaultRule {
+            opcode,
+            fault,
+            one_in: one_in.max(1),
+        });
+    }
+    pub fn clear(&mut self) {
+        self.rules.clear();
+    }
+
+    /// SplitMix64.
+    fn next_random(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl<H: Handler, C: Clock> Handler for FaultLayer<H, C> {
+    unsafe fn handle(&mut self, sqe: &Sqe, out: &mut Vec<Cqe>) {
+        if !is_release(sqe) {
+            let opcode = Opcode::try_from_raw(sqe.opcode);
+            for index in 0..self.rules.len() {
+                let FaultRule {
+                    opcode: wanted,
+                    fault,
+                    one_in,
+                } = self.rules[index];
+                if wanted.is_some_and(|wanted| Some(wanted) != opcode)
+                    || !self.next_random().is_multiple_of(u64::from(one_in))
+                {
+                    continue;
+                }
+                match fault {
+                    Fault::Error(errno) => {
+                        return out.extend(reply_cqe(sqe, Err(Error::new(errno))));
+                    }
+                    Fault::Delay(duration) => self.clock.sleep(duration),
+                }
+            }
+        }
+        self.handler.handle(sqe, out);
+    }
+}
+
+/// How often requests with an opcode were handled, and how long it took.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct OpcodeStats {
+    pub count: u64,
+    /// How many were answered with an error.
+    pub errors: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl OpcodeStats {
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => self.total / u32::try_from(count).unwrap_or(u32::MAX),
+        }
+    }
+}
+
+/// Counts requests and measures their latency for each [`Opcode`].
+///
+/// The latency is the time the inner handler takes to handle the request, which for requests
+/// answered later is only the time until they are queued.
+pub struct StatsLayer<H, C> {
+    handler: H,
+    clock: C,
+    stats: BTreeMap<Opcode, OpcodeStats>,
+}
+
+impl<H: Handler, C: Clock> StatsLayer<H, C> {
+    pub fn new(handler: H, clock: C) -> Self {
+        Self {
+            handler,
+            clock,
+            stats: BTreeMap::new(),
+        }
+    }
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+    pub fn into_handler(self) -> H {
+        self.handler
+    }
+
+    /// The statistics of each opcode that was handled, in opcode order.
+    pub fn stats(&self) -> impl Iterator<Item = (Opcode, &OpcodeStats)> {
+        self.stats.iter().map(|(&opcode, stats)| (opcode, stats))
+    }
+    pub fn get(&self, opcode: Opcode) -> Option<&OpcodeStats> {
+        self.stats.get(&opcode)
+    }
+    pub fn reset(&mut self) {
+        self.stats.clear();
+    }
+}
+
+impl<H: Handler, C: Clock> Handler for StatsLayer<H, C> {
+    unsafe fn handle(&mut self, sqe: &Sqe, out: &mut Vec<Cqe>) {
+        let start = out.len();
+        let before = self.clock.now();
+        self.handler.handle(sqe, out);
+        let elapsed = self.clock.now().saturating_sub(before);
+
+        // Unknown opcodes are answered with `ENOSYS` by the handler, and not counted.
+        let Some(opcode) = Opcode::try_from_raw(sqe.opcode) else {
+            return;
+        };
+        let failed = matches!(
+            response_to(sqe, &out[start..]),
+            Some(Ok(Response::Regular { result: Err(_), .. }
+                | Response::NotifyOnDetach { result: Err(_), .. }))
+        );
+        let stats = self.stats.entry(opcode).or_default();
+        stats.count += 1;
+        stats.errors += u64::from(failed);
+        stats.total += elapsed;
+        stats.max = stats.max.max(elapsed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{cell::Cell, time::Duration};
+    use std::{rc::Rc, vec::Vec};
+
+    use super::{AuthLayer, Clock, Exchange, Fault, FaultLayer, LogLayer, StatsLayer};
+    use crate::{
+        error::*,
+        flag::{O_CREAT, O_RDWR},
+        schemev2::{CallerCtx, Loopback, Opcode, Request, Response, Tmpfs},
+    };
+
+    /// A clock that only moves when slept on, and by a millisecond per reading.
+    #[derive(Clone, Default)]
+    struct FakeClock(Rc<Cell<Duration>>);
+
+    impl Clock for FakeClock {
+        fn now(&mut self) -> Duration {
+            let now = self.0.get();
+            self.0.set(now + Duration::from_millis(1));
+            now
+        }
+        fn sleep(&mut self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+    }
+
+    fn ctx(uid: u32, gid: u32) -> CallerCtx {
+        CallerCtx { pid: 1, uid, gid }
+    }
+
+    #[test]
+    fn log_and_auth() {
+        let mut auth = AuthLayer::new(Tmpfs::new("tmp"));
+        auth.allow_uid(1000);
+        auth.allow_gid(50);
+        let mut log = Vec::new();
+        let layers = LogLayer::new(auth, |exchange: &Exchange| log.push(*exchange));
+        let mut fs = Loopback::new(layers, ctx(1000, 1000));
+
+        let fd = fs.open("file", O_RDWR | O_CREAT | 0o666).unwrap();
+        assert_eq!(fs.write(fd, b"hi"), Ok(2));
+        fs.set_ctx(ctx(1001, 50));
+        assert_eq!(fs.fsize(fd), Ok(2));
+        fs.set_ctx(ctx(1001, 1001));
+        assert_eq!(fs.fsize(fd), Err(Error::new(EACCES)));
+        assert_eq!(fs.close(fd), Ok(()));
+        drop(fs);
+
+        assert_eq!(log.len(), 6);
+        assert_eq!(log[3].ctx, ctx(1001, 1001));
+        assert!(matches!(log[3].request, Ok(Request::Fsize { .. })));
+        assert!(matches!(
+            log[3].response,
+            Some(Ok(Response::Regular { result: Err(err), .. })) if err.errno == EACCES
+        ));
+        assert!(matches!(log[4].request, Ok(Request::Detach { .. })));
+        assert!(matches!(log[5].request, Ok(Request::CloseMsg { .. })));
+        assert_eq!(log[5].response, None);
+    }
+
+    #[test]
+    fn faults_and_stats() {
+        let clock = FakeClock::default();
+        let mut faults = FaultLayer::new(Tmpfs::new("tmp"), clock.clone(), 7);
+        faults.inject(Some(Opcode::Write), Fault::Error(EIO), 1);
+        faults.inject(None, Fault::Delay(Duration::from_millis(10)), 2);
+        let stats = StatsLayer::new(faults, clock.clone());
+        let mut fs = Loopback::new(stats, ctx(1000, 1000));
+
+        let fd = fs.open("file", O_RDWR | O_CREAT | 0o666).unwrap();
+        for _ in 0..20 {
+            assert_eq!(fs.write(fd, b"hi"), Err(Error::new(EIO)));
+            assert_eq!(fs.fsize(fd), Ok(0));
+        }
+        fs.close(fd).unwrap();
+
+        let stats = fs.handler();
+        let write = stats.get(Opcode::Write).unwrap();
+        assert_eq!((write.count, write.errors), (20, 20));
+        let fsize = stats.get(Opcode::Fsize).unwrap();
+        assert_eq!((fsize.count, fsize.errors), (20, 0));
+        // Some requests were delayed, and some not.
+        assert_eq!(fsize.max, Duration::from_millis(11));
+        assert!(fsize.mean() > Duration::from_millis(1) && fsize.mean() < fsize.max);
+        assert_eq!(
+            stats.stats().map(|(opcode, _)| opcode).collect::<Vec<_>>(),
+            [
+                Opcode::Write,
+                Opcode::Fsize,
+                Opcode::CloseMsg,
+                Opcode::OpenAt,
+                Opcode::Detach
+            ]
+        );
+    }
+}
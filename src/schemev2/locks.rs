@@ -0,0 +1,328 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use core::ops::Range;
+
+use crate::{
+    data::Flock,
+    error::*,
+    flag::{F_RDLCK, F_UNLCK, F_WRLCK, SEEK_SET},
+};
+
+/// Whether a lock can be shared with other owners.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LockKind {
+    Read,
+    Write,
+}
+
+impl LockKind {
+    /// The kind of lock for [`Flock::l_type`], or `None` for [`F_UNLCK`].
+    ///
+    /// Fails with `EINVAL` for unknown types.
+    pub fn from_l_type(l_type: i16) -> Result<Option<Self>> {
+        match l_type {
+            F_RDLCK => Ok(Some(Self::Read)),
+            F_WRLCK => Ok(Some(Self::Write)),
+            F_UNLCK => Ok(None),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+}
+
+/// A lock of `owner` on the bytes `start..end`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ByteLock {
+    pub owner: usize,
+    pub kind: LockKind,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteLock {
+    fn overlaps(&self, range: &Range<u64>) -> bool {
+        self.start < range.end && range.start < self.end
+    }
+    fn conflicts(&self, other: &ByteLock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(&(other.start..other.end))
+            && (self.kind == LockKind::Write || other.kind == LockKind::Write)
+    }
+    /// The lock as a [`Flock`] relative to [`SEEK_SET`], to answer
+    /// [`StdFsCallKind::GetLock`](crate::flag::StdFsCallKind::GetLock). The owner is the pid.
+    pub fn to_flock(&self) -> Flock {
+        let offset = |n: u64| i64::try_from(n).unwrap_or(i64::MAX);
+        Flock {
+            l_type: match self.kind {
+                LockKind::Read => F_RDLCK,
+                LockKind::Write => F_WRLCK,
+            },
+            l_whence: SEEK_SET as i16,
+            l_start: offset(self.start),
+            l_len: match self.end {
+                u64::MAX => 0,
+                end => offset(end - self.start),
+            },
+            l_pid: self.owner,
+        }
+    }
+}
+
+/// POSIX advisory locks on a scheme's files, where owners are processes, and files are whatever
+/// the scheme tells them apart by, such as inode numbers.
+///
+/// An owner's locks are split and merged as it locks and unlocks ranges, so it holds at most one
+/// lock on any byte. Owners waiting for a lock, like with `F_SETLKW`, are tracked to detect
+/// deadlocks.
+#[derive(Default)]
+pub struct LockTable {
+    /// The locks on each file, by start.
+    files: BTreeMap<usize, Vec<ByteLock>>,
+    /// The lock each waiting owner waits for, and its file.
+    waiting: BTreeMap<usize, (usize, ByteLock)>,
+}
+
+impl LockTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The locks on `file`, by start.
+    pub fn locks(&self, file: usize) -> &[ByteLock] {
+        self.files.get(&file).map_or(&[], Vec::as_slice)
+    }
+    /// The locks owners wait for on `file`, which may be granted after [`LockTable::unlock`] or
+    /// [`LockTable::release`].
+    pub fn waiting(&self, file: usize) -> impl Iterator<Item = &ByteLock> {
+        self.waiting
+            .values()
+            .filter(move |(waits_on, _)| *waits_on == file)
+            .map(|(_, lock)| lock)
+    }
+    /// The first lock on `file` that conflicts with `lock`, which answers
+    /// [`StdFsCallKind::GetLock`](crate::flag::StdFsCallKind::GetLock).
+    pub fn conflict(&self, file: usize, lock: &ByteLock) -> Option<&ByteLock> {
+        self.locks(file).iter().find(|held| held.conflicts(lock))
+    }
+
+    /// Take `lock` on `file`, replacing the owner's locks on its bytes. Returns `false` if the
+    /// owner waits for it instead, until it tries again or [`LockTable::cancel_wait`].
+    ///
+    /// Fails with `EINVAL` if the lock is empty. If another owner's lock conflicts, fails with
+    /// `EAGAIN` unless `wait`, and with `EDEADLK` if waiting would deadlock, as an owner with a
+    /// conflicting lock waits for this owner, directly or not.
+    pub fn lock(&mut self, file: usize, lock: ByteLock, wait: bool) -> Result<bool> {
+        if lock.start >= lock.end {
+            return Err(Error::new(EINVAL));
+        }
+        if self.conflict(file, &lock).is_some() {
+            self.waiting.remove(&lock.owner);
+            if !wait {
+                return Err(Error::new(EAGAIN));
+            }
+            if self.deadlocks(file, &lock) {
+                return Err(Error::new(EDEADLK));
+            }
+            self.waiting.insert(lock.owner, (file, lock));
+            return Ok(false);
+        }
+        self.waiting.remove(&lock.owner);
+
+        let locks = self.files.entry(file).or_default();
+        carve(locks, lock.owner, &(lock.start..lock.end));
+        // The owner's other locks don't overlap, and aren't adjacent if of the same kind, so
+        // only the new lock's neighbours can merge with it.
+        let mut merged = lock;
+        locks.retain(|held| {
+            let adjacent = held.owner == lock.owner
+                && held.kind == lock.kind
+                && (held.end == lock.start || held.start == lock.end);
+            if adjacent {
+                merged.start = merged.start.min(held.start);
+                merged.end = merged.end.max(held.end);
+            }
+            !adjacent
+        });
+        locks.push(merged);
+        locks.sort_by_key(|held| held.start);
+        Ok(true)
+    }
+    /// Release the locks of `owner` on `range` of `file`.
+    pub fn unlock(&mut self, file: usize, owner: usize, range: Range<u64>) {
+        if let Some(locks) = self.files.get_mut(&file) {
+            carve(locks, owner, &range);
+            locks.sort_by_key(|held| held.start);
+            if locks.is_empty() {
+                self.files.remove(&file);
+            }
+        }
+    }
+    /// Stop waiting for a lock, such as when the request is cancelled.
+    pub fn cancel_wait(&mut self, owner: usize) {
+        self.waiting.remove(&owner);
+    }
+    /// Release every lock of `owner` on `file`, and stop it waiting for one. POSIX releases them
+    /// when the owner closes any fd for the file, which schemes learn of by opening it with
+    /// [`NewFdFlags::NOTIFY_ON_DETACH`](super::NewFdFlags::NOTIFY_ON_DETACH).
+    pub fn release(&mut self, file: usize, owner: usize) {
+        if self
+            .waiting
+            .get(&owner)
+            .is_some_and(|(waits_on, _)| *waits_on == file)
+        {
+            self.waiting.remove(&owner);
+        }
+        self.unlock(file, owner, 0..u64::MAX);
+    }
+
+    fn deadlocks(&self, file: usize, lock: &ByteLock) -> bool {
+        let mut visited = BTreeSet::new();
+        let mut blockers: Vec<usize> = self.blockers(file, lock).collect();
+        while let Some(owner) = blockers.pop() {
+            if owner == lock.owner {
+                return true;
+            }
+            if !visited.insert(owner) {
+                continue;
+            }
+            if let Some((file, waits_for)) = self.waiting.get(&owner) {
+                blockers.extend(self.blockers(*file, waits_for));
+            }
+        }
+        false
+    }
+    fn blockers<'a>(&'a self, file: usize, lock: &'a ByteLock) -> impl Iterator<Item = usize> + 'a {
+        self.locks(file)
+            .iter()
+            .filter(move |held| held.conflicts(lock))
+            .map(|held| held.owner)
+    }
+}
+
+/// Remove `range` from the locks of `owner`, splitting those that extend past it.
+fn carve(locks: &mut Vec<ByteLock>, owner: usize, range: &Range<u64>) {
+    let old = core::mem::take(locks);
+    for held in old {
+        if held.owner != owner || !held.overlaps(range) {
+            locks.push(held);
+            continue;
+        }
+        if held.start < range.start {
+            locks.push(ByteLock {
+                end: range.start,
+                ..held
+            });
+        }
+        if held.end > range.end {
+            locks.push(ByteLock {
+                start: range.end,
+                ..held
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::{
+        ByteLock, LockKind,
+        LockKind::{Read, Write},
+        LockTable,
+    };
+    use crate::{
+        error::*,
+        flag::{F_UNLCK, F_WRLCK},
+    };
+
+    fn lock(owner: usize, kind: LockKind, start: u64, end: u64) -> ByteLock {
+        ByteLock {
+            owner,
+            kind,
+            start,
+            end,
+        }
+    }
+    fn ranges(table: &LockTable, file: usize) -> Vec<(usize, LockKind, u64, u64)> {
+        table
+            .locks(file)
+            .iter()
+            .map(|held| (held.owner, held.kind, held.start, held.end))
+            .collect()
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let mut table = LockTable::new();
+        assert_eq!(table.lock(1, lock(1, Write, 0, 100), false), Ok(true));
+        assert_eq!(table.lock(1, lock(1, Read, 40, 60), false), Ok(true));
+        assert_eq!(
+            ranges(&table, 1),
+            [(1, Write, 0, 40), (1, Read, 40, 60), (1, Write, 60, 100)]
+        );
+        table.unlock(1, 1, 50..70);
+        assert_eq!(
+            ranges(&table, 1),
+            [(1, Write, 0, 40), (1, Read, 40, 50), (1, Write, 70, 100)]
+        );
+        assert_eq!(table.lock(1, lock(1, Write, 40, 70), false), Ok(true));
+        assert_eq!(ranges(&table, 1), [(1, Write, 0, 100)]);
+        assert_eq!(
+            table.lock(1, lock(1, Write, 5, 5), false),
+            Err(Error::new(EINVAL))
+        );
+
+        table.release(1, 1);
+        assert!(table.locks(1).is_empty());
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut table = LockTable::new();
+        table.lock(1, lock(1, Write, 0, 100), false).unwrap();
+        table.lock(1, lock(1, Read, 200, u64::MAX), false).unwrap();
+
+        let wanted = lock(2, Read, 50, 60);
+        let held = *table.conflict(1, &wanted).unwrap();
+        assert_eq!(held, lock(1, Write, 0, 100));
+        assert_eq!(table.lock(1, wanted, false), Err(Error::new(EAGAIN)));
+        assert_eq!(held.to_flock().l_type, F_WRLCK);
+        assert_eq!((held.to_flock().l_len, held.to_flock().l_pid), (100, 1));
+        // Reads share, and other files and owners' own locks don't conflict.
+        assert_eq!(table.lock(1, lock(2, Read, 300, 400), false), Ok(true));
+        assert_eq!(table.lock(2, lock(2, Write, 0, 100), false), Ok(true));
+        assert!(table.conflict(1, &lock(1, Write, 0, 400)).is_some());
+        assert!(table.conflict(1, &lock(1, Write, 0, 300)).is_none());
+
+        let flock = table.locks(1)[1].to_flock();
+        assert_eq!((flock.l_start, flock.l_len), (200, 0));
+        assert_eq!(LockKind::from_l_type(F_UNLCK), Ok(None));
+        assert_eq!(LockKind::from_l_type(3), Err(Error::new(EINVAL)));
+    }
+
+    #[test]
+    fn deadlock() {
+        let mut table = LockTable::new();
+        table.lock(1, lock(1, Write, 0, 10), false).unwrap();
+        table.lock(2, lock(2, Write, 0, 10), false).unwrap();
+        table.lock(1, lock(3, Write, 10, 20), false).unwrap();
+
+        // 1 waits for 2, and 2 for 3, so 3 waiting for 1 would deadlock.
+        assert_eq!(table.lock(2, lock(1, Write, 0, 10), true), Ok(false));
+        assert_eq!(table.lock(1, lock(2, Read, 10, 20), true), Ok(false));
+        assert_eq!(table.waiting(1).count(), 1);
+        assert_eq!(
+            table.lock(1, lock(3, Write, 0, 5), true),
+            Err(Error::new(EDEADLK))
+        );
+        table.cancel_wait(2);
+        assert_eq!(table.lock(1, lock(3, Write, 0, 5), true), Ok(false));
+
+        // Once 2 closes its file, 1 gets the lock.
+        table.release(2, 2);
+        assert_eq!(table.lock(2, lock(1, Write, 0, 10), true), Ok(true));
+        assert_eq!(table.waiting(2).count(), 0);
+    }
+}
@@ -0,0 +1,618 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{mem, slice};
+
+use super::{handle_sync, CallerCtx, Cqe, NewFdFlags, Request, Response, SchemeSync, Sqe};
+use crate::{
+    data::{Stat, StdFsCallMeta},
+    dirent::DirentHeader,
+    error::*,
+    flag::{EventFlags, SEEK_CUR, SEEK_END, SEEK_SET},
+};
+
+/// Something that answers [`Sqe`]s, like the main loop of a scheme daemon.
+///
+/// Implemented for every [`SchemeSync`]. Implement it directly to also send fevents or use other
+/// [`Cqe`] opcodes.
+pub trait Handler {
+    /// Handle `sqe`, pushing its response and any other `Cqe`s, such as fevents, to `out`.
+    ///
+    /// # Safety
+    ///
+    /// The pointers in `sqe.args` must be valid for the request.
+    unsafe fn handle(&mut self, sqe: &Sqe, out: &mut Vec<Cqe>);
+}
+
+impl<S: SchemeSync + ?Sized> Handler for S {
+    unsafe fn handle(&mut self, sqe: &Sqe, out: &mut Vec<Cqe>) {
+        out.extend(handle_sync(self, sqe));
+    }
+}
+
+/// What a [`Loopback`] fd refers to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LoopbackFd {
+    /// A file description in the scheme.
+    Scheme {
+        number: usize,
+        /// The file offset, if the scheme asked for [`NewFdFlags::POSITIONED`].
+        offset: Option<u64>,
+        /// Whether to send [`Request::Detach`] when the fd is closed.
+        notify_on_detach: bool,
+    },
+    /// One of the scheme process's own fds, passed to the client.
+    Passed { fd: usize },
+}
+
+/// An in-process client for a scheme, for testing it under plain `cargo test`.
+///
+/// Each call is encoded as an [`Sqe`] from `ctx` and handed to the [`Handler`] right away, and
+/// its [`Cqe`] is turned back into a result. Fds the scheme opens or passes are kept in a
+/// simulated fd table, and fevents are collected for [`Loopback::take_events`]. Responses that
+/// break the protocol, such as a missing or duplicate answer, fail with `EIO`.
+pub struct Loopback<H> {
+    handler: H,
+    ctx: CallerCtx,
+    next_tag: u32,
+    fds: BTreeMap<usize, LoopbackFd>,
+    events: Vec<(usize, EventFlags)>,
+}
+
+impl<H: Handler> Loopback<H> {
+    pub fn new(handler: H, ctx: CallerCtx) -> Self {
+        Self {
+            handler,
+            ctx,
+            next_tag: 0,
+            fds: BTreeMap::new(),
+            events: Vec::new(),
+        }
+    }
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+    pub fn into_handler(self) -> H {
+        self.handler
+    }
+    pub fn ctx(&self) -> CallerCtx {
+        self.ctx
+    }
+    /// Send the following requests on behalf of `ctx`.
+    pub fn set_ctx(&mut self, ctx: CallerCtx) {
+        self.ctx = ctx;
+    }
+    pub fn fd(&self, fd: usize) -> Option<LoopbackFd> {
+        self.fds.get(&fd).copied()
+    }
+    /// The fevents the scheme sent so far, as scheme ids and flags.
+    pub fn take_events(&mut self) -> Vec<(usize, EventFlags)> {
+        mem::take(&mut self.events)
+    }
+
+    /// Send `request` and return the response, or `None` for [`Request::is_oneway`] requests.
+    /// Fds in the response are not added to the fd table, see [`Loopback::send_for_fds`].
+    ///
+    /// # Safety
+    ///
+    /// The pointers in `request` must be valid for it.
+    pub unsafe fn send(&mut self, request: Request) -> Result<Option<Response>> {
+        let tag = self.next_tag;
+        self.next_tag = tag.wrapping_add(1);
+        let sqe = request.encode(tag, &self.ctx);
+
+        let mut cqes = Vec::new();
+        self.handler.handle(&sqe, &mut cqes);
+
+        let mut response = None;
+        for cqe in cqes {
+            match cqe.decode().map_err(|_| Error::new(EIO))? {
+                Response::Fevent { id, flags } => self.events.push((id, flags)),
+                other if other.tag() == Some(tag) && response.is_none() => response = Some(other),
+                _ => return Err(Error::new(EIO)),
+            }
+        }
+        match (request.is_oneway(), response) {
+            (true, None) => Ok(None),
+            (false, Some(response)) => Ok(Some(response)),
+            _ => Err(Error::new(EIO)),
+        }
+    }
+
+    /// Send `request`, and add the fds in the response to the fd table. Returns the new fds.
+    ///
+    /// # Safety
+    ///
+    /// The pointers in `request` must be valid for it.
+    pub unsafe fn send_for_fds(&mut self, request: Request) -> Result<Vec<usize>> {
+        let response = self.send(request)?.ok_or(Error::new(EIO))?;
+        let fds: Vec<LoopbackFd> = match response {
+            Response::Regular { result, flags, .. } => {
+                let offset = flags.contains(NewFdFlags::POSITIONED).then_some(0);
+                [LoopbackFd::Scheme {
+                    number: result?,
+                    offset,
+                    notify_on_detach: false,
+                }]
+                .into()
+            }
+            Response::NotifyOnDetach { result, flags, .. } => [LoopbackFd::Scheme {
+                number: result?,
+                offset: flags.contains(NewFdFlags::POSITIONED).then_some(0),
+                notify_on_detach: true,
+            }]
+            .into(),
+            Response::WithFd { fd, .. } => [LoopbackFd::Passed { fd }].into(),
+            Response::WithMultipleFds { fds, count, .. } => {
+                // The scheme keeps the array alive until the response is read, which is now.
+                let fds = match count {
+                    0 => &[],
+                    _ => slice::from_raw_parts(fds as *const usize, count),
+                };
+                fds.iter().map(|&fd| LoopbackFd::Passed { fd }).collect()
+            }
+            Response::ObtainFd { .. } | Response::Fevent { .. } | Response::ProvideMmap { .. } => {
+                return Err(Error::new(EOPNOTSUPP))
+            }
+        };
+        Ok(fds.into_iter().map(|fd| self.insert(fd)).collect())
+    }
+
+    fn insert(&mut self, fd: LoopbackFd) -> usize {
+        // Lowest free fd, like the kernel.
+        let number = (0..)
+            .zip(self.fds.keys())
+            .find(|(free, used)| free != *used)
+            .map_or(self.fds.len(), |(free, _)| free);
+        self.fds.insert(number, fd);
+        number
+    }
+
+    /// Send a request that answers with a regular result.
+    fn regular(&mut self, request: Request) -> Result<usize> {
+        // SAFETY: Only called with pointers from references that outlive the call.
+        match unsafe { self.send(request) }? {
+            Some(Response::Regular { result, flags, .. }) if flags.is_empty() => result,
+            _ => Err(Error::new(EIO)),
+        }
+    }
+
+    fn scheme_fd(&self, fd: usize) -> Result<(usize, Option<u64>)> {
+        match self.fds.get(&fd) {
+            Some(&LoopbackFd::Scheme { number, offset, .. }) => Ok((number, offset)),
+            _ => Err(Error::new(EBADF)),
+        }
+    }
+    fn advance(&mut self, fd: usize, count: usize) {
+        if let Some(LoopbackFd::Scheme {
+            offset: Some(offset),
+            ..
+        }) = self.fds.get_mut(&fd)
+        {
+            *offset += count as u64;
+        }
+    }
+
+    /// Open `path` relative to the scheme root, whose id is 0.
+    pub fn open(&mut self, path: &str, flags: usize) -> Result<usize> {
+        self.open_raw(0, path, flags)
+    }
+    /// Remove `path` relative to the scheme root.
+    pub fn unlink(&mut self, path: &str, flags: usize) -> Result<()> {
+        self.unlink_raw(0, path, flags)
+    }
+    /// Remove `path` relative to `dirfd`.
+    pub fn unlinkat(&mut self, dirfd: usize, path: &str, flags: usize) -> Result<()> {
+        let (dirfd, _) = self.scheme_fd(dirfd)?;
+        self.unlink_raw(dirfd, path, flags)
+    }
+    fn unlink_raw(&mut self, dirfd: usize, path: &str, flags: usize) -> Result<()> {
+        self.regular(Request::UnlinkAt {
+            dirfd,
+            path: path.as_ptr() as usize,
+            path_len: path.len(),
+            flags,
+        })
+        .map(|_| ())
+    }
+    /// Open `path` relative to `dirfd`.
+    pub fn openat(&mut self, dirfd: usize, path: &str, flags: usize) -> Result<usize> {
+        let (dirfd, _) = self.scheme_fd(dirfd)?;
+        self.open_raw(dirfd, path, flags)
+    }
+    fn open_raw(&mut self, dirfd: usize, path: &str, flags: usize) -> Result<usize> {
+        let request = Request::OpenAt {
+            dirfd,
+            path: path.as_ptr() as usize,
+            path_len: path.len(),
+            flags,
+            fcntl_flags: 0,
+        };
+        // SAFETY: `path` outlives the request.
+        let fds = unsafe { self.send_for_fds(request) }?;
+        Ok(fds[0])
+    }
+    pub fn dup(&mut self, fd: usize, buf: &[u8]) -> Result<usize> {
+        let (old_id, _) = self.scheme_fd(fd)?;
+        let request = Request::Dup {
+            old_id,
+            buf: buf.as_ptr() as usize,
+            len: buf.len(),
+        };
+        // SAFETY: `buf` outlives the request.
+        let fds = unsafe { self.send_for_fds(request) }?;
+        Ok(fds[0])
+    }
+
+    /// Read at the file offset, which is advanced if the fd is positioned.
+    pub fn read(&mut self, fd: usize, buf: &mut [u8]) -> Result<usize> {
+        let (id, offset) = self.scheme_fd(fd)?;
+        let count = self.regular(Request::Read {
+            id,
+            buf: buf.as_mut_ptr() as usize,
+            len: buf.len(),
+            offset: offset.unwrap_or(0),
+            fcntl_flags: 0,
+        })?;
+        self.advance(fd, count);
+        Ok(count)
+    }
+    /// Write at the file offset, which is advanced if the fd is positioned.
+    pub fn write(&mut self, fd: usize, buf: &[u8]) -> Result<usize> {
+        let (id, offset) = self.scheme_fd(fd)?;
+        let count = self.regular(Request::Write {
+            id,
+            buf: buf.as_ptr() as usize,
+            len: buf.len(),
+            offset: offset.unwrap_or(0),
+            fcntl_flags: 0,
+        })?;
+        self.advance(fd, count);
+        Ok(count)
+    }
+    /// Set the file offset of a positioned fd, where `SEEK_END` is relative to the size from
+    /// [`Request::Fsize`], like the kernel does. Returns the new offset.
+    ///
+    /// Fails with `ESPIPE` if the fd is not positioned, and with `EINVAL` for an unknown `whence`
+    /// or a negative offset.
+    pub fn lseek(&mut self, fd: usize, pos: i64, whence: usize) -> Result<u64> {
+        let (_, offset) = self.scheme_fd(fd)?;
+        let offset = offset.ok_or(Error::new(ESPIPE))?;
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => offset,
+            SEEK_END => self.fsize(fd)?,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        let new = base
+            .checked_add_signed(pos)
+            .ok_or(Error::new(match pos < 0 {
+                true => EINVAL,
+                false => EOVERFLOW,
+            }))?;
+        if let Some(LoopbackFd::Scheme {
+            offset: Some(offset),
+            ..
+        }) = self.fds.get_mut(&fd)
+        {
+            *offset = new;
+        }
+        Ok(new)
+    }
+    pub fn fsize(&mut self, fd: usize) -> Result<u64> {
+        let (id, _) = self.scheme_fd(fd)?;
+        self.regular(Request::Fsize { id }).map(|size| size as u64)
+    }
+    pub fn fstat(&mut self, fd: usize, stat: &mut Stat) -> Result<()> {
+        let (id, _) = self.scheme_fd(fd)?;
+        self.regular(Request::Fstat {
+            id,
+            buf: stat as *mut Stat as usize,
+            len: mem::size_of::<Stat>(),
+        })
+        .map(|_| ())
+    }
+    pub fn fpath(&mut self, fd: usize, buf: &mut [u8]) -> Result<usize> {
+        let (id, _) = self.scheme_fd(fd)?;
+        self.regular(Request::Fpath {
+            id,
+            buf: buf.as_mut_ptr() as usize,
+            len: buf.len(),
+        })
+    }
+    pub fn flink(&mut self, fd: usize, path: &str) -> Result<()> {
+        let (id, _) = self.scheme_fd(fd)?;
+        self.regular(Request::Flink {
+            id,
+            path: path.as_ptr() as usize,
+            path_len: path.len(),
+        })
+        .map(|_| ())
+    }
+    pub fn frename(&mut self, fd: usize, path: &str) -> Result<()> {
+        let (id, _) = self.scheme_fd(fd)?;
+        self.regular(Request::Frename {
+            id,
+            path: path.as_ptr() as usize,
+            path_len: path.len(),
+        })
+        .map(|_| ())
+    }
+    pub fn ftruncate(&mut self, fd: usize, len: u64) -> Result<()> {
+        let (id, _) = self.scheme_fd(fd)?;
+        self.regular(Request::Ftruncate { id, len }).map(|_| ())
+    }
+    /// Read directory entries from `opaque_offset` into `buf`, returning the bytes written.
+    pub fn getdents(&mut self, fd: usize, buf: &mut [u8], opaque_offset: u64) -> Result<usize> {
+        let (id, _) = self.scheme_fd(fd)?;
+        self.regular(Request::Getdents {
+            id,
+            buf: buf.as_mut_ptr() as usize,
+            len: buf.len(),
+            header_size: mem::size_of::<DirentHeader>() as u16,
+            opaque_offset,
+        })
+    }
+    /// Subscribe to `flags` on `fd`, returning the events that are ready now.
+    pub fn fevent(&mut self, fd: usize, flags: EventFlags) -> Result<EventFlags> {
+        let (id, _) = self.scheme_fd(fd)?;
+        let ready = self.regular(Request::Fevent { id, flags })?;
+        EventFlags::from_bits(ready).ok_or(Error::new(EIO))
+    }
+    pub fn fsync(&mut self, fd: usize) -> Result<()> {
+        let (id, _) = self.scheme_fd(fd)?;
+        self.regular(Request::Fsync { id }).map(|_| ())
+    }
+    pub fn call(&mut self, fd: usize, payload: &mut [u8], metadata: &[u64]) -> Result<usize> {
+        let (id, _) = self.scheme_fd(fd)?;
+        self.regular(Request::Call {
+            id,
+            payload: payload.as_mut_ptr() as usize,
+            payload_len: payload.len(),
+            metadata: metadata.as_ptr() as usize,
+            metadata_len: metadata.len(),
+        })
+    }
+    pub fn std_fs_call(
+        &mut self,
+        fd: usize,
+        payload: &mut [u8],
+        meta: &StdFsCallMeta,
+    ) -> Result<usize> {
+        let (id, _) = self.scheme_fd(fd)?;
+        // `StdFsCallMeta` is packed, but sent as aligned words.
+        let words: [u64; 3] = unsafe {
+            (meta as *const StdFsCallMeta)
+                .cast::<[u64; 3]>()
+                .read_unaligned()
+        };
+        self.regular(Request::StdFsCall {
+            id,
+            payload: payload.as_mut_ptr() as usize,
+            payload_len: payload.len(),
+            metadata: words.as_ptr() as usize,
+            metadata_len: words.len(),
+        })
+    }
+
+    /// Remove `fd` from the fd table. For scheme fds, this sends [`Request::Detach`] if the
+    /// scheme asked for it, and then [`Request::CloseMsg`].
+    pub fn close(&mut self, fd: usize) -> Result<()> {
+        let (id, detach) = match self.fds.remove(&fd).ok_or(Error::new(EBADF))? {
+            LoopbackFd::Scheme {
+                number,
+                notify_on_detach,
+                ..
+            } => (number, notify_on_detach),
+            LoopbackFd::Passed { .. } => return Ok(()),
+        };
+        if detach {
+            self.regular(Request::Detach { id })?;
+        }
+        // SAFETY: No pointers.
+        unsafe { self.send(Request::CloseMsg { id }) }.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{
+        collections::BTreeMap,
+        string::{String, ToString},
+        vec::Vec,
+    };
+
+    use super::{Handler, Loopback, LoopbackFd};
+    use crate::{
+        data::Stat,
+        error::*,
+        flag::{EventFlags, MODE_FILE},
+        schemev2::{handle_sync, CallerCtx, Cqe, NewFdFlags, OpenResult, Request, SchemeSync, Sqe},
+    };
+
+    /// Files in memory, readable by their owner only.
+    #[derive(Default)]
+    struct MemFs {
+        files: BTreeMap<String, (u32, Vec<u8>)>,
+        handles: BTreeMap<usize, String>,
+        next: usize,
+        closed: Vec<usize>,
+    }
+
+    impl SchemeSync for MemFs {
+        fn openat(
+            &mut self,
+            _dirfd: usize,
+            path: &str,
+            _flags: usize,
+            _fcntl_flags: u32,
+            ctx: &CallerCtx,
+        ) -> Result<OpenResult> {
+            if path == "null" {
+                return Ok(OpenResult::OtherScheme { fd: 99 });
+            }
+            let (owner, _) = self
+                .files
+                .entry(path.to_string())
+                .or_insert((ctx.uid, Vec::new()));
+            if *owner != ctx.uid {
+                return Err(Error::new(EACCES));
+            }
+            self.next += 1;
+            self.handles.insert(self.next, path.to_string());
+            Ok(OpenResult::ThisScheme {
+                number: self.next,
+                flags: NewFdFlags::POSITIONED,
+            })
+        }
+        fn read(
+            &mut self,
+            id: usize,
+            buf: &mut [u8],
+            offset: u64,
+            _fcntl_flags: u32,
+            _ctx: &CallerCtx,
+        ) -> Result<usize> {
+            let data = &self.files[&self.handles[&id]].1;
+            let data = data.get(offset as usize..).unwrap_or(&[]);
+            let count = data.len().min(buf.len());
+            buf[..count].copy_from_slice(&data[..count]);
+            Ok(count)
+        }
+        fn write(
+            &mut self,
+            id: usize,
+            buf: &[u8],
+            offset: u64,
+            _fcntl_flags: u32,
+            _ctx: &CallerCtx,
+        ) -> Result<usize> {
+            let data = &mut self.files.get_mut(&self.handles[&id]).unwrap().1;
+            let end = offset as usize + buf.len();
+            data.resize(data.len().max(end), 0);
+            data[offset as usize..end].copy_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn fstat(&mut self, id: usize, stat: &mut Stat, _ctx: &CallerCtx) -> Result<()> {
+            stat.st_mode = MODE_FILE | 0o600;
+            stat.st_size = self.files[&self.handles[&id]].1.len() as u64;
+            Ok(())
+        }
+        fn fevent(
+            &mut self,
+            _id: usize,
+            _flags: EventFlags,
+            _ctx: &CallerCtx,
+        ) -> Result<EventFlags> {
+            Ok(EventFlags::empty())
+        }
+        fn on_close(&mut self, id: usize) {
+            self.handles.remove(&id);
+            self.closed.push(id);
+        }
+    }
+
+    /// Sends an fevent after every write, and passes two fds on `Call`.
+    struct Notifying {
+        fs: MemFs,
+        passed: [usize; 2],
+    }
+
+    impl Handler for Notifying {
+        unsafe fn handle(&mut self, sqe: &Sqe, out: &mut Vec<Cqe>) {
+            match Request::decode(sqe) {
+                Ok(Request::Call { .. }) => {
+                    out.push(Cqe::respond_with_multiple_fds(sqe.tag, &self.passed).unwrap())
+                }
+                Ok(Request::Write { id, .. }) => {
+                    out.extend(handle_sync(&mut self.fs, sqe));
+                    out.push(Cqe::send_fevent(id, EventFlags::EVENT_READ));
+                }
+                _ => out.extend(handle_sync(&mut self.fs, sqe)),
+            }
+        }
+    }
+
+    fn user(uid: u32) -> CallerCtx {
+        CallerCtx {
+            pid: 10,
+            uid,
+            gid: uid,
+        }
+    }
+
+    #[test]
+    fn files() {
+        let mut client = Loopback::new(MemFs::default(), user(1000));
+        let fd = client.open("notes", 0).unwrap();
+        assert_eq!(client.write(fd, b"hello "), Ok(6));
+        assert_eq!(client.write(fd, b"world"), Ok(5));
+
+        let mut stat = Stat::default();
+        client.fstat(fd, &mut stat).unwrap();
+        assert_eq!(stat.st_size, 11);
+
+        // A new fd starts at offset 0, while the first one is at the end.
+        let other = client.open("notes", 0).unwrap();
+        assert_eq!(other, fd + 1);
+        let mut buf = [0; 16];
+        assert_eq!(client.read(other, &mut buf), Ok(11));
+        assert_eq!(&buf[..11], b"hello world");
+        assert_eq!(client.read(fd, &mut buf), Ok(0));
+
+        client.set_ctx(user(1001));
+        assert_eq!(client.open("notes", 0), Err(Error::new(EACCES)));
+
+        // Unimplemented methods, passed fds, and closing.
+        assert_eq!(client.fsync(fd), Err(Error::new(EBADF)));
+        let null = client.open("null", 0).unwrap();
+        assert_eq!(client.fd(null), Some(LoopbackFd::Passed { fd: 99 }));
+        client.close(fd).unwrap();
+        assert_eq!(client.close(fd), Err(Error::new(EBADF)));
+        assert_eq!(client.open("notes2", 0), Ok(fd));
+        assert_eq!(client.handler().closed, [1]);
+    }
+
+    #[test]
+    fn events_and_multiple_fds() {
+        let handler = Notifying {
+            fs: MemFs::default(),
+            passed: [20, 21],
+        };
+        let mut client = Loopback::new(handler, user(0));
+        let fd = client.open("pipe", 0).unwrap();
+        assert_eq!(
+            client.fevent(fd, EventFlags::EVENT_READ),
+            Ok(EventFlags::empty())
+        );
+        client.write(fd, b"x").unwrap();
+        client.write(fd, b"y").unwrap();
+        assert_eq!(
+            client.take_events(),
+            [(1, EventFlags::EVENT_READ), (1, EventFlags::EVENT_READ)]
+        );
+        assert!(client.take_events().is_empty());
+
+        assert_eq!(
+            client.fd(fd),
+            Some(LoopbackFd::Scheme {
+                number: 1,
+                offset: Some(2),
+                notify_on_detach: false
+            })
+        );
+        let fds = unsafe {
+            client.send_for_fds(Request::Call {
+                id: 1,
+                payload: 0,
+                payload_len: 0,
+                metadata: 0,
+                metadata_len: 0,
+            })
+        }
+        .unwrap();
+        assert_eq!(fds, [1, 2]);
+        assert_eq!(client.fd(2), Some(LoopbackFd::Passed { fd: 21 }));
+    }
+}
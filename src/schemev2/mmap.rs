@@ -0,0 +1,390 @@
+use alloc::{
+    alloc::{alloc_zeroed, dealloc},
+    collections::BTreeMap,
+    vec,
+    vec::Vec,
+};
+use core::{alloc::Layout, ops::Range, ptr::NonNull, slice};
+
+use crate::{
+    error::*,
+    flag::{MapFlags, MunmapFlags},
+    PAGE_SIZE,
+};
+
+/// Where [`MmapPages`] reads the contents of mappings from, and writes them back to.
+pub trait MmapSource {
+    /// Fill `buf` with the contents of `id` at `offset`.
+    fn read_pages(&mut self, id: usize, offset: u64, buf: &mut [u8]) -> Result<()>;
+    /// Write `buf`, the mapped contents of `id` at `offset`, back.
+    fn write_pages(&mut self, id: usize, offset: u64, buf: &[u8]) -> Result<()>;
+}
+
+/// Pages allocated for consecutive offsets of one file description.
+struct Chunk {
+    ptr: NonNull<u8>,
+    /// How many mappings include each page.
+    refs: Vec<u32>,
+    /// Whether each page was read from the source yet.
+    filled: Vec<bool>,
+}
+
+impl Chunk {
+    fn layout(pages: usize) -> Result<Layout> {
+        pages
+            .checked_mul(PAGE_SIZE)
+            .and_then(|size| Layout::from_size_align(size, PAGE_SIZE).ok())
+            .ok_or(Error::new(ENOMEM))
+    }
+    fn new(pages: usize) -> Result<Self> {
+        let ptr = unsafe { alloc_zeroed(Self::layout(pages)?) };
+        Ok(Self {
+            ptr: NonNull::new(ptr).ok_or(Error::new(ENOMEM))?,
+            refs: vec![0; pages],
+            filled: vec![false; pages],
+        })
+    }
+    fn addr(&self, page: usize) -> usize {
+        self.ptr.as_ptr() as usize + page * PAGE_SIZE
+    }
+    fn bytes(&mut self, pages: Range<usize>) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(
+                self.ptr.as_ptr().add(pages.start * PAGE_SIZE),
+                pages.len() * PAGE_SIZE,
+            )
+        }
+    }
+    /// The first run of pages in `pages` where `filled` is `want`.
+    fn run(&self, pages: Range<usize>, want: bool) -> Option<Range<usize>> {
+        let start = pages.clone().find(|&page| self.filled[page] == want)?;
+        let end = (start..pages.end)
+            .find(|&page| self.filled[page] != want)
+            .unwrap_or(pages.end);
+        Some(start..end)
+    }
+    /// Read the pages in `pages` that aren't yet, where the chunk is at `base` in `id`.
+    fn fill(
+        &mut self,
+        source: &mut impl MmapSource,
+        id: usize,
+        base: u64,
+        pages: Range<usize>,
+    ) -> Result<()> {
+        let mut from = pages.start;
+        while let Some(run) = self.run(from..pages.end, false) {
+            source.read_pages(id, page_offset(base, run.start), self.bytes(run.clone()))?;
+            self.filled[run.clone()].fill(true);
+            from = run.end;
+        }
+        Ok(())
+    }
+    fn write_back(
+        &mut self,
+        source: &mut impl MmapSource,
+        id: usize,
+        base: u64,
+        pages: Range<usize>,
+    ) -> Result<()> {
+        let mut from = pages.start;
+        while let Some(run) = self.run(from..pages.end, true) {
+            source.write_pages(id, page_offset(base, run.start), self.bytes(run.clone()))?;
+            from = run.end;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        let layout = Self::layout(self.refs.len()).unwrap();
+        unsafe { dealloc(self.ptr.as_ptr(), layout) }
+    }
+}
+
+fn page_offset(base: u64, page: usize) -> u64 {
+    base + (page * PAGE_SIZE) as u64
+}
+
+/// Memory of the scheme's own that backs mappings of its file descriptions, read from and
+/// written back to an [`MmapSource`].
+///
+/// Mappings of the same pages of a file description share memory, which is freed once the last
+/// of them is unmapped. A mapping must lie either within the pages of existing ones, or outside
+/// of them, and fails with `EBUSY` otherwise. Offsets must be page aligned, and sizes are
+/// rounded up to whole pages.
+#[derive(Default)]
+pub struct MmapPages {
+    /// Keyed by file description and offset of the first page.
+    chunks: BTreeMap<(usize, u64), Chunk>,
+}
+
+impl MmapPages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The pages of `size` bytes at `offset`, as an offset range.
+    fn span(offset: u64, size: usize) -> Result<Range<u64>> {
+        let len = size
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or(Error::new(EINVAL))?;
+        match offset.checked_add(len as u64) {
+            Some(end) if size != 0 && offset.is_multiple_of(PAGE_SIZE as u64) => Ok(offset..end),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+    /// The chunk of `id` that contains `span`, and the pages of it that `span` covers.
+    fn find(&mut self, id: usize, span: &Range<u64>) -> Option<(u64, &mut Chunk, Range<usize>)> {
+        let (&(_, base), chunk) = self
+            .chunks
+            .range_mut((id, 0)..=(id, span.start))
+            .next_back()?;
+        let first = usize::try_from(span.start - base).ok()? / PAGE_SIZE;
+        let pages = first..first + (span.end - span.start) as usize / PAGE_SIZE;
+        (pages.end <= chunk.refs.len()).then_some((base, chunk, pages))
+    }
+
+    /// Answer [`Request::MmapPrep`](super::Request::MmapPrep): returns the address of memory
+    /// for `size` bytes at `offset` of `id`, read from `source` unless
+    /// [`MapFlags::MAP_LAZY`] is set.
+    pub fn mmap_prep(
+        &mut self,
+        source: &mut impl MmapSource,
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MapFlags,
+    ) -> Result<usize> {
+        let span = Self::span(offset, size)?;
+        if self.find(id, &span).is_none() {
+            // Chunks don't overlap, so only the last one starting before the end can.
+            let overlaps = self
+                .chunks
+                .range((id, 0)..(id, span.end))
+                .next_back()
+                .is_some_and(|(&(_, base), chunk)| {
+                    page_offset(base, chunk.refs.len()) > span.start
+                });
+            if overlaps {
+                return Err(Error::new(EBUSY));
+            }
+            let chunk = Chunk::new((span.end - span.start) as usize / PAGE_SIZE)?;
+            self.chunks.insert((id, offset), chunk);
+        }
+        let (base, chunk, pages) = self.find(id, &span).unwrap();
+
+        if !flags.contains(MapFlags::MAP_LAZY) {
+            if let Err(err) = chunk.fill(source, id, base, pages.clone()) {
+                if chunk.refs.iter().all(|&refs| refs == 0) {
+                    self.chunks.remove(&(id, base));
+                }
+                return Err(err);
+            }
+        }
+        chunk.refs[pages.clone()]
+            .iter_mut()
+            .for_each(|refs| *refs += 1);
+        Ok(chunk.addr(pages.start))
+    }
+
+    /// Answer [`Request::RequestMmap`](super::Request::RequestMmap) for a lazy mapping: reads
+    /// the pages of `size` bytes at `offset` of `id` that aren't yet, and returns their address.
+    ///
+    /// Fails with `EINVAL` if the pages are not mapped.
+    pub fn request_mmap(
+        &mut self,
+        source: &mut impl MmapSource,
+        id: usize,
+        offset: u64,
+        size: usize,
+    ) -> Result<usize> {
+        let span = Self::span(offset, size)?;
+        let (base, chunk, pages) = self.find(id, &span).ok_or(Error::new(EINVAL))?;
+        chunk.fill(source, id, base, pages.clone())?;
+        Ok(chunk.addr(pages.start))
+    }
+
+    /// Answer [`Request::Munmap`](super::Request::Munmap), writing the pages back first if
+    /// `flags` contains [`MunmapFlags::NEEDS_SYNC`]. The pages are released even if that fails.
+    ///
+    /// Fails with `EINVAL` if the pages are not mapped.
+    pub fn munmap(
+        &mut self,
+        source: &mut impl MmapSource,
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MunmapFlags,
+    ) -> Result<()> {
+        let span = Self::span(offset, size)?;
+        let (base, chunk, pages) = self.find(id, &span).ok_or(Error::new(EINVAL))?;
+        if chunk.refs[pages.clone()].contains(&0) {
+            return Err(Error::new(EINVAL));
+        }
+
+        let result = match flags.contains(MunmapFlags::NEEDS_SYNC) {
+            true => chunk.write_back(source, id, base, pages.clone()),
+            false => Ok(()),
+        };
+        chunk.refs[pages].iter_mut().for_each(|refs| *refs -= 1);
+        if chunk.refs.iter().all(|&refs| refs == 0) {
+            self.chunks.remove(&(id, base));
+        }
+        result
+    }
+
+    /// Answer [`Request::Msync`](super::Request::Msync) by writing the pages of `size` bytes
+    /// at `offset` of `id` back.
+    ///
+    /// Fails with `EINVAL` if the pages are not mapped.
+    pub fn msync(
+        &mut self,
+        source: &mut impl MmapSource,
+        id: usize,
+        offset: u64,
+        size: usize,
+    ) -> Result<()> {
+        let span = Self::span(offset, size)?;
+        let (base, chunk, pages) = self.find(id, &span).ok_or(Error::new(EINVAL))?;
+        chunk.write_back(source, id, base, pages)
+    }
+
+    /// How many pages of `id` are mapped.
+    pub fn mapped_pages(&self, id: usize) -> usize {
+        self.chunks
+            .range((id, 0)..=(id, u64::MAX))
+            .flat_map(|(_, chunk)| &chunk.refs)
+            .filter(|&&refs| refs != 0)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::{MmapPages, MmapSource};
+    use crate::{
+        error::*,
+        flag::{MapFlags, MunmapFlags},
+        PAGE_SIZE,
+    };
+
+    /// One file, with a log of the pages read.
+    struct File {
+        data: Vec<u8>,
+        reads: Vec<u64>,
+    }
+
+    impl MmapSource for File {
+        fn read_pages(&mut self, id: usize, offset: u64, buf: &mut [u8]) -> Result<()> {
+            assert_eq!(id, 1);
+            let offset = offset as usize;
+            self.reads.push(offset as u64);
+            buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+            Ok(())
+        }
+        fn write_pages(&mut self, id: usize, offset: u64, buf: &[u8]) -> Result<()> {
+            assert_eq!(id, 1);
+            let offset = offset as usize;
+            self.data[offset..offset + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    fn file() -> File {
+        File {
+            data: (0..4 * PAGE_SIZE).map(|i| (i / PAGE_SIZE) as u8).collect(),
+            reads: Vec::new(),
+        }
+    }
+    fn byte(addr: usize) -> *mut u8 {
+        addr as *mut u8
+    }
+
+    #[test]
+    fn shared_and_written_back() {
+        let mut file = file();
+        let mut pages = MmapPages::new();
+        let page = PAGE_SIZE as u64;
+        let flags = MapFlags::PROT_READ | MapFlags::PROT_WRITE | MapFlags::MAP_SHARED;
+
+        let addr = pages
+            .mmap_prep(&mut file, 1, page, 3 * PAGE_SIZE, flags)
+            .unwrap();
+        assert_eq!(file.reads, [page]);
+        assert_eq!(addr % PAGE_SIZE, 0);
+        assert_eq!(unsafe { *byte(addr + 2 * PAGE_SIZE) }, 3);
+
+        // A mapping within the first shares its memory, without reading again.
+        let inner = pages.mmap_prep(&mut file, 1, 2 * page, 1, flags).unwrap();
+        assert_eq!(inner, addr + PAGE_SIZE);
+        assert_eq!(file.reads, [page]);
+        assert_eq!(pages.mapped_pages(1), 3);
+        assert_eq!(
+            pages.mmap_prep(&mut file, 1, 0, 2 * PAGE_SIZE, flags),
+            Err(Error::new(EBUSY))
+        );
+        assert_eq!(
+            pages.mmap_prep(&mut file, 1, 1, PAGE_SIZE, flags),
+            Err(Error::new(EINVAL))
+        );
+        // Sizes that overflow when rounded up to whole pages.
+        assert_eq!(
+            pages.mmap_prep(&mut file, 1, 0, usize::MAX, flags),
+            Err(Error::new(EINVAL))
+        );
+        assert_eq!(
+            pages.msync(&mut file, 1, page, usize::MAX - 1),
+            Err(Error::new(EINVAL))
+        );
+
+        unsafe { *byte(inner) = 42 };
+        pages.msync(&mut file, 1, 2 * page, PAGE_SIZE).unwrap();
+        assert_eq!(file.data[2 * PAGE_SIZE], 42);
+        unsafe { *byte(addr) = 43 };
+        pages
+            .munmap(&mut file, 1, page, 3 * PAGE_SIZE, MunmapFlags::NEEDS_SYNC)
+            .unwrap();
+        assert_eq!(file.data[PAGE_SIZE], 43);
+        assert_eq!(pages.mapped_pages(1), 1);
+        assert_eq!(
+            pages.munmap(&mut file, 1, page, PAGE_SIZE, MunmapFlags::empty()),
+            Err(Error::new(EINVAL))
+        );
+        pages
+            .munmap(&mut file, 1, 2 * page, PAGE_SIZE, MunmapFlags::empty())
+            .unwrap();
+        assert_eq!(pages.mapped_pages(1), 0);
+    }
+
+    #[test]
+    fn lazy() {
+        let mut file = file();
+        let mut pages = MmapPages::new();
+        let page = PAGE_SIZE as u64;
+        let flags = MapFlags::PROT_READ | MapFlags::MAP_PRIVATE | MapFlags::MAP_LAZY;
+
+        let addr = pages
+            .mmap_prep(&mut file, 1, 0, 4 * PAGE_SIZE, flags)
+            .unwrap();
+        assert!(file.reads.is_empty());
+        let third = pages
+            .request_mmap(&mut file, 1, 2 * page, PAGE_SIZE)
+            .unwrap();
+        assert_eq!(third, addr + 2 * PAGE_SIZE);
+        assert_eq!(unsafe { *byte(third) }, 2);
+        // Only the pages not read yet are.
+        pages.request_mmap(&mut file, 1, 0, 4 * PAGE_SIZE).unwrap();
+        assert_eq!(file.reads, [2 * page, 0, 3 * page]);
+        assert_eq!(
+            pages.request_mmap(&mut file, 1, 4 * page, PAGE_SIZE),
+            Err(Error::new(EINVAL))
+        );
+        pages
+            .munmap(&mut file, 1, 0, 4 * PAGE_SIZE, MunmapFlags::empty())
+            .unwrap();
+        assert_eq!(pages.mapped_pages(1), 0);
+    }
+}
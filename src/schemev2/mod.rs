@@ -6,14 +6,66 @@ use core::{
 
 use bitflags::bitflags;
 
+#[cfg(any(feature = "tmpfs", test))]
+pub use self::tmpfs::*;
+pub use self::{async_scheme::*, ids::*, request::*, response::*, ring::*, sync_scheme::*};
+#[cfg(any(feature = "alloc", test))]
+pub use self::{
+    capture::*, conformance::*, handles::*, layers::*, locks::*, loopback::*, mmap::*, queue::*,
+};
+
+mod access;
+mod args;
+mod async_scheme;
+#[cfg(any(feature = "alloc", test))]
+mod capture;
+#[cfg(any(feature = "alloc", test))]
+mod conformance;
+#[cfg(any(feature = "alloc", test))]
+mod handles;
+mod ids;
+#[cfg(any(feature = "alloc", test))]
+mod layers;
+#[cfg(any(feature = "alloc", test))]
+mod locks;
+#[cfg(any(feature = "alloc", test))]
+mod loopback;
+#[cfg(any(feature = "alloc", test))]
+mod mmap;
+#[cfg(any(feature = "alloc", test))]
+mod queue;
+mod request;
+mod response;
+mod ring;
+mod sync_scheme;
+#[cfg(any(feature = "tmpfs", test))]
+mod tmpfs;
+
+/// The process a request came from.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub struct CallerCtx {
     pub pid: usize,
     pub uid: u32,
     pub gid: u32,
 }
 
+impl CallerCtx {
+    /// The caller of `sqe`. The kernel passes the pid in `caller`, and the uid and gid in the low
+    /// and high halves of the last argument.
+    pub fn from_sqe(sqe: &Sqe) -> Self {
+        Self {
+            pid: sqe.caller as usize,
+            uid: sqe.args[5] as u32,
+            gid: (sqe.args[5] >> 32) as u32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum OpenResult {
-    ThisScheme { number: usize },
+    /// A new file description in this scheme, with the scheme's own id `number`.
+    ThisScheme { number: usize, flags: NewFdFlags },
+    /// An fd in the scheme process, to be passed to the caller instead.
     OtherScheme { fd: usize },
 }
 
@@ -22,7 +74,9 @@ pub enum OpenResult {
 pub struct Sqe {
     pub opcode: u8,
     pub sqe_flags: SqeFlags,
-    pub _rsvd: u16, // TODO: priority
+    /// How urgent the request is, where larger is more urgent and 0 is normal. Schemes may
+    /// ignore it, or serve more urgent requests first with a `RequestQueue`.
+    pub priority: u16,
     pub tag: u32,
     pub args: [u64; 6],
     pub caller: u64,
@@ -43,18 +97,23 @@ impl DerefMut for Sqe {
 bitflags! {
     #[derive(Clone, Copy, Debug, Default)]
     pub struct SqeFlags: u8 {
-        // If zero, the message is bidirectional, and the scheme is expected to pass the Ksmsg's
-        // tag field to the Skmsg. Some opcodes require this flag to be set.
+        /// If zero, the message is bidirectional, and the scheme is expected to pass the Ksmsg's
+        /// tag field to the Skmsg. Some opcodes require this flag to be set.
         const ONEWAY = 1;
 
-        // If this flag is set, index 0 of Sqe's args stores the IDs buffer address,
-        // and index 1 stores the IDs buffer length.
+        /// If this flag is set, index 0 of Sqe's args stores the IDs buffer address,
+        /// and index 1 stores the IDs buffer length in bytes. See `Sqe::ids`.
+        ///
+        /// Only `Opcode::Call` takes this flag, as `Request::CallWithIds`. Its other arguments
+        /// move up by one, leaving no room for the metadata length: index 2 stores the payload
+        /// address, index 3 the payload length in the low 56 bits and the metadata length in
+        /// `u64`s in the top 8 bits, and index 4 the metadata address.
         const MULTIPLE_IDS = 1 << 1;
     }
 }
 
 #[repr(C)]
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub struct Cqe {
     pub flags: u8, // bits 2:0 are CqeOpcode
     pub extra_raw: [u8; 3],
@@ -78,6 +137,10 @@ bitflags! {
     #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
     pub struct NewFdFlags: u8 {
         const POSITIONED = 1;
+        /// Ask for [`Opcode::Detach`] each time an fd referring to the new file description is
+        /// closed. Not sent as a flag: [`Cqe::respond_open`] answers with
+        /// [`CqeOpcode::RespondAndNotifyOnDetach`] instead.
+        const NOTIFY_ON_DETACH = 1 << 7;
     }
 }
 
@@ -98,10 +161,12 @@ pub enum CqeOpcode {
     /// [`SchemeAsync::on_close`] and [`SchemeSync::on_close`] are only called when the last file
     /// descriptor referring to the file description is closed. To implement traditional POSIX
     /// advisory file locking, [`CqeOpcode::RespondAndNotifyOnDetach`] is used to notify the scheme
-    /// by sending a [`RequestKind::OnDetach`] request the next time the file description is
-    /// "detached" from a file descriptor. Not done by default to avoid unnecessary IPC.
+    /// by sending an [`Opcode::Detach`] request, handled by [`SchemeSync::on_detach`], the next
+    /// time the file description is "detached" from a file descriptor. Not done by default to
+    /// avoid unnecessary IPC.
     RespondAndNotifyOnDetach,
-    // TODO: ProvideMmap
+    /// Answers [`Opcode::RequestMmap`] with the address of the scheme's pages to map.
+    ProvideMmap,
 }
 
 impl CqeOpcode {
@@ -114,6 +179,7 @@ impl CqeOpcode {
             3 => Self::ObtainFd,
             4 => Self::RespondWithMultipleFds,
             5 => Self::RespondAndNotifyOnDetach,
+            6 => Self::ProvideMmap,
             _ => return None,
         })
     }
@@ -122,7 +188,7 @@ impl CqeOpcode {
 /// SqeOpcode
 #[repr(u8)]
 #[non_exhaustive]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub enum Opcode {
     Close = 3,   // fd
     Dup = 4,     // old fd, buf_ptr, buf_len
@@ -146,7 +212,7 @@ pub enum Opcode {
     RequestMmap = 21,
     Mremap = 22,
     Munmap = 23,
-    Msync = 24, // TODO
+    Msync = 24,
 
     Cancel = 25, // @tag
 
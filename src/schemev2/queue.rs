@@ -0,0 +1,155 @@
+use alloc::collections::BinaryHeap;
+use core::cmp::Ordering;
+
+use super::Sqe;
+
+struct Queued {
+    rank: u128,
+    seq: u64,
+    sqe: Sqe,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Queued {}
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Earlier arrivals win ties.
+        (self.rank, other.seq).cmp(&(other.rank, self.seq))
+    }
+}
+
+/// Pending [`Sqe`]s, served by [`Sqe::priority`] and then in arrival order.
+///
+/// So that a steady stream of urgent requests can't starve the rest, requests age: a request
+/// that `aging` newer requests arrived after is served as if it had one more level of priority.
+/// The order is fixed when a request is pushed, so all operations are `O(log n)` except
+/// [`RequestQueue::remove`].
+pub struct RequestQueue {
+    heap: BinaryHeap<Queued>,
+    aging: u64,
+    next: u64,
+}
+
+impl RequestQueue {
+    /// A queue that serves by priority, without aging.
+    pub fn new() -> Self {
+        Self::with_aging(u64::MAX)
+    }
+    /// A queue where requests gain a level of priority for every `aging` newer requests. With
+    /// an `aging` of 0, requests are served in arrival order.
+    pub fn with_aging(aging: u64) -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            aging,
+            next: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    pub fn push(&mut self, sqe: Sqe) {
+        let seq = self.next;
+        self.next += 1;
+        // Comparing priority + age / aging between requests is the same as comparing
+        // priority * aging - arrival, which doesn't change as requests wait.
+        let rank = u128::from(sqe.priority) * u128::from(self.aging) + u128::from(u64::MAX - seq);
+        self.heap.push(Queued { rank, seq, sqe });
+    }
+    /// The next request to serve.
+    pub fn peek(&self) -> Option<&Sqe> {
+        self.heap.peek().map(|queued| &queued.sqe)
+    }
+    /// Remove and return the next request to serve.
+    pub fn pop(&mut self) -> Option<Sqe> {
+        self.heap.pop().map(|queued| queued.sqe)
+    }
+    /// Remove the pending request with `tag`, such as when it is cancelled.
+    pub fn remove(&mut self, tag: u32) -> Option<Sqe> {
+        let mut removed = None;
+        self.heap
+            .retain(|queued| match queued.sqe.tag == tag && removed.is_none() {
+                true => {
+                    removed = Some(queued.sqe);
+                    false
+                }
+                false => true,
+            });
+        removed
+    }
+}
+
+impl Default for RequestQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::RequestQueue;
+    use crate::schemev2::Sqe;
+
+    fn sqe(tag: u32, priority: u16) -> Sqe {
+        Sqe {
+            tag,
+            priority,
+            ..Sqe::default()
+        }
+    }
+    fn drain(queue: &mut RequestQueue) -> Vec<u32> {
+        core::iter::from_fn(|| queue.pop())
+            .map(|sqe| sqe.tag)
+            .collect()
+    }
+
+    #[test]
+    fn priority_then_arrival() {
+        let mut queue = RequestQueue::new();
+        for (tag, priority) in [(0, 0), (1, 2), (2, 0), (3, 1), (4, 2)] {
+            queue.push(sqe(tag, priority));
+        }
+        assert_eq!(queue.peek().map(|sqe| sqe.tag), Some(1));
+        assert_eq!(queue.remove(3).map(|sqe| sqe.tag), Some(3));
+        assert!(queue.remove(3).is_none());
+        assert_eq!(drain(&mut queue), [1, 4, 0, 2]);
+        assert!(queue.is_empty());
+
+        let mut fifo = RequestQueue::with_aging(0);
+        for (tag, priority) in [(0, 0), (1, 2), (2, 1)] {
+            fifo.push(sqe(tag, priority));
+        }
+        assert_eq!(drain(&mut fifo), [0, 1, 2]);
+    }
+
+    #[test]
+    fn aging() {
+        // A bulk request, followed by a steady stream of interactive ones.
+        let mut queue = RequestQueue::with_aging(4);
+        queue.push(sqe(0, 0));
+        let mut served = Vec::new();
+        for tag in 1..=10 {
+            queue.push(sqe(tag, 1));
+            served.push(queue.pop().unwrap().tag);
+        }
+        // Once 4 newer requests arrived, the bulk one is as urgent as them, and wins by arriving
+        // first.
+        assert_eq!(served, [1, 2, 3, 0, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(drain(&mut queue), [10]);
+    }
+}
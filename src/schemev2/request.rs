@@ -0,0 +1,981 @@
+use core::mem;
+
+use super::{CallerCtx, Opcode, Sqe, SqeFlags};
+use crate::{
+    error::*,
+    flag::{EventFlags, MapFlags, MremapFlags, MsyncFlags, MunmapFlags, RecvFdFlags, SendFdFlags},
+};
+
+/// A decoded [`Sqe`], with one variant per [`Opcode`].
+///
+/// Buffers are passed as an address and a length in bytes, in the address space of the process
+/// reading the `Sqe`. The last argument is reserved for the caller's uid and gid, see
+/// [`CallerCtx::from_sqe`], so no request uses more than five.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Request {
+    Close {
+        id: usize,
+    },
+    Dup {
+        old_id: usize,
+        buf: usize,
+        len: usize,
+    },
+    Read {
+        id: usize,
+        buf: usize,
+        len: usize,
+        offset: u64,
+        fcntl_flags: u32,
+    },
+    Write {
+        id: usize,
+        buf: usize,
+        len: usize,
+        offset: u64,
+        fcntl_flags: u32,
+    },
+    Fsize {
+        id: usize,
+    },
+    Fchmod {
+        id: usize,
+        mode: u16,
+    },
+    Fchown {
+        id: usize,
+        uid: u32,
+        gid: u32,
+    },
+    Fcntl {
+        id: usize,
+        cmd: usize,
+        arg: usize,
+    },
+    Fevent {
+        id: usize,
+        flags: EventFlags,
+    },
+    /// `SYS_SENDFD` of `num_fds` file descriptors to `id`, which the scheme takes with
+    /// [`Cqe::obtain_fd`](super::Cqe::obtain_fd).
+    Sendfd {
+        id: usize,
+        flags: SendFdFlags,
+        arg: u64,
+        num_fds: usize,
+    },
+    Fpath {
+        id: usize,
+        buf: usize,
+        len: usize,
+    },
+    Frename {
+        id: usize,
+        path: usize,
+        path_len: usize,
+    },
+    /// `buf` points to a [`Stat`](crate::data::Stat), and `len` must be its size.
+    Fstat {
+        id: usize,
+        buf: usize,
+        len: usize,
+    },
+    /// `buf` points to a [`StatVfs`](crate::data::StatVfs), and `len` must be its size.
+    Fstatvfs {
+        id: usize,
+        buf: usize,
+        len: usize,
+    },
+    Fsync {
+        id: usize,
+    },
+    Ftruncate {
+        id: usize,
+        len: u64,
+    },
+    /// `buf` points to `len` bytes of [`TimeSpec`](crate::data::TimeSpec)s.
+    Futimens {
+        id: usize,
+        buf: usize,
+        len: usize,
+    },
+
+    /// A mapping of `size` bytes at `offset` is being created. Answered with the address of the
+    /// scheme's memory backing it, which `MmapPages` helps with.
+    MmapPrep {
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MapFlags,
+    },
+    /// Pages of a [`MapFlags::MAP_LAZY`] mapping were faulted on, answered with
+    /// [`Cqe::provide_mmap`](super::Cqe::provide_mmap).
+    RequestMmap {
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MapFlags,
+    },
+    /// A mapping at `offset` is being resized from `old_size` to `new_size` bytes.
+    Mremap {
+        id: usize,
+        offset: u64,
+        old_size: usize,
+        new_size: usize,
+        flags: MremapFlags,
+    },
+    Munmap {
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MunmapFlags,
+    },
+    Msync {
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MsyncFlags,
+    },
+
+    /// Cancel the pending request with the same tag.
+    Cancel,
+    Getdents {
+        id: usize,
+        buf: usize,
+        len: usize,
+        header_size: u16,
+        opaque_offset: u64,
+    },
+    CloseMsg {
+        id: usize,
+    },
+    /// `metadata` points to `metadata_len` `u64`s, not bytes.
+    Call {
+        id: usize,
+        payload: usize,
+        payload_len: usize,
+        metadata: usize,
+        metadata_len: usize,
+    },
+    /// [`Request::Call`] on several file descriptions, sent with [`SqeFlags::MULTIPLE_IDS`].
+    /// `ids` points to `ids_len` bytes of ids, which [`Sqe::ids`] reads.
+    ///
+    /// With no argument left for `metadata_len`, it is stored in the top byte of the payload
+    /// length, like `SYS_CALL` stores it next to the flags.
+    CallWithIds {
+        ids: usize,
+        ids_len: usize,
+        payload: usize,
+        payload_len: usize,
+        metadata: usize,
+        metadata_len: usize,
+    },
+    OpenAt {
+        dirfd: usize,
+        path: usize,
+        path_len: usize,
+        flags: usize,
+        fcntl_flags: u32,
+    },
+    Flink {
+        id: usize,
+        path: usize,
+        path_len: usize,
+    },
+    /// A request for up to `num_fds` file descriptors from `id`, answered with
+    /// [`Cqe::respond_with_multiple_fds`](super::Cqe::respond_with_multiple_fds).
+    Recvfd {
+        id: usize,
+        flags: RecvFdFlags,
+        num_fds: usize,
+    },
+    UnlinkAt {
+        dirfd: usize,
+        path: usize,
+        path_len: usize,
+        flags: usize,
+    },
+    /// [`Request::Call`] with a `StdFsCallMeta` as the metadata, like `SYS_CALL` with
+    /// `CallFlags::STD_FS`.
+    StdFsCall {
+        id: usize,
+        payload: usize,
+        payload_len: usize,
+        metadata: usize,
+        metadata_len: usize,
+    },
+    Detach {
+        id: usize,
+    },
+}
+
+const METADATA_LEN_SHIFT: u32 = 56;
+
+/// Reads `Sqe` arguments, remembering which were used so the rest can be checked to be zero.
+struct Args {
+    raw: [u64; 6],
+    used: u8,
+}
+
+impl Args {
+    fn u64(&mut self, i: usize) -> u64 {
+        self.used |= 1 << i;
+        self.raw[i]
+    }
+    fn get<T: TryFrom<u64>>(&mut self, i: usize) -> Result<T> {
+        T::try_from(self.u64(i)).map_err(|_| Error::new(EINVAL))
+    }
+    fn finish(self) -> Result<()> {
+        // args[5] is the caller's credentials
+        let unused = (0..5).filter(|i| self.used & (1 << i) == 0);
+        match unused.map(|i| self.raw[i]).all(|arg| arg == 0) {
+            true => Ok(()),
+            false => Err(Error::new(EINVAL)),
+        }
+    }
+}
+
+impl Request {
+    /// Decode and validate `sqe`.
+    ///
+    /// Fails with `ENOSYS` for unknown opcodes, and with `EINVAL` if unknown flags are set,
+    /// unused arguments are not zero, arguments don't fit their field, or the flags
+    /// don't make sense for the opcode.
+    pub fn decode(sqe: &Sqe) -> Result<Self> {
+        let opcode = Opcode::try_from_raw(sqe.opcode).ok_or(Error::new(ENOSYS))?;
+        if !SqeFlags::all().contains(sqe.sqe_flags) {
+            return Err(Error::new(EINVAL));
+        }
+        let request = Self::decode_args(opcode, sqe)?;
+        if request.is_oneway() && !sqe.sqe_flags.contains(SqeFlags::ONEWAY) {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(request)
+    }
+    fn decode_args(opcode: Opcode, sqe: &Sqe) -> Result<Self> {
+        let multiple_ids = sqe.sqe_flags.contains(SqeFlags::MULTIPLE_IDS);
+        if multiple_ids && !matches!(opcode, Opcode::Call) {
+            return Err(Error::new(EINVAL));
+        }
+        let mut a = Args {
+            raw: sqe.args,
+            used: 0,
+        };
+        let request = match opcode {
+            Opcode::Close => Self::Close { id: a.get(0)? },
+            Opcode::Dup => Self::Dup {
+                old_id: a.get(0)?,
+                buf: a.get(1)?,
+                len: a.get(2)?,
+            },
+            Opcode::Read => Self::Read {
+                id: a.get(0)?,
+                buf: a.get(1)?,
+                len: a.get(2)?,
+                offset: a.u64(3),
+                fcntl_flags: a.get(4)?,
+            },
+            Opcode::Write => Self::Write {
+                id: a.get(0)?,
+                buf: a.get(1)?,
+                len: a.get(2)?,
+                offset: a.u64(3),
+                fcntl_flags: a.get(4)?,
+            },
+            Opcode::Fsize => Self::Fsize { id: a.get(0)? },
+            Opcode::Fchmod => Self::Fchmod {
+                id: a.get(0)?,
+                mode: a.get(1)?,
+            },
+            Opcode::Fchown => Self::Fchown {
+                id: a.get(0)?,
+                uid: a.get(1)?,
+                gid: a.get(2)?,
+            },
+            Opcode::Fcntl => Self::Fcntl {
+                id: a.get(0)?,
+                cmd: a.get(1)?,
+                arg: a.get(2)?,
+            },
+            Opcode::Fevent => Self::Fevent {
+                id: a.get(0)?,
+                flags: EventFlags::from_bits(a.get(1)?).ok_or(Error::new(EINVAL))?,
+            },
+            Opcode::Sendfd => Self::Sendfd {
+                id: a.get(0)?,
+                flags: SendFdFlags::from_bits(a.get(1)?).ok_or(Error::new(EINVAL))?,
+                arg: a.u64(2),
+                num_fds: a.get(3)?,
+            },
+            Opcode::Fpath => Self::Fpath {
+                id: a.get(0)?,
+                buf: a.get(1)?,
+                len: a.get(2)?,
+            },
+            Opcode::Frename => Self::Frename {
+                id: a.get(0)?,
+                path: a.get(1)?,
+                path_len: a.get(2)?,
+            },
+            Opcode::Fstat => Self::Fstat {
+                id: a.get(0)?,
+                buf: a.get(1)?,
+                len: a.get(2)?,
+            },
+            Opcode::Fstatvfs => Self::Fstatvfs {
+                id: a.get(0)?,
+                buf: a.get(1)?,
+                len: a.get(2)?,
+            },
+            Opcode::Fsync => Self::Fsync { id: a.get(0)? },
+            Opcode::Ftruncate => Self::Ftruncate {
+                id: a.get(0)?,
+                len: a.u64(1),
+            },
+            Opcode::Futimens => Self::Futimens {
+                id: a.get(0)?,
+                buf: a.get(1)?,
+                len: a.get(2)?,
+            },
+            Opcode::MmapPrep => Self::MmapPrep {
+                id: a.get(0)?,
+                size: a.get(1)?,
+                flags: MapFlags::from_bits_retain(a.get(2)?),
+                offset: a.u64(3),
+            },
+            Opcode::RequestMmap => Self::RequestMmap {
+                id: a.get(0)?,
+                size: a.get(1)?,
+                flags: MapFlags::from_bits_retain(a.get(2)?),
+                offset: a.u64(3),
+            },
+            Opcode::Mremap => Self::Mremap {
+                id: a.get(0)?,
+                old_size: a.get(1)?,
+                new_size: a.get(2)?,
+                offset: a.u64(3),
+                flags: MremapFlags::from_bits(a.get(4)?).ok_or(Error::new(EINVAL))?,
+            },
+            Opcode::Munmap => Self::Munmap {
+                id: a.get(0)?,
+                size: a.get(1)?,
+                flags: MunmapFlags::from_bits(a.get(2)?).ok_or(Error::new(EINVAL))?,
+                offset: a.u64(3),
+            },
+            Opcode::Msync => Self::Msync {
+                id: a.get(0)?,
+                size: a.get(1)?,
+                flags: MsyncFlags::from_bits(a.get(2)?).ok_or(Error::new(EINVAL))?,
+                offset: a.u64(3),
+            },
+            Opcode::Cancel => Self::Cancel,
+            Opcode::Getdents => Self::Getdents {
+                id: a.get(0)?,
+                buf: a.get(1)?,
+                len: a.get(2)?,
+                header_size: a.get(3)?,
+                opaque_offset: a.u64(4),
+            },
+            Opcode::CloseMsg => Self::CloseMsg { id: a.get(0)? },
+            Opcode::Call if multiple_ids => {
+                let lens = a.u64(3);
+                Self::CallWithIds {
+                    ids: a.get(0)?,
+                    ids_len: a.get(1)?,
+                    payload: a.get(2)?,
+                    payload_len: usize::try_from(lens & ((1 << METADATA_LEN_SHIFT) - 1))
+                        .map_err(|_| Error::new(EINVAL))?,
+                    metadata: a.get(4)?,
+                    metadata_len: (lens >> METADATA_LEN_SHIFT) as usize,
+                }
+            }
+            Opcode::Call => Self::Call {
+                id: a.get(0)?,
+                payload: a.get(1)?,
+                payload_len: a.get(2)?,
+                metadata: a.get(3)?,
+                metadata_len: a.get(4)?,
+            },
+            Opcode::OpenAt => Self::OpenAt {
+                dirfd: a.get(0)?,
+                path: a.get(1)?,
+                path_len: a.get(2)?,
+                flags: a.get(3)?,
+                fcntl_flags: a.get(4)?,
+            },
+            Opcode::Flink => Self::Flink {
+                id: a.get(0)?,
+                path: a.get(1)?,
+                path_len: a.get(2)?,
+            },
+            Opcode::Recvfd => Self::Recvfd {
+                id: a.get(0)?,
+                flags: RecvFdFlags::from_bits(a.get(1)?).ok_or(Error::new(EINVAL))?,
+                num_fds: a.get(2)?,
+            },
+            Opcode::UnlinkAt => Self::UnlinkAt {
+                dirfd: a.get(0)?,
+                path: a.get(1)?,
+                path_len: a.get(2)?,
+                flags: a.get(3)?,
+            },
+            Opcode::StdFsCall => Self::StdFsCall {
+                id: a.get(0)?,
+                payload: a.get(1)?,
+                payload_len: a.get(2)?,
+                metadata: a.get(3)?,
+                metadata_len: a.get(4)?,
+            },
+            Opcode::Detach => Self::Detach { id: a.get(0)? },
+        };
+        a.finish()?;
+        Ok(request)
+    }
+
+    /// Encode the request as an `Sqe` with `tag` and normal priority, sent on behalf of `ctx`.
+    ///
+    /// # Panics
+    ///
+    /// If a [`Request::CallWithIds`] has lengths that do not fit in its packed argument, which
+    /// [`Request::call_with_ids`] checks for.
+    pub fn encode(&self, tag: u32, ctx: &CallerCtx) -> Sqe {
+        let (opcode, args): (Opcode, [u64; 5]) = match *self {
+            Self::Close { id } => (Opcode::Close, [id as u64, 0, 0, 0, 0]),
+            Self::Dup { old_id, buf, len } => {
+                (Opcode::Dup, [old_id as u64, buf as u64, len as u64, 0, 0])
+            }
+            Self::Read {
+                id,
+                buf,
+                len,
+                offset,
+                fcntl_flags,
+            } => (
+                Opcode::Read,
+                [
+                    id as u64,
+                    buf as u64,
+                    len as u64,
+                    offset,
+                    fcntl_flags.into(),
+                ],
+            ),
+            Self::Write {
+                id,
+                buf,
+                len,
+                offset,
+                fcntl_flags,
+            } => (
+                Opcode::Write,
+                [
+                    id as u64,
+                    buf as u64,
+                    len as u64,
+                    offset,
+                    fcntl_flags.into(),
+                ],
+            ),
+            Self::Fsize { id } => (Opcode::Fsize, [id as u64, 0, 0, 0, 0]),
+            Self::Fchmod { id, mode } => (Opcode::Fchmod, [id as u64, mode.into(), 0, 0, 0]),
+            Self::Fchown { id, uid, gid } => {
+                (Opcode::Fchown, [id as u64, uid.into(), gid.into(), 0, 0])
+            }
+            Self::Fcntl { id, cmd, arg } => {
+                (Opcode::Fcntl, [id as u64, cmd as u64, arg as u64, 0, 0])
+            }
+            Self::Fevent { id, flags } => {
+                (Opcode::Fevent, [id as u64, flags.bits() as u64, 0, 0, 0])
+            }
+            Self::Sendfd {
+                id,
+                flags,
+                arg,
+                num_fds,
+            } => (
+                Opcode::Sendfd,
+                [id as u64, flags.bits() as u64, arg, num_fds as u64, 0],
+            ),
+            Self::Fpath { id, buf, len } => {
+                (Opcode::Fpath, [id as u64, buf as u64, len as u64, 0, 0])
+            }
+            Self::Frename { id, path, path_len } => (
+                Opcode::Frename,
+                [id as u64, path as u64, path_len as u64, 0, 0],
+            ),
+            Self::Fstat { id, buf, len } => {
+                (Opcode::Fstat, [id as u64, buf as u64, len as u64, 0, 0])
+            }
+            Self::Fstatvfs { id, buf, len } => {
+                (Opcode::Fstatvfs, [id as u64, buf as u64, len as u64, 0, 0])
+            }
+            Self::Fsync { id } => (Opcode::Fsync, [id as u64, 0, 0, 0, 0]),
+            Self::Ftruncate { id, len } => (Opcode::Ftruncate, [id as u64, len, 0, 0, 0]),
+            Self::Futimens { id, buf, len } => {
+                (Opcode::Futimens, [id as u64, buf as u64, len as u64, 0, 0])
+            }
+            Self::MmapPrep {
+                id,
+                offset,
+                size,
+                flags,
+            } => (
+                Opcode::MmapPrep,
+                [id as u64, size as u64, flags.bits() as u64, offset, 0],
+            ),
+            Self::RequestMmap {
+                id,
+                offset,
+                size,
+                flags,
+            } => (
+                Opcode::RequestMmap,
+                [id as u64, size as u64, flags.bits() as u64, offset, 0],
+            ),
+            Self::Mremap {
+                id,
+                offset,
+                old_size,
+                new_size,
+                flags,
+            } => (
+                Opcode::Mremap,
+                [
+                    id as u64,
+                    old_size as u64,
+                    new_size as u64,
+                    offset,
+                    flags.bits() as u64,
+                ],
+            ),
+            Self::Munmap {
+                id,
+                offset,
+                size,
+                flags,
+            } => (
+                Opcode::Munmap,
+                [id as u64, size as u64, flags.bits() as u64, offset, 0],
+            ),
+            Self::Msync {
+                id,
+                offset,
+                size,
+                flags,
+            } => (
+                Opcode::Msync,
+                [id as u64, size as u64, flags.bits() as u64, offset, 0],
+            ),
+            Self::Cancel => (Opcode::Cancel, [0; 5]),
+            Self::Getdents {
+                id,
+                buf,
+                len,
+                header_size,
+                opaque_offset,
+            } => (
+                Opcode::Getdents,
+                [
+                    id as u64,
+                    buf as u64,
+                    len as u64,
+                    header_size.into(),
+                    opaque_offset,
+                ],
+            ),
+            Self::CloseMsg { id } => (Opcode::CloseMsg, [id as u64, 0, 0, 0, 0]),
+            Self::Call {
+                id,
+                payload,
+                payload_len,
+                metadata,
+                metadata_len,
+            } => (
+                Opcode::Call,
+                [
+                    id as u64,
+                    payload as u64,
+                    payload_len as u64,
+                    metadata as u64,
+                    metadata_len as u64,
+                ],
+            ),
+            Self::OpenAt {
+                dirfd,
+                path,
+                path_len,
+                flags,
+                fcntl_flags,
+            } => (
+                Opcode::OpenAt,
+                [
+                    dirfd as u64,
+                    path as u64,
+                    path_len as u64,
+                    flags as u64,
+                    fcntl_flags.into(),
+                ],
+            ),
+            Self::Flink { id, path, path_len } => (
+                Opcode::Flink,
+                [id as u64, path as u64, path_len as u64, 0, 0],
+            ),
+            Self::Recvfd { id, flags, num_fds } => (
+                Opcode::Recvfd,
+                [id as u64, flags.bits() as u64, num_fds as u64, 0, 0],
+            ),
+            Self::UnlinkAt {
+                dirfd,
+                path,
+                path_len,
+                flags,
+            } => (
+                Opcode::UnlinkAt,
+                [dirfd as u64, path as u64, path_len as u64, flags as u64, 0],
+            ),
+            Self::CallWithIds {
+                ids,
+                ids_len,
+                payload,
+                payload_len,
+                metadata,
+                metadata_len,
+            } => {
+                assert!(
+                    payload_len as u64 >> METADATA_LEN_SHIFT == 0
+                        && metadata_len as u64 >> (64 - METADATA_LEN_SHIFT) == 0,
+                    "CallWithIds lengths too large to encode"
+                );
+                (
+                    Opcode::Call,
+                    [
+                        ids as u64,
+                        ids_len as u64,
+                        payload as u64,
+                        payload_len as u64 | ((metadata_len as u64) << METADATA_LEN_SHIFT),
+                        metadata as u64,
+                    ],
+                )
+            }
+            Self::StdFsCall {
+                id,
+                payload,
+                payload_len,
+                metadata,
+                metadata_len,
+            } => (
+                Opcode::StdFsCall,
+                [
+                    id as u64,
+                    payload as u64,
+                    payload_len as u64,
+                    metadata as u64,
+                    metadata_len as u64,
+                ],
+            ),
+            Self::Detach { id } => (Opcode::Detach, [id as u64, 0, 0, 0, 0]),
+        };
+        let [a, b, c, d, e] = args;
+        let mut sqe_flags = SqeFlags::empty();
+        sqe_flags.set(SqeFlags::ONEWAY, self.is_oneway());
+        sqe_flags.set(
+            SqeFlags::MULTIPLE_IDS,
+            matches!(self, Self::CallWithIds { .. }),
+        );
+        Sqe {
+            opcode: opcode as u8,
+            sqe_flags,
+            priority: 0,
+            tag,
+            args: [
+                a,
+                b,
+                c,
+                d,
+                e,
+                u64::from(ctx.uid) | (u64::from(ctx.gid) << 32),
+            ],
+            caller: ctx.pid as u64,
+        }
+    }
+
+    /// A [`Request::CallWithIds`] on `ids`, the client side of `SYS_CALL` with
+    /// [`CallFlags::MULTIPLE_FDS`](crate::flag::CallFlags::MULTIPLE_FDS).
+    ///
+    /// Fails with `E2BIG` if `payload` or `metadata` are too long to encode.
+    pub fn call_with_ids(ids: &[usize], payload: &mut [u8], metadata: &[u64]) -> Result<Self> {
+        if payload.len() as u64 >> METADATA_LEN_SHIFT != 0
+            || metadata.len() as u64 >> (64 - METADATA_LEN_SHIFT) != 0
+        {
+            return Err(Error::new(E2BIG));
+        }
+        Ok(Self::CallWithIds {
+            ids: ids.as_ptr() as usize,
+            ids_len: mem::size_of_val(ids),
+            payload: payload.as_mut_ptr() as usize,
+            payload_len: payload.len(),
+            metadata: metadata.as_ptr() as usize,
+            metadata_len: metadata.len(),
+        })
+    }
+
+    /// Whether the request must be sent with [`SqeFlags::ONEWAY`], because it is never
+    /// answered.
+    pub fn is_oneway(&self) -> bool {
+        matches!(self, Self::CloseMsg { .. } | Self::Cancel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Request;
+    use crate::{
+        error::*,
+        flag::{
+            EventFlags, MapFlags, MremapFlags, MsyncFlags, MunmapFlags, RecvFdFlags, SendFdFlags,
+        },
+        schemev2::{CallerCtx, Opcode, Sqe, SqeFlags},
+    };
+
+    #[test]
+    fn round_trip() {
+        let ctx = CallerCtx {
+            pid: 12,
+            uid: 1000,
+            gid: 100,
+        };
+        let requests = [
+            Request::Close { id: 1 },
+            Request::Dup {
+                old_id: 1,
+                buf: 0x1000,
+                len: 4,
+            },
+            Request::Read {
+                id: 1,
+                buf: 0x1000,
+                len: 16,
+                offset: u64::MAX,
+                fcntl_flags: 2,
+            },
+            Request::Write {
+                id: 1,
+                buf: 0x1000,
+                len: 16,
+                offset: 3,
+                fcntl_flags: 0,
+            },
+            Request::Fsize { id: 2 },
+            Request::Fchmod { id: 2, mode: 0o755 },
+            Request::Fchown {
+                id: 2,
+                uid: 1000,
+                gid: u32::MAX,
+            },
+            Request::Fcntl {
+                id: 2,
+                cmd: 3,
+                arg: 4,
+            },
+            Request::Fevent {
+                id: 4,
+                flags: EventFlags::EVENT_READ,
+            },
+            Request::Sendfd {
+                id: 7,
+                flags: SendFdFlags::EXCLUSIVE,
+                arg: u64::MAX,
+                num_fds: 2,
+            },
+            Request::Fpath {
+                id: 3,
+                buf: 0x1000,
+                len: 64,
+            },
+            Request::Frename {
+                id: 3,
+                path: 0x2000,
+                path_len: 5,
+            },
+            Request::Fstat {
+                id: 3,
+                buf: 0x1000,
+                len: 128,
+            },
+            Request::Fstatvfs {
+                id: 3,
+                buf: 0x1000,
+                len: 32,
+            },
+            Request::Fsync { id: 3 },
+            Request::Ftruncate {
+                id: 3,
+                len: 1 << 40,
+            },
+            Request::Futimens {
+                id: 3,
+                buf: 0x1000,
+                len: 32,
+            },
+            Request::MmapPrep {
+                id: 5,
+                offset: 4096,
+                size: 8192,
+                flags: MapFlags::PROT_READ,
+            },
+            Request::RequestMmap {
+                id: 5,
+                offset: 4096,
+                size: 4096,
+                flags: MapFlags::PROT_READ | MapFlags::MAP_LAZY,
+            },
+            Request::Mremap {
+                id: 5,
+                offset: 0,
+                old_size: 4096,
+                new_size: 8192,
+                flags: MremapFlags::KEEP_OLD,
+            },
+            Request::Munmap {
+                id: 5,
+                offset: 0,
+                size: 4096,
+                flags: MunmapFlags::NEEDS_SYNC,
+            },
+            Request::Msync {
+                id: 5,
+                offset: 0,
+                size: 4096,
+                flags: MsyncFlags::MS_SYNC,
+            },
+            Request::Cancel,
+            Request::Getdents {
+                id: 6,
+                buf: 0x3000,
+                len: 256,
+                header_size: 19,
+                opaque_offset: 7,
+            },
+            Request::CloseMsg { id: 8 },
+            Request::Call {
+                id: 9,
+                payload: 0x4000,
+                payload_len: 32,
+                metadata: 0x5000,
+                metadata_len: 2,
+            },
+            Request::CallWithIds {
+                ids: 0x6000,
+                ids_len: 16,
+                payload: 0x4000,
+                payload_len: 32,
+                metadata: 0x5000,
+                metadata_len: 255,
+            },
+            Request::OpenAt {
+                dirfd: 3,
+                path: 0x2000,
+                path_len: 5,
+                flags: 0x1_0000,
+                fcntl_flags: 0,
+            },
+            Request::Flink {
+                id: 3,
+                path: 0x2000,
+                path_len: 5,
+            },
+            Request::Recvfd {
+                id: 7,
+                flags: RecvFdFlags::CLOEXEC,
+                num_fds: 3,
+            },
+            Request::UnlinkAt {
+                dirfd: 3,
+                path: 0x2000,
+                path_len: 5,
+                flags: 1,
+            },
+            Request::StdFsCall {
+                id: 9,
+                payload: 0x4000,
+                payload_len: 32,
+                metadata: 0x5000,
+                metadata_len: 3,
+            },
+            Request::Detach { id: 10 },
+        ];
+        for request in requests {
+            let sqe = request.encode(42, &ctx);
+            assert_eq!(sqe.tag, 42);
+            assert_eq!(CallerCtx::from_sqe(&sqe), ctx);
+            assert_eq!(Request::decode(&sqe), Ok(request));
+        }
+        // Every opcode is covered.
+        for raw in (0..=u8::MAX).filter(|&raw| Opcode::try_from_raw(raw).is_some()) {
+            assert!(requests
+                .iter()
+                .any(|request| request.encode(0, &ctx).opcode == raw));
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let ctx = CallerCtx::default();
+        let valid = Request::Fchmod { id: 1, mode: 0o644 }.encode(0, &ctx);
+        assert!(Request::decode(&valid).is_ok());
+
+        let mut sqe = valid;
+        sqe.opcode = 0;
+        assert_eq!(Request::decode(&sqe), Err(Error::new(ENOSYS)));
+
+        let mut sqe = valid;
+        sqe.priority = u16::MAX;
+        assert!(Request::decode(&sqe).is_ok());
+
+        let mut sqe = valid;
+        sqe.sqe_flags = SqeFlags::from_bits_retain(0x80);
+        assert_eq!(Request::decode(&sqe), Err(Error::new(EINVAL)));
+
+        // Mode doesn't fit in u16, and an unused argument is set.
+        let mut sqe = valid;
+        sqe.args[1] = 0x1_0000;
+        assert_eq!(Request::decode(&sqe), Err(Error::new(EINVAL)));
+        let mut sqe = valid;
+        sqe.args[4] = 1;
+        assert_eq!(Request::decode(&sqe), Err(Error::new(EINVAL)));
+
+        let mut sqe = valid;
+        sqe.sqe_flags = SqeFlags::MULTIPLE_IDS;
+        assert_eq!(Request::decode(&sqe), Err(Error::new(EINVAL)));
+
+        let mut sqe = Request::CloseMsg { id: 1 }.encode(0, &ctx);
+        sqe.sqe_flags = SqeFlags::empty();
+        assert_eq!(Request::decode(&sqe), Err(Error::new(EINVAL)));
+
+        let sqe = Sqe {
+            opcode: Opcode::Fevent as u8,
+            args: [1, 1 << 40, 0, 0, 0, 0],
+            ..Sqe::default()
+        };
+        assert_eq!(Request::decode(&sqe), Err(Error::new(EINVAL)));
+    }
+
+    #[test]
+    #[should_panic = "too large to encode"]
+    fn call_with_ids_too_long() {
+        let metadata = [0; 256];
+        assert_eq!(
+            Request::call_with_ids(&[1], &mut [], &metadata),
+            Err(Error::new(E2BIG))
+        );
+        Request::CallWithIds {
+            ids: 0x6000,
+            ids_len: 8,
+            payload: 0x4000,
+            payload_len: 0,
+            metadata: 0x5000,
+            metadata_len: 256,
+        }
+        .encode(0, &CallerCtx::default());
+    }
+}
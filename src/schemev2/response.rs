@@ -0,0 +1,338 @@
+use super::{Cqe, CqeOpcode, NewFdFlags, OpenResult};
+use crate::{
+    error::*,
+    flag::{EventFlags, FobtainFdFlags},
+};
+
+/// A decoded [`Cqe`], with one variant per [`CqeOpcode`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Response {
+    /// The result of the request. When it opened a file description in the scheme, `result` is
+    /// the scheme's number for it.
+    Regular {
+        tag: u32,
+        result: Result<usize>,
+        flags: NewFdFlags,
+    },
+    /// The scheme passes its own fd `fd` to the caller.
+    WithFd { tag: u32, fd: usize },
+    /// The scheme passes `count` of its own fds, stored as an array of `usize` at `fds`.
+    WithMultipleFds { tag: u32, fds: usize, count: usize },
+    /// Not an answer to any request: the events in `flags` happened on `id`.
+    Fevent { id: usize, flags: EventFlags },
+    /// The scheme takes fds from the caller, placed according to `flags` and `dst`.
+    ObtainFd {
+        tag: u32,
+        dst: usize,
+        flags: FobtainFdFlags,
+    },
+    /// Like [`Response::Regular`], but the scheme also wants [`Opcode::Detach`] for the file
+    /// description.
+    ///
+    /// [`Opcode::Detach`]: super::Opcode::Detach
+    NotifyOnDetach {
+        tag: u32,
+        result: Result<usize>,
+        flags: NewFdFlags,
+    },
+    /// The scheme's pages at `addr` back the pages asked for by [`Opcode::RequestMmap`].
+    ///
+    /// [`Opcode::RequestMmap`]: super::Opcode::RequestMmap
+    ProvideMmap { tag: u32, addr: usize },
+}
+
+impl Response {
+    /// The tag of the request being answered, if any.
+    pub fn tag(&self) -> Option<u32> {
+        match *self {
+            Self::Regular { tag, .. }
+            | Self::WithFd { tag, .. }
+            | Self::WithMultipleFds { tag, .. }
+            | Self::ObtainFd { tag, .. }
+            | Self::NotifyOnDetach { tag, .. }
+            | Self::ProvideMmap { tag, .. } => Some(tag),
+            Self::Fevent { .. } => None,
+        }
+    }
+}
+
+impl Cqe {
+    /// Largest value that fits in [`Cqe::extra`].
+    pub const EXTRA_MAX: u32 = 0xFF_FFFF;
+
+    fn new(opcode: CqeOpcode, extra: u32, tag: u32, result: u64) -> Self {
+        debug_assert!(extra <= Self::EXTRA_MAX);
+        let [a, b, c, _] = extra.to_ne_bytes();
+        Self {
+            flags: opcode as u8,
+            extra_raw: [a, b, c],
+            tag,
+            result,
+        }
+    }
+
+    /// The [`CqeOpcode`] in bits 2:0 of `flags`.
+    pub fn opcode(&self) -> Option<CqeOpcode> {
+        CqeOpcode::try_from_raw(self.flags & 0b111)
+    }
+
+    /// Answer request `tag` with `result`.
+    pub fn respond(tag: u32, result: Result<usize>) -> Self {
+        Self::new(CqeOpcode::RespondRegular, 0, tag, Error::mux(result) as u64)
+    }
+    /// Answer a request that opens a file description, such as [`Opcode::OpenAt`] or
+    /// [`Opcode::Dup`]. With [`NewFdFlags::NOTIFY_ON_DETACH`], this answers like
+    /// [`Cqe::respond_and_notify_on_detach`].
+    ///
+    /// [`Opcode::OpenAt`]: super::Opcode::OpenAt
+    /// [`Opcode::Dup`]: super::Opcode::Dup
+    pub fn respond_open(tag: u32, result: Result<OpenResult>) -> Self {
+        match result {
+            Ok(OpenResult::ThisScheme { number, flags }) => Self::new(
+                match flags.contains(NewFdFlags::NOTIFY_ON_DETACH) {
+                    true => CqeOpcode::RespondAndNotifyOnDetach,
+                    false => CqeOpcode::RespondRegular,
+                },
+                (flags - NewFdFlags::NOTIFY_ON_DETACH).bits().into(),
+                tag,
+                number as u64,
+            ),
+            Ok(OpenResult::OtherScheme { fd }) => Self::respond_with_fd(tag, fd),
+            Err(err) => Self::respond(tag, Err(err)),
+        }
+    }
+    /// Answer request `tag` by moving the scheme's `fd` to the caller.
+    pub fn respond_with_fd(tag: u32, fd: usize) -> Self {
+        Self::new(CqeOpcode::RespondWithFd, 0, tag, fd as u64)
+    }
+    /// Answer request `tag` by moving the scheme's `fds` to the caller. `fds` must stay valid
+    /// until the kernel has read the response.
+    ///
+    /// Fails with `E2BIG` if there are more than [`Cqe::EXTRA_MAX`] fds.
+    pub fn respond_with_multiple_fds(tag: u32, fds: &[usize]) -> Result<Self> {
+        let count = u32::try_from(fds.len())
+            .ok()
+            .filter(|&count| count <= Self::EXTRA_MAX)
+            .ok_or(Error::new(E2BIG))?;
+        Ok(Self::new(
+            CqeOpcode::RespondWithMultipleFds,
+            count,
+            tag,
+            fds.as_ptr() as u64,
+        ))
+    }
+    /// Notify the kernel that the events in `flags` happened on `id`. Not an answer to any
+    /// request, so the flags take the place of the tag.
+    pub fn send_fevent(id: usize, flags: EventFlags) -> Self {
+        Self::new(CqeOpcode::SendFevent, 0, flags.bits() as u32, id as u64)
+    }
+    /// Answer request `tag` by taking fds from the caller.
+    pub fn obtain_fd(tag: u32, flags: FobtainFdFlags, dst: usize) -> Self {
+        Self::new(CqeOpcode::ObtainFd, flags.bits() as u32, tag, dst as u64)
+    }
+    /// Answer request `tag` with `result`, and ask for [`Opcode::Detach`] the next time an fd
+    /// referring to the file description is closed.
+    ///
+    /// [`Opcode::Detach`]: super::Opcode::Detach
+    pub fn respond_and_notify_on_detach(tag: u32, result: Result<usize>) -> Self {
+        Self::new(
+            CqeOpcode::RespondAndNotifyOnDetach,
+            0,
+            tag,
+            Error::mux(result) as u64,
+        )
+    }
+    /// Answer [`Opcode::RequestMmap`] `tag` with the address of the scheme's pages to map.
+    /// Failures are answered with [`Cqe::respond`].
+    ///
+    /// [`Opcode::RequestMmap`]: super::Opcode::RequestMmap
+    pub fn provide_mmap(tag: u32, addr: usize) -> Self {
+        Self::new(CqeOpcode::ProvideMmap, 0, tag, addr as u64)
+    }
+
+    /// Decode and validate the response.
+    ///
+    /// Fails with `EINVAL` for unknown opcodes or flags, and for nonzero fields the opcode does
+    /// not use.
+    pub fn decode(&self) -> Result<Response> {
+        let einval = || Error::new(EINVAL);
+        let opcode = self.opcode().filter(|_| self.flags >> 3 == 0);
+        let opcode = opcode.ok_or_else(einval)?;
+        let extra = self.extra();
+        let tag = self.tag;
+        let value = usize::try_from(self.result).map_err(|_| einval())?;
+        let no_extra = |response| match extra {
+            0 => Ok(response),
+            _ => Err(einval()),
+        };
+        let new_fd_flags = || {
+            u8::try_from(extra)
+                .ok()
+                .and_then(NewFdFlags::from_bits)
+                .filter(|flags| !flags.contains(NewFdFlags::NOTIFY_ON_DETACH))
+                .ok_or_else(einval)
+        };
+
+        match opcode {
+            CqeOpcode::RespondRegular => Ok(Response::Regular {
+                tag,
+                result: Error::demux(value),
+                flags: new_fd_flags()?,
+            }),
+            CqeOpcode::RespondWithFd => no_extra(Response::WithFd { tag, fd: value }),
+            CqeOpcode::RespondWithMultipleFds => Ok(Response::WithMultipleFds {
+                tag,
+                fds: value,
+                count: extra as usize,
+            }),
+            CqeOpcode::SendFevent => no_extra(Response::Fevent {
+                id: value,
+                flags: EventFlags::from_bits(tag as usize).ok_or_else(einval)?,
+            }),
+            CqeOpcode::ObtainFd => Ok(Response::ObtainFd {
+                tag,
+                dst: value,
+                flags: FobtainFdFlags::from_bits(extra as usize).ok_or_else(einval)?,
+            }),
+            CqeOpcode::RespondAndNotifyOnDetach => Ok(Response::NotifyOnDetach {
+                tag,
+                result: Error::demux(value),
+                flags: new_fd_flags()?,
+            }),
+            CqeOpcode::ProvideMmap => no_extra(Response::ProvideMmap { tag, addr: value }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Response;
+    use crate::{
+        error::*,
+        flag::{EventFlags, FobtainFdFlags},
+        schemev2::{Cqe, CqeOpcode, NewFdFlags, OpenResult},
+    };
+
+    #[test]
+    fn round_trip() {
+        let fds = [3, 4, 5];
+        let cases = [
+            (
+                Cqe::respond(1, Ok(13)),
+                Response::Regular {
+                    tag: 1,
+                    result: Ok(13),
+                    flags: NewFdFlags::empty(),
+                },
+            ),
+            (
+                Cqe::respond(2, Err(Error::new(ENOENT))),
+                Response::Regular {
+                    tag: 2,
+                    result: Err(Error::new(ENOENT)),
+                    flags: NewFdFlags::empty(),
+                },
+            ),
+            (
+                Cqe::respond_open(
+                    3,
+                    Ok(OpenResult::ThisScheme {
+                        number: 7,
+                        flags: NewFdFlags::POSITIONED,
+                    }),
+                ),
+                Response::Regular {
+                    tag: 3,
+                    result: Ok(7),
+                    flags: NewFdFlags::POSITIONED,
+                },
+            ),
+            (
+                Cqe::respond_open(4, Ok(OpenResult::OtherScheme { fd: 9 })),
+                Response::WithFd { tag: 4, fd: 9 },
+            ),
+            (
+                Cqe::respond_with_multiple_fds(5, &fds).unwrap(),
+                Response::WithMultipleFds {
+                    tag: 5,
+                    fds: fds.as_ptr() as usize,
+                    count: 3,
+                },
+            ),
+            (
+                Cqe::send_fevent(6, EventFlags::EVENT_READ | EventFlags::EVENT_WRITE),
+                Response::Fevent {
+                    id: 6,
+                    flags: EventFlags::EVENT_READ | EventFlags::EVENT_WRITE,
+                },
+            ),
+            (
+                Cqe::obtain_fd(7, FobtainFdFlags::EXCLUSIVE, 0x1000),
+                Response::ObtainFd {
+                    tag: 7,
+                    dst: 0x1000,
+                    flags: FobtainFdFlags::EXCLUSIVE,
+                },
+            ),
+            (
+                Cqe::respond_and_notify_on_detach(8, Ok(0)),
+                Response::NotifyOnDetach {
+                    tag: 8,
+                    result: Ok(0),
+                    flags: NewFdFlags::empty(),
+                },
+            ),
+            (
+                Cqe::respond_open(
+                    8,
+                    Ok(OpenResult::ThisScheme {
+                        number: 7,
+                        flags: NewFdFlags::POSITIONED | NewFdFlags::NOTIFY_ON_DETACH,
+                    }),
+                ),
+                Response::NotifyOnDetach {
+                    tag: 8,
+                    result: Ok(7),
+                    flags: NewFdFlags::POSITIONED,
+                },
+            ),
+            (
+                Cqe::provide_mmap(9, 0x2000),
+                Response::ProvideMmap {
+                    tag: 9,
+                    addr: 0x2000,
+                },
+            ),
+        ];
+        for (cqe, response) in cases {
+            assert_eq!(cqe.decode(), Ok(response));
+        }
+        assert_eq!(
+            Cqe::send_fevent(6, EventFlags::EVENT_READ)
+                .decode()
+                .unwrap()
+                .tag(),
+            None
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let valid = Cqe::respond_with_fd(1, 3);
+        assert_eq!(valid.opcode(), Some(CqeOpcode::RespondWithFd));
+
+        let mut cqe = valid;
+        cqe.flags = 7;
+        assert_eq!(cqe.decode(), Err(Error::new(EINVAL)));
+        let mut cqe = valid;
+        cqe.flags |= 1 << 3;
+        assert_eq!(cqe.decode(), Err(Error::new(EINVAL)));
+        let mut cqe = valid;
+        cqe.extra_raw = [1, 0, 0];
+        assert_eq!(cqe.decode(), Err(Error::new(EINVAL)));
+
+        let mut cqe = Cqe::respond(1, Ok(0));
+        cqe.extra_raw = [0x80, 0, 0];
+        assert_eq!(cqe.decode(), Err(Error::new(EINVAL)));
+    }
+}
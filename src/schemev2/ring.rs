@@ -0,0 +1,513 @@
+use core::{mem, mem::MaybeUninit};
+
+#[cfg(not(loom))]
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{fence, AtomicU32, Ordering},
+};
+#[cfg(loom)]
+use loom::{
+    cell::UnsafeCell,
+    sync::atomic::{fence, AtomicU32, Ordering},
+};
+
+use crate::error::*;
+#[cfg(all(feature = "userspace", not(loom)))]
+use crate::{data::TimeSpec, sync::Futex};
+
+/// An index into the ring, and whether the other side is blocked waiting for it to change.
+///
+/// Each index is written by one side only, and has a cache line of its own so the producer and
+/// consumer don't slow each other down.
+#[derive(Debug, Default)]
+#[repr(C, align(64))]
+struct Index {
+    value: AtomicU32,
+    waiting: AtomicU32,
+}
+
+/// The start of a ring region, followed by the entries.
+///
+/// All zeroes is an empty ring, so freshly mapped memory can be used as is.
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct RingHeader {
+    /// Next entry to consume, written by the consumer.
+    head: Index,
+    /// Next entry to produce, written by the producer.
+    tail: Index,
+}
+
+#[repr(transparent)]
+struct Slot<T>(UnsafeCell<MaybeUninit<T>>);
+
+impl<T: Copy> Slot<T> {
+    #[cfg(not(loom))]
+    unsafe fn write(&self, value: T) {
+        self.0.get().write(MaybeUninit::new(value))
+    }
+    #[cfg(not(loom))]
+    unsafe fn read(&self) -> T {
+        self.0.get().read().assume_init()
+    }
+    #[cfg(loom)]
+    unsafe fn write(&self, value: T) {
+        self.0.with_mut(|ptr| ptr.write(MaybeUninit::new(value)))
+    }
+    #[cfg(loom)]
+    unsafe fn read(&self) -> T {
+        self.0.with(|ptr| ptr.read().assume_init())
+    }
+}
+
+/// A single-producer single-consumer ring of `T`s, usually [`Sqe`]s or [`Cqe`]s, in memory
+/// shared between two processes or the kernel and a process.
+///
+/// Use [`Ring::split`] to get the side this process is on.
+///
+/// [`Sqe`]: super::Sqe
+/// [`Cqe`]: super::Cqe
+pub struct Ring<'a, T> {
+    header: &'a RingHeader,
+    slots: &'a [Slot<T>],
+}
+
+impl<'a, T: Copy> Ring<'a, T> {
+    /// Size in bytes of the region needed for `capacity` entries.
+    pub const fn region_len(capacity: usize) -> usize {
+        mem::size_of::<RingHeader>() + capacity * mem::size_of::<T>()
+    }
+
+    fn new(header: &'a RingHeader, slots: &'a [Slot<T>]) -> Result<Self> {
+        if !slots.len().is_power_of_two() || slots.len() > 1 << 31 {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(Self { header, slots })
+    }
+
+    /// Use the region at `region` as a ring with room for `capacity` entries, which must be a
+    /// power of two.
+    ///
+    /// Fails with `EINVAL` if `capacity` is invalid or `region` is not aligned to 64 bytes.
+    ///
+    /// # Safety
+    ///
+    /// `region` must be valid for reads and writes of [`Ring::region_len`] bytes for `'a`,
+    /// and either zeroed or used by a ring of the same `T` and capacity. Across all processes,
+    /// only one [`Producer`] and one [`Consumer`] may be used at a time.
+    ///
+    /// Any bit pattern must be a valid `T`, since the other side can write anything.
+    #[cfg(not(loom))]
+    pub unsafe fn from_raw(region: *mut u8, capacity: usize) -> Result<Self> {
+        let align = mem::align_of::<RingHeader>();
+        if !(region as usize).is_multiple_of(align) || mem::align_of::<T>() > align {
+            return Err(Error::new(EINVAL));
+        }
+        let header = &*region.cast::<RingHeader>();
+        let slots = region.add(mem::size_of::<RingHeader>()).cast::<Slot<T>>();
+        Self::new(header, core::slice::from_raw_parts(slots, capacity))
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Both sides of the ring, of which a process normally only uses one.
+    pub fn split(&mut self) -> (Producer<'_, T>, Consumer<'_, T>) {
+        let head = self.header.head.value.load(Ordering::Acquire);
+        let tail = self.header.tail.value.load(Ordering::Acquire);
+        let producer = Producer {
+            header: self.header,
+            slots: self.slots,
+            tail,
+            head,
+        };
+        let consumer = Consumer {
+            header: self.header,
+            slots: self.slots,
+            head,
+            tail,
+        };
+        (producer, consumer)
+    }
+}
+
+/// Announce that this side is about to sleep until `index` is no longer `seen`. Returns `None` if
+/// it already changed, in which case no wakeup will come.
+fn prepare_wait(index: &Index, seen: u32) -> Option<(&AtomicU32, u32)> {
+    index.waiting.store(1, Ordering::Relaxed);
+    // Pairs with the fence in `needs_wakeup`, so either the other side sees `waiting`, or we see
+    // its update.
+    fence(Ordering::SeqCst);
+    if index.value.load(Ordering::Acquire) != seen {
+        index.waiting.store(0, Ordering::Relaxed);
+        return None;
+    }
+    Some((&index.value, seen))
+}
+
+/// Whether the other side was waiting for `index`, which was just updated, and needs a wakeup.
+fn needs_wakeup(index: &Index) -> bool {
+    fence(Ordering::SeqCst);
+    index.waiting.load(Ordering::Relaxed) != 0 && index.waiting.swap(0, Ordering::Relaxed) != 0
+}
+
+/// The side of a [`Ring`] that adds entries.
+pub struct Producer<'a, T> {
+    header: &'a RingHeader,
+    slots: &'a [Slot<T>],
+    tail: u32,
+    /// Last seen head, so the shared one is only read when the ring looks full.
+    head: u32,
+}
+
+impl<T: Copy> Producer<'_, T> {
+    fn free(&mut self, wanted: usize) -> usize {
+        let capacity = self.slots.len();
+        let used = |head: u32, tail: u32| (tail.wrapping_sub(head) as usize).min(capacity);
+        if capacity - used(self.head, self.tail) < wanted {
+            self.head = self.header.head.value.load(Ordering::Acquire);
+        }
+        capacity - used(self.head, self.tail)
+    }
+
+    /// Number of entries that can be pushed without waiting.
+    pub fn space(&mut self) -> usize {
+        self.free(usize::MAX)
+    }
+
+    /// Push as many of `entries` as there is room for, and make them visible to the consumer
+    /// at once. Returns how many were pushed.
+    pub fn push_batch(&mut self, entries: &[T]) -> usize {
+        let count = entries.len().min(self.free(entries.len()));
+        let mask = self.slots.len() - 1;
+        for (i, entry) in entries[..count].iter().enumerate() {
+            let index = self.tail.wrapping_add(i as u32) as usize & mask;
+            // SAFETY: The consumer has released this slot, and won't read it until the tail
+            // is published.
+            unsafe { self.slots[index].write(*entry) };
+        }
+        self.tail = self.tail.wrapping_add(count as u32);
+        self.header.tail.value.store(self.tail, Ordering::Release);
+        count
+    }
+
+    /// Push `entry`, returning false if the ring is full.
+    pub fn push(&mut self, entry: T) -> bool {
+        self.push_batch(&[entry]) == 1
+    }
+
+    /// Whether the consumer is waiting for entries, and should be woken up after a push. Only
+    /// returns true once per wait.
+    pub fn needs_wakeup(&self) -> bool {
+        needs_wakeup(&self.header.tail)
+    }
+
+    /// Announce that the producer is about to wait for space. Returns the futex word and the
+    /// value to wait on, or `None` if there is space already.
+    ///
+    /// For waiting without a [`Futex`](crate::sync::Futex), such as in the kernel. Pair with
+    /// [`Consumer::needs_wakeup`].
+    pub fn prepare_wait(&mut self) -> Option<(&AtomicU32, u32)> {
+        match self.space() {
+            0 => prepare_wait(&self.header.head, self.head),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(all(feature = "userspace", not(loom)))]
+impl<T: Copy> Producer<'_, T> {
+    /// Push as many of `entries` as fit, and wake the consumer if it is waiting.
+    pub fn submit<F: Futex>(&mut self, entries: &[T]) -> usize {
+        let count = self.push_batch(entries);
+        if count > 0 && self.needs_wakeup() {
+            F::wake(&self.header.tail.value, 1);
+        }
+        count
+    }
+
+    /// Block until there is space for at least one entry, or `timeout` expires with
+    /// `ETIMEDOUT`.
+    pub fn wait<F: Futex>(&mut self, timeout: Option<&TimeSpec>) -> Result<()> {
+        while let Some((word, seen)) = self.prepare_wait() {
+            match F::wait(word, seen, timeout) {
+                Ok(())
+                | Err(Error {
+                    errno: EAGAIN | EINTR,
+                }) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The side of a [`Ring`] that removes entries.
+pub struct Consumer<'a, T> {
+    header: &'a RingHeader,
+    slots: &'a [Slot<T>],
+    head: u32,
+    /// Last seen tail, so the shared one is only read when the ring looks empty.
+    tail: u32,
+}
+
+impl<T: Copy> Consumer<'_, T> {
+    fn available(&mut self, wanted: usize) -> usize {
+        let capacity = self.slots.len();
+        // A misbehaving producer can't make us read outside the ring, only garbage entries.
+        let used = |head: u32, tail: u32| (tail.wrapping_sub(head) as usize).min(capacity);
+        if used(self.head, self.tail) < wanted {
+            self.tail = self.header.tail.value.load(Ordering::Acquire);
+        }
+        used(self.head, self.tail)
+    }
+
+    /// Number of entries that can be popped without waiting.
+    pub fn len(&mut self) -> usize {
+        self.available(usize::MAX)
+    }
+    pub fn is_empty(&mut self) -> bool {
+        self.available(1) == 0
+    }
+
+    /// Pop as many entries as fit in `out`, and release their slots to the producer at once.
+    /// Returns how many were popped.
+    pub fn pop_batch(&mut self, out: &mut [T]) -> usize {
+        let count = out.len().min(self.available(out.len()));
+        let mask = self.slots.len() - 1;
+        for (i, entry) in out[..count].iter_mut().enumerate() {
+            let index = self.head.wrapping_add(i as u32) as usize & mask;
+            // SAFETY: The producer published this slot, and won't write it until the head is
+            // released.
+            *entry = unsafe { self.slots[index].read() };
+        }
+        self.head = self.head.wrapping_add(count as u32);
+        self.header.head.value.store(self.head, Ordering::Release);
+        count
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.available(1) == 0 {
+            return None;
+        }
+        let index = self.head as usize & (self.slots.len() - 1);
+        // SAFETY: As in `pop_batch`.
+        let entry = unsafe { self.slots[index].read() };
+        self.head = self.head.wrapping_add(1);
+        self.header.head.value.store(self.head, Ordering::Release);
+        Some(entry)
+    }
+
+    /// Whether the producer is waiting for space, and should be woken up after a pop. Only
+    /// returns true once per wait.
+    pub fn needs_wakeup(&self) -> bool {
+        needs_wakeup(&self.header.head)
+    }
+
+    /// Announce that the consumer is about to wait for entries. Returns the futex word and the
+    /// value to wait on, or `None` if there are entries already.
+    ///
+    /// For waiting without a [`Futex`](crate::sync::Futex), such as in the kernel. Pair with
+    /// [`Producer::needs_wakeup`].
+    pub fn prepare_wait(&mut self) -> Option<(&AtomicU32, u32)> {
+        match self.is_empty() {
+            true => prepare_wait(&self.header.tail, self.tail),
+            false => None,
+        }
+    }
+}
+
+#[cfg(all(feature = "userspace", not(loom)))]
+impl<T: Copy> Consumer<'_, T> {
+    /// Pop as many entries as fit in `out`, and wake the producer if it is waiting.
+    pub fn reap<F: Futex>(&mut self, out: &mut [T]) -> usize {
+        let count = self.pop_batch(out);
+        if count > 0 && self.needs_wakeup() {
+            F::wake(&self.header.head.value, 1);
+        }
+        count
+    }
+
+    /// Block until there is at least one entry, or `timeout` expires with `ETIMEDOUT`.
+    pub fn wait<F: Futex>(&mut self, timeout: Option<&TimeSpec>) -> Result<()> {
+        while let Some((word, seen)) = self.prepare_wait() {
+            match F::wait(word, seen, timeout) {
+                Ok(())
+                | Err(Error {
+                    errno: EAGAIN | EINTR,
+                }) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+// SAFETY: Slots are only accessed by the one producer and consumer, synchronized by the indices.
+unsafe impl<T: Copy + Send> Send for Ring<'_, T> {}
+unsafe impl<T: Copy + Send> Send for Producer<'_, T> {}
+unsafe impl<T: Copy + Send> Send for Consumer<'_, T> {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{
+        alloc::{alloc_zeroed, dealloc, Layout},
+        sync::atomic::AtomicU32,
+        thread,
+        vec::Vec,
+    };
+
+    use super::Ring;
+    use crate::{data::TimeSpec, error::Result, schemev2::Sqe, sync::Futex};
+
+    /// Waits by yielding, which is allowed since futex waits may wake up spuriously.
+    struct YieldFutex;
+
+    impl Futex for YieldFutex {
+        fn wait(word: &AtomicU32, expected: u32, _timeout: Option<&TimeSpec>) -> Result<()> {
+            if word.load(core::sync::atomic::Ordering::Relaxed) == expected {
+                thread::yield_now();
+            }
+            Ok(())
+        }
+        fn wake(_word: &AtomicU32, _count: u32) {}
+    }
+
+    #[test]
+    fn two_threads() {
+        const CAPACITY: usize = 8;
+        const COUNT: u32 = 10_000;
+        let layout = Layout::from_size_align(Ring::<Sqe>::region_len(CAPACITY), 64).unwrap();
+        let region = unsafe { alloc_zeroed(layout) } as usize;
+
+        let producer = thread::spawn(move || {
+            let mut ring = unsafe { Ring::<Sqe>::from_raw(region as *mut u8, CAPACITY) }.unwrap();
+            let (mut producer, _) = ring.split();
+            let mut tag = 0;
+            while tag < COUNT {
+                producer.wait::<YieldFutex>(None).unwrap();
+                let batch: Vec<Sqe> = (tag..COUNT.min(tag + 3))
+                    .map(|tag| Sqe {
+                        tag,
+                        ..Sqe::default()
+                    })
+                    .collect();
+                tag += producer.submit::<YieldFutex>(&batch) as u32;
+            }
+        });
+
+        let mut ring = unsafe { Ring::<Sqe>::from_raw(region as *mut u8, CAPACITY) }.unwrap();
+        let (_, mut consumer) = ring.split();
+        let mut expected = 0;
+        let mut out = [Sqe::default(); 5];
+        while expected < COUNT {
+            consumer.wait::<YieldFutex>(None).unwrap();
+            let count = consumer.reap::<YieldFutex>(&mut out);
+            for sqe in &out[..count] {
+                assert_eq!(sqe.tag, expected);
+                expected += 1;
+            }
+        }
+        producer.join().unwrap();
+        assert!(consumer.is_empty());
+        unsafe { dealloc(region as *mut u8, layout) };
+    }
+
+    #[test]
+    fn invalid() {
+        let layout = Layout::from_size_align(Ring::<u64>::region_len(4), 64).unwrap();
+        let region = unsafe { alloc_zeroed(layout) };
+        unsafe {
+            assert!(Ring::<u64>::from_raw(region, 3).is_err());
+            assert!(Ring::<u64>::from_raw(region.add(8), 4).is_err());
+            let mut ring = Ring::<u64>::from_raw(region, 4).unwrap();
+            let (mut producer, mut consumer) = ring.split();
+            assert_eq!(producer.push_batch(&[1, 2, 3, 4, 5]), 4);
+            assert!(!producer.push(6));
+            assert_eq!(consumer.pop(), Some(1));
+            assert!(producer.push(6));
+            let mut out = [0; 8];
+            assert_eq!(consumer.pop_batch(&mut out), 4);
+            assert_eq!(out[..4], [2, 3, 4, 6]);
+            assert_eq!(consumer.pop(), None);
+            dealloc(region, layout);
+        }
+    }
+}
+
+#[cfg(all(test, loom))]
+mod tests {
+    use loom::{model, sync::Arc, thread};
+    use std::vec::Vec;
+
+    use super::{Ring, RingHeader, Slot};
+
+    struct Shared {
+        header: RingHeader,
+        slots: Vec<Slot<u32>>,
+    }
+    fn shared(capacity: usize) -> Arc<Shared> {
+        Arc::new(Shared {
+            header: RingHeader::default(),
+            slots: (0..capacity)
+                .map(|_| Slot(loom::cell::UnsafeCell::new(core::mem::MaybeUninit::uninit())))
+                .collect(),
+        })
+    }
+
+    #[test]
+    fn spsc_order() {
+        model(|| {
+            let shared = shared(2);
+            let producer = {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || {
+                    let mut ring = Ring::new(&shared.header, &shared.slots).unwrap();
+                    let (mut producer, _) = ring.split();
+                    let mut next = 0;
+                    while next < 3 {
+                        let batch = [next, next + 1, next + 2];
+                        next += producer.push_batch(&batch[..3 - next as usize]) as u32;
+                        thread::yield_now();
+                    }
+                })
+            };
+            let mut ring = Ring::new(&shared.header, &shared.slots).unwrap();
+            let (_, mut consumer) = ring.split();
+            let mut expected = 0;
+            while expected < 3 {
+                match consumer.pop() {
+                    Some(value) => {
+                        assert_eq!(value, expected);
+                        expected += 1;
+                    }
+                    None => thread::yield_now(),
+                }
+            }
+            producer.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn no_lost_wakeup() {
+        model(|| {
+            let shared = shared(2);
+            let producer = {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || {
+                    let mut ring = Ring::new(&shared.header, &shared.slots).unwrap();
+                    let (mut producer, _) = ring.split();
+                    assert!(producer.push(7));
+                    producer.needs_wakeup()
+                })
+            };
+            let mut ring = Ring::new(&shared.header, &shared.slots).unwrap();
+            let (_, mut consumer) = ring.split();
+            let sleeps = consumer.prepare_wait().is_some();
+            let woken = producer.join().unwrap();
+            // Going to sleep is only fine if the producer will wake us up.
+            assert!(!sleeps || woken);
+        });
+    }
+}
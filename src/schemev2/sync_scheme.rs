@@ -0,0 +1,667 @@
+use super::{args::Op, CallerCtx, Cqe, OpenResult, Request, Sqe, SqeFlags};
+use crate::{
+    data::{Stat, StatVfs, StdFsCallMeta, TimeSpec},
+    dirent::DirentBuf,
+    error::*,
+    flag::{EventFlags, MapFlags, MremapFlags, MsyncFlags, MunmapFlags, RecvFdFlags, SendFdFlags},
+};
+
+/// A scheme that handles one request at a time, with a method per [`Request`] variant.
+///
+/// `id` is always the number the scheme returned in [`OpenResult::ThisScheme`]. Methods that are
+/// not implemented fail with `EBADF`, or `ENOENT` for `openat` and `unlinkat`, which look up a
+/// path rather than take an `id`.
+#[allow(unused_variables)]
+pub trait SchemeSync {
+    /// Open `path` relative to `dirfd`.
+    fn openat(
+        &mut self,
+        dirfd: usize,
+        path: &str,
+        flags: usize,
+        fcntl_flags: u32,
+        ctx: &CallerCtx,
+    ) -> Result<OpenResult> {
+        Err(Error::new(ENOENT))
+    }
+    fn unlinkat(&mut self, dirfd: usize, path: &str, flags: usize, ctx: &CallerCtx) -> Result<()> {
+        Err(Error::new(ENOENT))
+    }
+    fn dup(&mut self, old_id: usize, buf: &[u8], ctx: &CallerCtx) -> Result<OpenResult> {
+        Err(Error::new(EBADF))
+    }
+
+    fn read(
+        &mut self,
+        id: usize,
+        buf: &mut [u8],
+        offset: u64,
+        fcntl_flags: u32,
+        ctx: &CallerCtx,
+    ) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+    fn write(
+        &mut self,
+        id: usize,
+        buf: &[u8],
+        offset: u64,
+        fcntl_flags: u32,
+        ctx: &CallerCtx,
+    ) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+    fn fsize(&mut self, id: usize, ctx: &CallerCtx) -> Result<u64> {
+        Err(Error::new(EBADF))
+    }
+    fn fchmod(&mut self, id: usize, new_mode: u16, ctx: &CallerCtx) -> Result<()> {
+        Err(Error::new(EBADF))
+    }
+    fn fchown(&mut self, id: usize, new_uid: u32, new_gid: u32, ctx: &CallerCtx) -> Result<()> {
+        Err(Error::new(EBADF))
+    }
+    fn fcntl(&mut self, id: usize, cmd: usize, arg: usize, ctx: &CallerCtx) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+    /// Register interest in `flags`, returning the events that are already ready.
+    fn fevent(&mut self, id: usize, flags: EventFlags, ctx: &CallerCtx) -> Result<EventFlags> {
+        Err(Error::new(EBADF))
+    }
+    /// `num_fds` fds are being sent to `id`, which the scheme takes with [`Cqe::obtain_fd`]
+    /// before answering.
+    fn sendfd(
+        &mut self,
+        id: usize,
+        flags: SendFdFlags,
+        arg: u64,
+        num_fds: usize,
+        ctx: &CallerCtx,
+    ) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+    /// Up to `num_fds` fds are being received from `id`.
+    fn recvfd(
+        &mut self,
+        id: usize,
+        flags: RecvFdFlags,
+        num_fds: usize,
+        ctx: &CallerCtx,
+    ) -> Result<OpenResult> {
+        Err(Error::new(EBADF))
+    }
+    fn fpath(&mut self, id: usize, buf: &mut [u8], ctx: &CallerCtx) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+    fn flink(&mut self, id: usize, path: &str, ctx: &CallerCtx) -> Result<()> {
+        Err(Error::new(EBADF))
+    }
+    fn frename(&mut self, id: usize, path: &str, ctx: &CallerCtx) -> Result<()> {
+        Err(Error::new(EBADF))
+    }
+    fn fstat(&mut self, id: usize, stat: &mut Stat, ctx: &CallerCtx) -> Result<()> {
+        Err(Error::new(EBADF))
+    }
+    fn fstatvfs(&mut self, id: usize, stat: &mut StatVfs, ctx: &CallerCtx) -> Result<()> {
+        Err(Error::new(EBADF))
+    }
+    fn fsync(&mut self, id: usize, ctx: &CallerCtx) -> Result<()> {
+        Err(Error::new(EBADF))
+    }
+    fn ftruncate(&mut self, id: usize, len: u64, ctx: &CallerCtx) -> Result<()> {
+        Err(Error::new(EBADF))
+    }
+    fn futimens(&mut self, id: usize, times: &[TimeSpec], ctx: &CallerCtx) -> Result<()> {
+        Err(Error::new(EBADF))
+    }
+    /// Write directory entries starting at `opaque_offset` into `buf`.
+    fn getdents<'buf>(
+        &mut self,
+        id: usize,
+        buf: DirentBuf<&'buf mut [u8]>,
+        opaque_offset: u64,
+        ctx: &CallerCtx,
+    ) -> Result<DirentBuf<&'buf mut [u8]>> {
+        Err(Error::new(EBADF))
+    }
+
+    /// Prepare `size` bytes at `offset` for mapping, returning the address of the memory.
+    fn mmap_prep(
+        &mut self,
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MapFlags,
+        ctx: &CallerCtx,
+    ) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+    fn munmap(
+        &mut self,
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MunmapFlags,
+        ctx: &CallerCtx,
+    ) -> Result<()> {
+        Err(Error::new(EBADF))
+    }
+    /// Pages of a [`MapFlags::MAP_LAZY`] mapping were faulted on, returning the address of the
+    /// memory for `size` bytes at `offset`.
+    fn request_mmap(
+        &mut self,
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MapFlags,
+        ctx: &CallerCtx,
+    ) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+    /// A mapping at `offset` is being resized from `old_size` to `new_size` bytes, returning the
+    /// address of the scheme's memory backing it.
+    fn mremap(
+        &mut self,
+        id: usize,
+        offset: u64,
+        old_size: usize,
+        new_size: usize,
+        flags: MremapFlags,
+        ctx: &CallerCtx,
+    ) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+    fn msync(
+        &mut self,
+        id: usize,
+        offset: u64,
+        size: usize,
+        flags: MsyncFlags,
+        ctx: &CallerCtx,
+    ) -> Result<()> {
+        Err(Error::new(EBADF))
+    }
+
+    fn call(
+        &mut self,
+        id: usize,
+        payload: &mut [u8],
+        metadata: &[u64],
+        ctx: &CallerCtx,
+    ) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+    /// [`Self::call`] on several file descriptions at once, sent with
+    /// [`SqeFlags::MULTIPLE_IDS`].
+    fn call_with_ids(
+        &mut self,
+        ids: &[usize],
+        payload: &mut [u8],
+        metadata: &[u64],
+        ctx: &CallerCtx,
+    ) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+    /// A standard filesystem call, such as a lock, described by `meta`.
+    fn std_fs_call(
+        &mut self,
+        id: usize,
+        payload: &mut [u8],
+        meta: StdFsCallMeta,
+        ctx: &CallerCtx,
+    ) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    /// The last fd referring to `id` was closed.
+    fn on_close(&mut self, id: usize) {}
+    /// An fd referring to `id` was closed, if it was opened with
+    /// [`NewFdFlags::NOTIFY_ON_DETACH`](super::NewFdFlags::NOTIFY_ON_DETACH).
+    fn on_detach(&mut self, id: usize, ctx: &CallerCtx) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// What a request was answered with, before it is encoded as a [`Cqe`].
+pub(super) enum Reply {
+    Regular(usize),
+    Open(OpenResult),
+    ProvideMmap(usize),
+    None,
+}
+
+/// Decode `sqe`, call the matching [`SchemeSync`] method, and build the response.
+///
+/// Returns `None` for requests that must not be answered: [`SqeFlags::ONEWAY`] requests, which
+/// include [`Request::CloseMsg`] and [`Request::Cancel`]. Synchronous schemes have nothing pending
+/// to cancel. Requests that [`Request::decode`] rejects fail with its error, and opcodes without
+/// a method fail with `ENOSYS`.
+///
+/// # Safety
+///
+/// The pointers in `sqe.args` must be valid for the request, as they are when `sqe` was read
+/// from the kernel.
+pub unsafe fn handle_sync<S: SchemeSync + ?Sized>(scheme: &mut S, sqe: &Sqe) -> Option<Cqe> {
+    let reply = Request::decode(sqe)
+        .and_then(|request| dispatch(scheme, request, &CallerCtx::from_sqe(sqe)));
+    reply_cqe(sqe, reply)
+}
+
+/// The response to `sqe`, if it needs one.
+pub(super) fn reply_cqe(sqe: &Sqe, reply: Result<Reply>) -> Option<Cqe> {
+    if sqe.sqe_flags.contains(SqeFlags::ONEWAY) {
+        return None;
+    }
+    Some(match reply {
+        Ok(Reply::None) => return None,
+        Ok(Reply::Regular(value)) => Cqe::respond(sqe.tag, Ok(value)),
+        Ok(Reply::Open(open)) => Cqe::respond_open(sqe.tag, Ok(open)),
+        Ok(Reply::ProvideMmap(addr)) => Cqe::provide_mmap(sqe.tag, addr),
+        Err(err) => Cqe::respond(sqe.tag, Err(err)),
+    })
+}
+
+unsafe fn dispatch<S: SchemeSync + ?Sized>(
+    scheme: &mut S,
+    request: Request,
+    ctx: &CallerCtx,
+) -> Result<Reply> {
+    let regular = |result: Result<()>| result.map(|()| Reply::Regular(0));
+
+    match Op::from_request(request)? {
+        Op::OpenAt {
+            dirfd,
+            path,
+            flags,
+            fcntl_flags,
+        } => scheme
+            .openat(dirfd, path, flags, fcntl_flags, ctx)
+            .map(Reply::Open),
+        Op::UnlinkAt { dirfd, path, flags } => regular(scheme.unlinkat(dirfd, path, flags, ctx)),
+        Op::Dup { old_id, buf } => scheme.dup(old_id, buf, ctx).map(Reply::Open),
+
+        Op::Read {
+            id,
+            buf,
+            offset,
+            fcntl_flags,
+        } => scheme
+            .read(id, buf, offset, fcntl_flags, ctx)
+            .map(Reply::Regular),
+        Op::Write {
+            id,
+            buf,
+            offset,
+            fcntl_flags,
+        } => scheme
+            .write(id, buf, offset, fcntl_flags, ctx)
+            .map(Reply::Regular),
+        Op::Fsize { id } => scheme
+            .fsize(id, ctx)
+            .and_then(|size| usize::try_from(size).map_err(|_| Error::new(EOVERFLOW)))
+            .map(Reply::Regular),
+        Op::Fchmod { id, mode } => regular(scheme.fchmod(id, mode, ctx)),
+        Op::Fchown { id, uid, gid } => regular(scheme.fchown(id, uid, gid, ctx)),
+        Op::Fcntl { id, cmd, arg } => scheme.fcntl(id, cmd, arg, ctx).map(Reply::Regular),
+        Op::Fevent { id, flags } => scheme
+            .fevent(id, flags, ctx)
+            .map(|flags| Reply::Regular(flags.bits())),
+        Op::Sendfd {
+            id,
+            flags,
+            arg,
+            num_fds,
+        } => scheme
+            .sendfd(id, flags, arg, num_fds, ctx)
+            .map(Reply::Regular),
+        Op::Recvfd { id, flags, num_fds } => {
+            scheme.recvfd(id, flags, num_fds, ctx).map(Reply::Open)
+        }
+        Op::Fpath { id, buf } => scheme.fpath(id, buf, ctx).map(Reply::Regular),
+        Op::Flink { id, path } => regular(scheme.flink(id, path, ctx)),
+        Op::Frename { id, path } => regular(scheme.frename(id, path, ctx)),
+        Op::Fstat { id, stat } => regular(scheme.fstat(id, stat, ctx)),
+        Op::Fstatvfs { id, stat } => regular(scheme.fstatvfs(id, stat, ctx)),
+        Op::Fsync { id } => regular(scheme.fsync(id, ctx)),
+        Op::Ftruncate { id, len } => regular(scheme.ftruncate(id, len, ctx)),
+        Op::Futimens { id, times } => regular(scheme.futimens(id, times, ctx)),
+        Op::Getdents {
+            id,
+            buf,
+            opaque_offset,
+        } => scheme
+            .getdents(id, buf, opaque_offset, ctx)
+            .map(|buf| Reply::Regular(buf.finalize())),
+
+        Op::MmapPrep {
+            id,
+            offset,
+            size,
+            flags,
+        } => scheme
+            .mmap_prep(id, offset, size, flags, ctx)
+            .map(Reply::Regular),
+        Op::Munmap {
+            id,
+            offset,
+            size,
+            flags,
+        } => regular(scheme.munmap(id, offset, size, flags, ctx)),
+        Op::RequestMmap {
+            id,
+            offset,
+            size,
+            flags,
+        } => scheme
+            .request_mmap(id, offset, size, flags, ctx)
+            .map(Reply::ProvideMmap),
+        Op::Mremap {
+            id,
+            offset,
+            old_size,
+            new_size,
+            flags,
+        } => scheme
+            .mremap(id, offset, old_size, new_size, flags, ctx)
+            .map(Reply::Regular),
+        Op::Msync {
+            id,
+            offset,
+            size,
+            flags,
+        } => regular(scheme.msync(id, offset, size, flags, ctx)),
+
+        Op::Call {
+            id,
+            payload,
+            metadata,
+        } => scheme.call(id, payload, metadata, ctx).map(Reply::Regular),
+        Op::CallWithIds {
+            ids,
+            payload,
+            metadata,
+        } => scheme
+            .call_with_ids(ids, payload, metadata, ctx)
+            .map(Reply::Regular),
+        Op::StdFsCall { id, payload, meta } => scheme
+            .std_fs_call(id, payload, meta, ctx)
+            .map(Reply::Regular),
+
+        Op::Close { id } => {
+            scheme.on_close(id);
+            Ok(Reply::Regular(0))
+        }
+        Op::CloseMsg { id } => {
+            scheme.on_close(id);
+            Ok(Reply::None)
+        }
+        Op::Detach { id } => regular(scheme.on_detach(id, ctx)),
+        Op::Cancel => Ok(Reply::None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{string::String, vec::Vec};
+
+    use super::{handle_sync, SchemeSync};
+    use crate::{
+        data::Stat,
+        dirent::{DirEntry, DirentBuf, DirentHeader, DirentIter, DirentKind},
+        error::*,
+        flag::{RecvFdFlags, SendFdFlags, MODE_FILE},
+        schemev2::{
+            CallerCtx, Cqe, CqeOpcode, NewFdFlags, Opcode, OpenResult, Request, Sqe, SqeFlags,
+        },
+    };
+
+    #[derive(Default)]
+    struct Hello {
+        opened_by: Vec<CallerCtx>,
+        closed: Vec<usize>,
+        detached: Vec<usize>,
+    }
+
+    impl SchemeSync for Hello {
+        fn openat(
+            &mut self,
+            _dirfd: usize,
+            path: &str,
+            _flags: usize,
+            _fcntl_flags: u32,
+            ctx: &CallerCtx,
+        ) -> Result<OpenResult> {
+            self.opened_by.push(*ctx);
+            match path {
+                "hello" => Ok(OpenResult::ThisScheme {
+                    number: 7,
+                    flags: NewFdFlags::POSITIONED,
+                }),
+                "other" => Ok(OpenResult::OtherScheme { fd: 3 }),
+                "locked" => Ok(OpenResult::ThisScheme {
+                    number: 8,
+                    flags: NewFdFlags::POSITIONED | NewFdFlags::NOTIFY_ON_DETACH,
+                }),
+                _ => Err(Error::new(ENOENT)),
+            }
+        }
+        fn read(
+            &mut self,
+            _id: usize,
+            buf: &mut [u8],
+            offset: u64,
+            _fcntl_flags: u32,
+            _ctx: &CallerCtx,
+        ) -> Result<usize> {
+            let data = b"Hello, world!".get(offset as usize..).unwrap_or(&[]);
+            let count = data.len().min(buf.len());
+            buf[..count].copy_from_slice(&data[..count]);
+            Ok(count)
+        }
+        fn fstat(&mut self, _id: usize, stat: &mut Stat, _ctx: &CallerCtx) -> Result<()> {
+            stat.st_mode = MODE_FILE | 0o444;
+            stat.st_size = 13;
+            Ok(())
+        }
+        fn getdents<'buf>(
+            &mut self,
+            _id: usize,
+            mut buf: DirentBuf<&'buf mut [u8]>,
+            opaque_offset: u64,
+            _ctx: &CallerCtx,
+        ) -> Result<DirentBuf<&'buf mut [u8]>> {
+            if opaque_offset == 0 {
+                buf.entry(DirEntry {
+                    inode: 1,
+                    next_opaque_id: 1,
+                    name: "hello",
+                    kind: DirentKind::Regular,
+                })?;
+            }
+            Ok(buf)
+        }
+        fn call_with_ids(
+            &mut self,
+            ids: &[usize],
+            payload: &mut [u8],
+            metadata: &[u64],
+            _ctx: &CallerCtx,
+        ) -> Result<usize> {
+            payload.fill(ids.len() as u8);
+            Ok(ids.iter().sum::<usize>() + metadata.iter().sum::<u64>() as usize)
+        }
+        fn on_close(&mut self, id: usize) {
+            self.closed.push(id);
+        }
+        fn sendfd(
+            &mut self,
+            _id: usize,
+            _flags: SendFdFlags,
+            _arg: u64,
+            num_fds: usize,
+            _ctx: &CallerCtx,
+        ) -> Result<usize> {
+            Ok(num_fds)
+        }
+        fn recvfd(
+            &mut self,
+            _id: usize,
+            _flags: RecvFdFlags,
+            _num_fds: usize,
+            _ctx: &CallerCtx,
+        ) -> Result<OpenResult> {
+            Ok(OpenResult::OtherScheme { fd: 3 })
+        }
+        fn on_detach(&mut self, id: usize, _ctx: &CallerCtx) -> Result<()> {
+            self.detached.push(id);
+            Ok(())
+        }
+    }
+
+    fn sqe(opcode: Opcode, args: [u64; 6]) -> Sqe {
+        Sqe {
+            opcode: opcode as u8,
+            tag: 42,
+            args,
+            caller: 100,
+            ..Sqe::default()
+        }
+    }
+    fn handle(scheme: &mut Hello, sqe: &Sqe) -> Option<Cqe> {
+        unsafe { handle_sync(scheme, sqe) }
+    }
+    fn regular(cqe: Option<Cqe>) -> Result<usize> {
+        let cqe = cqe.unwrap();
+        assert_eq!(cqe.flags, CqeOpcode::RespondRegular as u8);
+        assert_eq!(cqe.tag, 42);
+        Error::demux(cqe.result as usize)
+    }
+
+    #[test]
+    fn dispatch() {
+        let mut scheme = Hello::default();
+        let path = "hello";
+        let creds = 1000 | (2000 << 32);
+        let open = sqe(
+            Opcode::OpenAt,
+            [0, path.as_ptr() as u64, path.len() as u64, 0, 0, creds],
+        );
+        let cqe = handle(&mut scheme, &open).unwrap();
+        assert_eq!(
+            (cqe.flags, cqe.result),
+            (CqeOpcode::RespondRegular as u8, 7)
+        );
+        assert_eq!(cqe.extra_raw[0], NewFdFlags::POSITIONED.bits());
+        assert_eq!(
+            scheme.opened_by,
+            [CallerCtx {
+                pid: 100,
+                uid: 1000,
+                gid: 2000
+            }]
+        );
+
+        let other = "other";
+        let open = sqe(Opcode::OpenAt, [0, other.as_ptr() as u64, 5, 0, 0, 0]);
+        let cqe = handle(&mut scheme, &open).unwrap();
+        assert_eq!((cqe.flags, cqe.result), (CqeOpcode::RespondWithFd as u8, 3));
+
+        let locked = "locked";
+        let open = sqe(Opcode::OpenAt, [0, locked.as_ptr() as u64, 6, 0, 0, 0]);
+        let cqe = handle(&mut scheme, &open).unwrap();
+        assert_eq!(
+            (cqe.flags, cqe.result),
+            (CqeOpcode::RespondAndNotifyOnDetach as u8, 8)
+        );
+        assert_eq!(cqe.extra(), u32::from(NewFdFlags::POSITIONED.bits()));
+        let detach = sqe(Opcode::Detach, [8, 0, 0, 0, 0, 0]);
+        assert_eq!(regular(handle(&mut scheme, &detach)), Ok(0));
+        assert_eq!(scheme.detached, [8]);
+
+        let missing = "missing";
+        let open = sqe(Opcode::OpenAt, [0, missing.as_ptr() as u64, 7, 0, 0, 0]);
+        assert_eq!(regular(handle(&mut scheme, &open)), Err(Error::new(ENOENT)));
+
+        let mut buf = [0; 5];
+        let read = sqe(Opcode::Read, [7, buf.as_mut_ptr() as u64, 5, 7, 0, 0]);
+        assert_eq!(regular(handle(&mut scheme, &read)), Ok(5));
+        assert_eq!(&buf, b"world");
+
+        let mut stat = Stat::default();
+        let size = core::mem::size_of::<Stat>() as u64;
+        let fstat = sqe(
+            Opcode::Fstat,
+            [7, &mut stat as *mut Stat as u64, size, 0, 0, 0],
+        );
+        assert_eq!(regular(handle(&mut scheme, &fstat)), Ok(0));
+        assert_eq!(stat.st_size, 13);
+        let fstat = sqe(
+            Opcode::Fstat,
+            [7, &mut stat as *mut Stat as u64, 1, 0, 0, 0],
+        );
+        assert_eq!(
+            regular(handle(&mut scheme, &fstat)),
+            Err(Error::new(EINVAL))
+        );
+
+        let mut dents = [0_u64; 8];
+        let header = core::mem::size_of::<DirentHeader>() as u64;
+        let getdents = sqe(
+            Opcode::Getdents,
+            [7, dents.as_mut_ptr() as u64, 64, header, 0, 0],
+        );
+        let len = regular(handle(&mut scheme, &getdents)).unwrap();
+        let bytes = unsafe { core::slice::from_raw_parts(dents.as_ptr().cast::<u8>(), len) };
+        let names: Vec<String> = DirentIter::new(bytes)
+            .map(|entry| {
+                // Names are NUL padded to the record length.
+                let name = entry.unwrap().1.split(|&b| b == 0).next().unwrap();
+                String::from_utf8(name.to_vec()).unwrap()
+            })
+            .collect();
+        assert_eq!(names, ["hello"]);
+
+        let mut payload = [0; 3];
+        let call = Request::call_with_ids(&[7, 8], &mut payload, &[100]).unwrap();
+        let call = call.encode(42, &CallerCtx::default());
+        assert_eq!(regular(handle(&mut scheme, &call)), Ok(115));
+        assert_eq!(payload, [2; 3]);
+        let mut partial = call;
+        partial.args[1] -= 1;
+        assert_eq!(
+            regular(handle(&mut scheme, &partial)),
+            Err(Error::new(EINVAL))
+        );
+
+        // Not implemented by the scheme, and not implemented at all.
+        let fsync = sqe(Opcode::Fsync, [7, 0, 0, 0, 0, 0]);
+        assert_eq!(regular(handle(&mut scheme, &fsync)), Err(Error::new(EBADF)));
+        let sendfd = sqe(Opcode::Sendfd, [7, 0, 0, 1, 0, 0]);
+        assert_eq!(regular(handle(&mut scheme, &sendfd)), Ok(1));
+        let recvfd = sqe(Opcode::Recvfd, [7, 0, 1, 0, 0, 0]);
+        let cqe = handle(&mut scheme, &recvfd).unwrap();
+        assert_eq!((cqe.flags, cqe.result), (CqeOpcode::RespondWithFd as u8, 3));
+        let mremap = sqe(Opcode::Mremap, [7, 4096, 8192, 0, 0, 0]);
+        assert_eq!(
+            regular(handle(&mut scheme, &mremap)),
+            Err(Error::new(EBADF))
+        );
+        let mut unknown = sqe(Opcode::Fsync, [0; 6]);
+        unknown.opcode = 0xff;
+        assert_eq!(
+            regular(handle(&mut scheme, &unknown)),
+            Err(Error::new(ENOSYS))
+        );
+
+        let mut close = sqe(Opcode::CloseMsg, [7, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            regular(handle(&mut scheme, &close)),
+            Err(Error::new(EINVAL))
+        );
+        close.sqe_flags = SqeFlags::ONEWAY;
+        assert!(handle(&mut scheme, &close).is_none());
+        let mut oneway = sqe(Opcode::Fsync, [7, 0, 0, 0, 0, 0]);
+        oneway.sqe_flags = SqeFlags::ONEWAY;
+        assert!(handle(&mut scheme, &oneway).is_none());
+        assert_eq!(scheme.closed, [7]);
+    }
+}
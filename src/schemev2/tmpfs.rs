@@ -0,0 +1,861 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::mem;
+
+use super::{
+    ByteLock, CallerCtx, HandleTable, LockKind, LockTable, NewFdFlags, OpenResult, SchemeSync,
+};
+use crate::{
+    data::{Flock, Stat, StdFsCallMeta},
+    dirent::{DirEntry, DirentBuf, DirentKind},
+    error::*,
+    flag::{
+        StdFsCallKind, AT_REMOVEDIR, F_UNLCK, MODE_DIR, MODE_FILE, MODE_PERM, MODE_TYPE, O_APPEND,
+        O_CREAT, O_DIRECTORY, O_EXCL, O_RDONLY, O_STAT, O_TRUNC, O_WRONLY, SEEK_CUR,
+    },
+};
+
+const ROOT: u64 = 1;
+const BLOCK_SIZE: u32 = 4096;
+
+enum Node {
+    File(Vec<u8>),
+    Dir {
+        entries: BTreeMap<String, u64>,
+        parent: u64,
+    },
+}
+
+struct Inode {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    /// How many directory entries refer to the inode.
+    nlink: u32,
+    /// How many handles refer to the inode, which keep it after its last link is removed.
+    open: usize,
+    node: Node,
+}
+
+impl Inode {
+    fn len(&self) -> u64 {
+        match &self.node {
+            Node::File(data) => data.len() as u64,
+            Node::Dir { .. } => 0,
+        }
+    }
+}
+
+struct Handle {
+    inode: u64,
+    flags: usize,
+    /// The path it was opened or last renamed as, relative to the root.
+    path: String,
+    /// The pids that took locks through it, which may differ from the opener's once the fd is
+    /// inherited or passed.
+    lockers: BTreeSet<usize>,
+}
+
+/// An in-memory filesystem, as a complete example of a [`SchemeSync`] server.
+///
+/// Paths are relative to `dirfd`, or to the root, whose id is 0, if they start with `/`. The
+/// permissions of the caller are checked with [`CallerCtx::check_access`], files created with
+/// `O_CREAT` take their permissions from the low bits of the open flags, and `O_DIRECTORY`
+/// creates a directory. Locks are set with [`StdFsCallKind::Lock`] and released when their owner
+/// closes an fd for the file, or the file description is closed. Since requests are answered one at a time, conflicting locks fail
+/// with `EAGAIN` even when the caller asked to wait. Renaming moves the file from the path its
+/// fd was opened or last renamed as, and fails with `ENOENT` if that path no longer refers to it.
+///
+/// It passes [`check_conformance`](super::check_conformance), so running the same requests
+/// through a `Loopback` against `Tmpfs` and another scheme shows where that scheme differs.
+pub struct Tmpfs {
+    name: String,
+    inodes: BTreeMap<u64, Inode>,
+    next_inode: u64,
+    handles: HandleTable<Handle>,
+    locks: LockTable,
+}
+
+impl Tmpfs {
+    /// An empty filesystem, served as the scheme `name`, whose root anyone can write to.
+    pub fn new(name: &str) -> Self {
+        let root = Inode {
+            mode: MODE_DIR | 0o777,
+            uid: 0,
+            gid: 0,
+            nlink: 1,
+            open: 0,
+            node: Node::Dir {
+                entries: BTreeMap::new(),
+                parent: ROOT,
+            },
+        };
+        Self {
+            name: name.to_string(),
+            inodes: BTreeMap::from([(ROOT, root)]),
+            next_inode: ROOT + 1,
+            handles: HandleTable::new(),
+            locks: LockTable::new(),
+        }
+    }
+
+    fn inode(&self, inode: u64) -> Result<&Inode> {
+        self.inodes.get(&inode).ok_or(Error::new(ENOENT))
+    }
+    fn entries(&self, dir: u64) -> Result<(&BTreeMap<String, u64>, u64)> {
+        match &self.inode(dir)?.node {
+            Node::Dir { entries, parent } => Ok((entries, *parent)),
+            Node::File(_) => Err(Error::new(ENOTDIR)),
+        }
+    }
+    fn entries_mut(&mut self, dir: u64) -> &mut BTreeMap<String, u64> {
+        match &mut self.inodes.get_mut(&dir).unwrap().node {
+            Node::Dir { entries, .. } => entries,
+            Node::File(_) => unreachable!(),
+        }
+    }
+    fn handle_inode(&self, id: usize) -> Result<u64> {
+        self.handles.get(id).map(|handle| handle.inode)
+    }
+    /// The directory `dirfd` refers to.
+    fn dir_fd(&self, dirfd: usize) -> Result<u64> {
+        let dir = match dirfd {
+            0 => ROOT,
+            _ => self.handle_inode(dirfd)?,
+        };
+        self.entries(dir).map(|_| dir)
+    }
+    fn check_dir_access(&self, dir: u64, flags: usize, ctx: &CallerCtx) -> Result<()> {
+        let inode = self.inode(dir)?;
+        ctx.check_access(inode.mode, inode.uid, inode.gid, flags)
+    }
+
+    /// Look up `name` in `dir`, which the caller must be allowed to search.
+    ///
+    /// A removed directory is empty and has no parent, even while it is still open.
+    fn step(&self, dir: u64, name: &str, ctx: &CallerCtx) -> Result<u64> {
+        let (entries, parent) = self.entries(dir)?;
+        let inode = self.inode(dir)?;
+        if !ctx.is_root() && ctx.perm(inode.mode, inode.uid, inode.gid) & 0o1 == 0 {
+            return Err(Error::new(EACCES));
+        }
+        match name {
+            "" | "." => Ok(dir),
+            _ if inode.nlink == 0 => Err(Error::new(ENOENT)),
+            ".." => Ok(parent),
+            name => entries.get(name).copied().ok_or(Error::new(ENOENT)),
+        }
+    }
+    /// The directory that holds the last component of `path`, and that component.
+    fn parent<'p>(&self, start: u64, path: &'p str, ctx: &CallerCtx) -> Result<(u64, &'p str)> {
+        let mut dir = if path.starts_with('/') { ROOT } else { start };
+        let path = path.trim_end_matches('/');
+        let (dirs, name) = path.rsplit_once('/').unwrap_or(("", path));
+        for component in dirs.split('/') {
+            dir = self.step(dir, component, ctx)?;
+        }
+        self.entries(dir)?;
+        Ok((dir, name))
+    }
+    /// The path of `dir` relative to the root.
+    fn dir_path(&self, mut dir: u64) -> String {
+        let mut names = Vec::new();
+        while dir != ROOT {
+            let Ok((_, parent)) = self.entries(dir) else {
+                break;
+            };
+            let Ok((entries, _)) = self.entries(parent) else {
+                break;
+            };
+            match entries.iter().find(|(_, &inode)| inode == dir) {
+                Some((name, _)) => names.push(name.as_str()),
+                None => break,
+            }
+            dir = parent;
+        }
+        names.reverse();
+        names.join("/")
+    }
+    fn entry_path(&self, dir: u64, name: &str, inode: u64) -> String {
+        if self.entries(inode).is_ok() {
+            return self.dir_path(inode);
+        }
+        match self.dir_path(dir) {
+            path if path.is_empty() => name.to_string(),
+            path => format!("{path}/{name}"),
+        }
+    }
+
+    fn new_name(name: &str) -> Result<&str> {
+        match name {
+            "" | "." | ".." => Err(Error::new(EEXIST)),
+            name => Ok(name),
+        }
+    }
+    fn create(&mut self, dir: u64, name: &str, flags: usize, ctx: &CallerCtx) -> Result<u64> {
+        Self::new_name(name)?;
+        if self.inode(dir)?.nlink == 0 {
+            return Err(Error::new(ENOENT));
+        }
+        self.check_dir_access(dir, O_WRONLY, ctx)?;
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        let (mode, node) = match flags & O_DIRECTORY {
+            0 => (MODE_FILE, Node::File(Vec::new())),
+            _ => (
+                MODE_DIR,
+                Node::Dir {
+                    entries: BTreeMap::new(),
+                    parent: dir,
+                },
+            ),
+        };
+        self.inodes.insert(
+            inode,
+            Inode {
+                mode: mode | (flags as u16 & MODE_PERM),
+                uid: ctx.uid,
+                gid: ctx.gid,
+                nlink: 1,
+                open: 0,
+                node,
+            },
+        );
+        self.entries_mut(dir).insert(name.to_string(), inode);
+        Ok(inode)
+    }
+    /// Drop a link to `inode`, and the inode itself once it has no links and is not open.
+    fn unlink_inode(&mut self, inode: u64) {
+        let node = self.inodes.get_mut(&inode).unwrap();
+        node.nlink -= 1;
+        if node.nlink == 0 && node.open == 0 {
+            self.inodes.remove(&inode);
+        }
+    }
+    fn is_ancestor(&self, ancestor: u64, mut dir: u64) -> bool {
+        loop {
+            if dir == ancestor {
+                return true;
+            }
+            match self.entries(dir) {
+                Ok((_, parent)) if dir != ROOT => dir = parent,
+                _ => return false,
+            }
+        }
+    }
+
+    fn lock(
+        &mut self,
+        id: usize,
+        kind: StdFsCallKind,
+        payload: &mut [u8],
+        wait: bool,
+        ctx: &CallerCtx,
+    ) -> Result<usize> {
+        let inode = self.handle_inode(id)?;
+        let mut flock = Flock::default();
+        if payload.len() != flock.len() {
+            return Err(Error::new(EINVAL));
+        }
+        flock.copy_from_slice(payload);
+        // Relative to a file offset the scheme doesn't know.
+        if flock.l_whence as usize == SEEK_CUR {
+            return Err(Error::new(EINVAL));
+        }
+        let range = flock.range(0, self.inodes[&inode].len())?;
+        let file = inode as usize;
+        let lock = |kind| ByteLock {
+            owner: ctx.pid,
+            kind,
+            start: range.start,
+            end: range.end,
+        };
+
+        match (kind, LockKind::from_l_type(flock.l_type)?) {
+            (StdFsCallKind::Unlock, _) | (StdFsCallKind::Lock, None) => {
+                self.locks.unlock(file, ctx.pid, range.clone())
+            }
+            (StdFsCallKind::Lock, Some(kind)) => {
+                if !self.locks.lock(file, lock(kind), wait)? {
+                    self.locks.cancel_wait(ctx.pid);
+                    return Err(Error::new(EAGAIN));
+                }
+                self.handles.get_mut(id)?.lockers.insert(ctx.pid);
+            }
+            (_, kind) => {
+                let kind = kind.ok_or(Error::new(EINVAL))?;
+                let reply = match self.locks.conflict(file, &lock(kind)) {
+                    Some(held) => held.to_flock(),
+                    None => Flock {
+                        l_type: F_UNLCK,
+                        ..flock
+                    },
+                };
+                payload.copy_from_slice(&reply);
+            }
+        }
+        Ok(0)
+    }
+}
+
+impl SchemeSync for Tmpfs {
+    fn openat(
+        &mut self,
+        dirfd: usize,
+        path: &str,
+        flags: usize,
+        _fcntl_flags: u32,
+        ctx: &CallerCtx,
+    ) -> Result<OpenResult> {
+        let (dir, name) = self.parent(self.dir_fd(dirfd)?, path, ctx)?;
+        let (inode, created) = match self.step(dir, name, ctx) {
+            Ok(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => {
+                return Err(Error::new(EEXIST))
+            }
+            Ok(inode) => (inode, false),
+            Err(err) if err.errno == ENOENT && flags & O_CREAT != 0 => {
+                (self.create(dir, name, flags, ctx)?, true)
+            }
+            Err(err) => return Err(err),
+        };
+
+        let path = self.entry_path(dir, name, inode);
+        let node = self.inodes.get_mut(&inode).unwrap();
+        let is_dir = matches!(node.node, Node::Dir { .. });
+        if flags & O_DIRECTORY != 0 && !is_dir {
+            return Err(Error::new(ENOTDIR));
+        }
+        if is_dir && flags & O_WRONLY == O_WRONLY {
+            return Err(Error::new(EISDIR));
+        }
+        if !created && flags & O_STAT == 0 {
+            ctx.check_access(node.mode, node.uid, node.gid, flags)?;
+        }
+        if let Node::File(data) = &mut node.node {
+            if flags & O_TRUNC != 0 && flags & O_WRONLY == O_WRONLY {
+                data.clear();
+            }
+        }
+        node.open += 1;
+
+        let handle = Handle {
+            inode,
+            flags,
+            path,
+            lockers: BTreeSet::new(),
+        };
+        let number = self.handles.insert(handle, ctx);
+        Ok(OpenResult::ThisScheme {
+            number,
+            flags: match is_dir {
+                true => NewFdFlags::NOTIFY_ON_DETACH,
+                false => NewFdFlags::POSITIONED | NewFdFlags::NOTIFY_ON_DETACH,
+            },
+        })
+    }
+    fn unlinkat(&mut self, dirfd: usize, path: &str, flags: usize, ctx: &CallerCtx) -> Result<()> {
+        let (dir, name) = self.parent(self.dir_fd(dirfd)?, path, ctx)?;
+        let inode = self.step(
+            dir,
+            Self::new_name(name).map_err(|_| Error::new(EINVAL))?,
+            ctx,
+        )?;
+        self.check_dir_access(dir, O_WRONLY, ctx)?;
+        match (self.entries(inode), flags & AT_REMOVEDIR != 0) {
+            (Ok(_), false) => return Err(Error::new(EISDIR)),
+            (Ok((entries, _)), true) if !entries.is_empty() => return Err(Error::new(ENOTEMPTY)),
+            (Err(_), true) => return Err(Error::new(ENOTDIR)),
+            _ => (),
+        }
+        self.entries_mut(dir).remove(name);
+        self.unlink_inode(inode);
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        id: usize,
+        buf: &mut [u8],
+        offset: u64,
+        _fcntl_flags: u32,
+        _ctx: &CallerCtx,
+    ) -> Result<usize> {
+        let handle = self.handles.get(id)?;
+        if handle.flags & O_RDONLY != O_RDONLY {
+            return Err(Error::new(EBADF));
+        }
+        let Node::File(data) = &self.inodes[&handle.inode].node else {
+            return Err(Error::new(EISDIR));
+        };
+        let data = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| data.get(offset..))
+            .unwrap_or(&[]);
+        let count = data.len().min(buf.len());
+        buf[..count].copy_from_slice(&data[..count]);
+        Ok(count)
+    }
+    fn write(
+        &mut self,
+        id: usize,
+        buf: &[u8],
+        offset: u64,
+        fcntl_flags: u32,
+        ctx: &CallerCtx,
+    ) -> Result<usize> {
+        let handle = self.handles.get(id)?;
+        if handle.flags & O_WRONLY != O_WRONLY {
+            return Err(Error::new(EBADF));
+        }
+        let append = (handle.flags | fcntl_flags as usize) & O_APPEND != 0;
+        let inode = self.inodes.get_mut(&handle.inode).unwrap();
+        let Node::File(data) = &mut inode.node else {
+            return Err(Error::new(EISDIR));
+        };
+        let start = match append {
+            true => data.len(),
+            false => usize::try_from(offset).map_err(|_| Error::new(EFBIG))?,
+        };
+        let end = start.checked_add(buf.len()).ok_or(Error::new(EFBIG))?;
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        inode.mode = ctx.drop_setid(inode.mode);
+        Ok(buf.len())
+    }
+    fn fsize(&mut self, id: usize, _ctx: &CallerCtx) -> Result<u64> {
+        Ok(self.inodes[&self.handle_inode(id)?].len())
+    }
+    fn fchmod(&mut self, id: usize, new_mode: u16, ctx: &CallerCtx) -> Result<()> {
+        let inode = self.inodes.get_mut(&self.handle_inode(id)?).unwrap();
+        let mode = ctx.check_chmod(inode.uid, inode.gid, new_mode)?;
+        inode.mode = inode.mode & MODE_TYPE | mode;
+        Ok(())
+    }
+    fn fchown(&mut self, id: usize, new_uid: u32, new_gid: u32, ctx: &CallerCtx) -> Result<()> {
+        let inode = self.inodes.get_mut(&self.handle_inode(id)?).unwrap();
+        // Only root gives files away, owners may change the group to their own.
+        let allowed = ctx.is_root()
+            || (ctx.uid == inode.uid
+                && new_uid == inode.uid
+                && (new_gid == inode.gid || new_gid == ctx.gid));
+        if !allowed {
+            return Err(Error::new(EPERM));
+        }
+        inode.mode = ctx.drop_setid(inode.mode);
+        inode.uid = new_uid;
+        inode.gid = new_gid;
+        Ok(())
+    }
+    fn fpath(&mut self, id: usize, buf: &mut [u8], _ctx: &CallerCtx) -> Result<usize> {
+        let path = format!("/scheme/{}/{}", self.name, self.handles.get(id)?.path);
+        let count = path.len().min(buf.len());
+        buf[..count].copy_from_slice(&path.as_bytes()[..count]);
+        Ok(count)
+    }
+    fn flink(&mut self, id: usize, path: &str, ctx: &CallerCtx) -> Result<()> {
+        let inode = self.handle_inode(id)?;
+        if self.entries(inode).is_ok() {
+            return Err(Error::new(EPERM));
+        }
+        let (dir, name) = self.parent(ROOT, path, ctx)?;
+        if self.step(dir, Self::new_name(name)?, ctx).is_ok() {
+            return Err(Error::new(EEXIST));
+        }
+        self.check_dir_access(dir, O_WRONLY, ctx)?;
+        self.entries_mut(dir).insert(name.to_string(), inode);
+        self.inodes.get_mut(&inode).unwrap().nlink += 1;
+        Ok(())
+    }
+    fn frename(&mut self, id: usize, path: &str, ctx: &CallerCtx) -> Result<()> {
+        let handle = self.handles.get(id)?;
+        let inode = handle.inode;
+        let (old_dir, old_name) = self.parent(ROOT, &handle.path, ctx)?;
+        let old_name = old_name.to_string();
+        if self.step(old_dir, &old_name, ctx) != Ok(inode) {
+            return Err(Error::new(ENOENT));
+        }
+        let (new_dir, new_name) = self.parent(ROOT, path, ctx)?;
+        let new_name = Self::new_name(new_name).map_err(|_| Error::new(EINVAL))?;
+        self.check_dir_access(old_dir, O_WRONLY, ctx)?;
+        self.check_dir_access(new_dir, O_WRONLY, ctx)?;
+
+        let is_dir = self.entries(inode).is_ok();
+        if is_dir && self.is_ancestor(inode, new_dir) {
+            return Err(Error::new(EINVAL));
+        }
+        let replaced = match self.step(new_dir, new_name, ctx) {
+            Ok(target) if target == inode => return Ok(()),
+            Ok(target) => match (is_dir, self.entries(target)) {
+                (true, Ok((entries, _))) if !entries.is_empty() => {
+                    return Err(Error::new(ENOTEMPTY))
+                }
+                (false, Ok(_)) => return Err(Error::new(EISDIR)),
+                (true, Err(_)) => return Err(Error::new(ENOTDIR)),
+                _ => Some(target),
+            },
+            Err(err) if err.errno == ENOENT => None,
+            Err(err) => return Err(err),
+        };
+
+        self.entries_mut(old_dir).remove(&old_name);
+        self.entries_mut(new_dir)
+            .insert(new_name.to_string(), inode);
+        if let Some(target) = replaced {
+            self.unlink_inode(target);
+        }
+        if let Node::Dir { parent, .. } = &mut self.inodes.get_mut(&inode).unwrap().node {
+            *parent = new_dir;
+        }
+        let path = self.entry_path(new_dir, new_name, inode);
+        self.handles.get_mut(id)?.path = path;
+        Ok(())
+    }
+    fn fstat(&mut self, id: usize, stat: &mut Stat, _ctx: &CallerCtx) -> Result<()> {
+        let number = self.handle_inode(id)?;
+        let inode = &self.inodes[&number];
+        *stat = Stat {
+            st_ino: number,
+            st_mode: inode.mode,
+            st_nlink: inode.nlink,
+            st_uid: inode.uid,
+            st_gid: inode.gid,
+            st_size: inode.len(),
+            st_blksize: BLOCK_SIZE,
+            st_blocks: inode.len().div_ceil(512),
+            ..Stat::default()
+        };
+        Ok(())
+    }
+    fn fsync(&mut self, id: usize, _ctx: &CallerCtx) -> Result<()> {
+        self.handles.get(id).map(|_| ())
+    }
+    fn ftruncate(&mut self, id: usize, len: u64, ctx: &CallerCtx) -> Result<()> {
+        let handle = self.handles.get(id)?;
+        if handle.flags & O_WRONLY != O_WRONLY {
+            return Err(Error::new(EBADF));
+        }
+        let inode = self.inodes.get_mut(&handle.inode).unwrap();
+        let Node::File(data) = &mut inode.node else {
+            return Err(Error::new(EISDIR));
+        };
+        data.resize(usize::try_from(len).map_err(|_| Error::new(EFBIG))?, 0);
+        inode.mode = ctx.drop_setid(inode.mode);
+        Ok(())
+    }
+    /// Lists the entries in name order, where `opaque_offset` is the index of the next one.
+    fn getdents<'buf>(
+        &mut self,
+        id: usize,
+        mut buf: DirentBuf<&'buf mut [u8]>,
+        opaque_offset: u64,
+        _ctx: &CallerCtx,
+    ) -> Result<DirentBuf<&'buf mut [u8]>> {
+        let (entries, _) = self.entries(self.handle_inode(id)?)?;
+        let skip = usize::try_from(opaque_offset).unwrap_or(usize::MAX);
+        for (index, (name, &inode)) in entries.iter().enumerate().skip(skip) {
+            let kind = match self.inodes[&inode].node {
+                Node::File(_) => DirentKind::Regular,
+                Node::Dir { .. } => DirentKind::Directory,
+            };
+            let entry = DirEntry {
+                inode,
+                next_opaque_id: index as u64 + 1,
+                name,
+                kind,
+            };
+            match buf.entry(entry) {
+                Ok(()) => (),
+                // Stop once the buffer is full, unless not even one entry fits.
+                Err(err) if index == skip => return Err(err),
+                Err(_) => break,
+            }
+        }
+        Ok(buf)
+    }
+    fn std_fs_call(
+        &mut self,
+        id: usize,
+        payload: &mut [u8],
+        meta: StdFsCallMeta,
+        ctx: &CallerCtx,
+    ) -> Result<usize> {
+        let kind = StdFsCallKind::try_from_raw(meta.kind).ok_or(Error::new(EINVAL))?;
+        match kind {
+            StdFsCallKind::Fchmod => self.fchmod(id, meta.arg1 as u16, ctx),
+            StdFsCallKind::Fchown => self.fchown(id, meta.arg1 as u32, meta.arg2 as u32, ctx),
+            StdFsCallKind::Fstat => {
+                let mut stat = Stat::default();
+                if payload.len() != mem::size_of::<Stat>() {
+                    return Err(Error::new(EINVAL));
+                }
+                self.fstat(id, &mut stat, ctx)?;
+                payload.copy_from_slice(&stat);
+                Ok(())
+            }
+            StdFsCallKind::Fsync => self.fsync(id, ctx),
+            StdFsCallKind::Ftruncate => self.ftruncate(id, meta.arg1, ctx),
+            StdFsCallKind::Lock | StdFsCallKind::Unlock | StdFsCallKind::GetLock => {
+                return self.lock(id, kind, payload, meta.arg1 != 0, ctx);
+            }
+            _ => Err(Error::new(EOPNOTSUPP)),
+        }
+        .map(|()| 0)
+    }
+
+    fn on_close(&mut self, id: usize) {
+        let Ok(handle) = self.handles.close(id) else {
+            return;
+        };
+        for &owner in &handle.lockers {
+            self.locks.release(handle.inode as usize, owner);
+        }
+        let inode = self.inodes.get_mut(&handle.inode).unwrap();
+        inode.open -= 1;
+        if inode.nlink == 0 && inode.open == 0 {
+            self.inodes.remove(&handle.inode);
+        }
+    }
+    fn on_detach(&mut self, id: usize, ctx: &CallerCtx) -> Result<()> {
+        let handle = self.handles.get_mut(id)?;
+        handle.lockers.remove(&ctx.pid);
+        let inode = handle.inode;
+        self.locks.release(inode as usize, ctx.pid);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{string::String, vec::Vec};
+
+    use super::Tmpfs;
+    use crate::{
+        data::{Flock, Stat, StdFsCallMeta},
+        dirent::{DirentHeader, DirentIter},
+        error::*,
+        flag::{
+            StdFsCallKind, AT_REMOVEDIR, F_UNLCK, F_WRLCK, MODE_DIR, MODE_FILE, O_CREAT,
+            O_DIRECTORY, O_EXCL, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, SEEK_SET,
+        },
+        schemev2::{CallerCtx, Loopback},
+    };
+
+    fn ctx(pid: usize, uid: u32) -> CallerCtx {
+        CallerCtx { pid, uid, gid: uid }
+    }
+    fn tmpfs() -> Loopback<Tmpfs> {
+        Loopback::new(Tmpfs::new("tmp"), ctx(1, 1000))
+    }
+    fn read_all(fs: &mut Loopback<Tmpfs>, path: &str) -> Result<Vec<u8>> {
+        let fd = fs.open(path, O_RDONLY)?;
+        let mut buf = [0; 64];
+        let count = fs.read(fd, &mut buf)?;
+        fs.close(fd)?;
+        Ok(buf[..count].to_vec())
+    }
+    fn path(fs: &mut Loopback<Tmpfs>, fd: usize) -> String {
+        let mut buf = [0; 64];
+        let count = fs.fpath(fd, &mut buf).unwrap();
+        String::from_utf8(buf[..count].to_vec()).unwrap()
+    }
+    fn names(fs: &mut Loopback<Tmpfs>, fd: usize) -> Vec<String> {
+        let mut buf = [0; 256];
+        let count = fs.getdents(fd, &mut buf, 0).unwrap();
+        DirentIter::new(&buf[..count])
+            // Names are followed by a NUL, and padding up to the alignment.
+            .map(|entry| entry.unwrap().1.split(|&b| b == 0).next().unwrap().to_vec())
+            .map(|name| String::from_utf8(name).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn files() {
+        let mut fs = tmpfs();
+        let dir = fs.open("dir", O_CREAT | O_DIRECTORY | 0o755).unwrap();
+        let fd = fs.openat(dir, "a", O_CREAT | O_RDWR | 0o644).unwrap();
+        assert_eq!(fs.write(fd, b"hello"), Ok(5));
+        assert_eq!(read_all(&mut fs, "/dir/a").unwrap(), b"hello");
+
+        let mut stat = Stat::default();
+        fs.fstat(fd, &mut stat).unwrap();
+        assert_eq!((stat.st_mode, stat.st_size), (MODE_FILE | 0o644, 5));
+        assert_eq!(path(&mut fs, fd), "/scheme/tmp/dir/a");
+        fs.ftruncate(fd, 2).unwrap();
+        assert_eq!(read_all(&mut fs, "dir/./a").unwrap(), b"he");
+        let fd2 = fs.open("dir/a", O_WRONLY | O_TRUNC).unwrap();
+        assert_eq!(read_all(&mut fs, "dir/a").unwrap(), b"");
+        assert_eq!(fs.read(fd2, &mut [0; 4]), Err(Error::new(EBADF)));
+
+        assert_eq!(
+            fs.open("dir/a", O_CREAT | O_EXCL | O_RDWR),
+            Err(Error::new(EEXIST))
+        );
+        assert_eq!(fs.open("dir/a", O_DIRECTORY), Err(Error::new(ENOTDIR)));
+        assert_eq!(fs.open("dir", O_RDWR), Err(Error::new(EISDIR)));
+        assert_eq!(fs.open("dir/b", O_RDONLY), Err(Error::new(ENOENT)));
+        assert_eq!(fs.open("dir/a/b", O_RDONLY), Err(Error::new(ENOTDIR)));
+
+        // Others may not read a private file, or write to the directory.
+        let private = fs.open("dir/private", O_CREAT | O_RDWR | 0o600).unwrap();
+        fs.set_ctx(ctx(2, 1001));
+        assert_eq!(fs.open("dir/private", O_RDONLY), Err(Error::new(EACCES)));
+        assert_eq!(fs.open("dir/c", O_CREAT | O_RDWR), Err(Error::new(EACCES)));
+        assert_eq!(read_all(&mut fs, "dir/a").unwrap(), b"");
+        let root = fs.open("", O_RDONLY | O_DIRECTORY).unwrap();
+        let mut stat = Stat::default();
+        fs.fstat(root, &mut stat).unwrap();
+        assert_eq!(stat.st_mode, MODE_DIR | 0o777);
+        for fd in [fd, fd2, private, dir, root] {
+            fs.close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn directories() {
+        let mut fs = tmpfs();
+        let dir = fs.open("dir", O_CREAT | O_DIRECTORY | 0o755).unwrap();
+        let fd = fs.open("dir/a", O_CREAT | O_RDWR | 0o644).unwrap();
+        fs.write(fd, b"data").unwrap();
+        fs.open("dir/sub", O_CREAT | O_DIRECTORY | 0o755)
+            .and_then(|sub| fs.close(sub))
+            .unwrap();
+        assert_eq!(names(&mut fs, dir), ["a", "sub"]);
+        let mut buf = [0; core::mem::size_of::<DirentHeader>() + 8];
+        let count = fs.getdents(dir, &mut buf, 1).unwrap();
+        assert_eq!(DirentIter::new(&buf[..count]).count(), 1);
+
+        fs.flink(fd, "dir/b").unwrap();
+        assert_eq!(fs.flink(fd, "dir/b"), Err(Error::new(EEXIST)));
+        fs.unlink("dir/a", 0).unwrap();
+        assert_eq!(read_all(&mut fs, "dir/b").unwrap(), b"data");
+        let mut stat = Stat::default();
+        fs.fstat(fd, &mut stat).unwrap();
+        assert_eq!(stat.st_nlink, 1);
+
+        // The open fd was still named `a`, so its file moves from `b` under the root.
+        assert_eq!(fs.frename(fd, "/c"), Err(Error::new(ENOENT)));
+        let b = fs.open("dir/b", O_RDONLY).unwrap();
+        fs.frename(b, "/c").unwrap();
+        assert_eq!(path(&mut fs, b), "/scheme/tmp/c");
+        assert_eq!(read_all(&mut fs, "c").unwrap(), b"data");
+        assert_eq!(names(&mut fs, dir), ["sub"]);
+
+        assert_eq!(fs.unlink("dir", AT_REMOVEDIR), Err(Error::new(ENOTEMPTY)));
+        assert_eq!(fs.unlink("dir", 0), Err(Error::new(EISDIR)));
+        assert_eq!(fs.unlink("c", AT_REMOVEDIR), Err(Error::new(ENOTDIR)));
+        assert_eq!(fs.frename(dir, "dir/sub/dir"), Err(Error::new(EINVAL)));
+        fs.frename(dir, "moved").unwrap();
+        assert_eq!(path(&mut fs, dir), "/scheme/tmp/moved");
+        fs.unlink("moved/sub", AT_REMOVEDIR).unwrap();
+        fs.unlink("moved", AT_REMOVEDIR).unwrap();
+        let root = fs.open("/", O_RDONLY).unwrap();
+        assert_eq!(names(&mut fs, root), ["c"]);
+    }
+
+    #[test]
+    fn removed_dirs() {
+        let mut fs = tmpfs();
+        fs.open("a", O_CREAT | O_DIRECTORY | 0o755).unwrap();
+        let b = fs.open("a/b", O_CREAT | O_DIRECTORY | 0o755).unwrap();
+        fs.unlink("a/b", AT_REMOVEDIR).unwrap();
+        fs.unlink("a", AT_REMOVEDIR).unwrap();
+
+        let create = O_CREAT | O_RDWR | 0o644;
+        assert_eq!(fs.openat(b, "../x", create), Err(Error::new(ENOENT)));
+        assert_eq!(fs.openat(b, "x", create), Err(Error::new(ENOENT)));
+        assert_eq!(
+            fs.openat(b, "y", O_CREAT | O_DIRECTORY | 0o755),
+            Err(Error::new(ENOENT))
+        );
+        assert_eq!(names(&mut fs, b), Vec::<String>::new());
+        fs.close(b).unwrap();
+    }
+
+    #[test]
+    fn std_fs_calls() {
+        let mut fs = tmpfs();
+        let fd = fs.open("file", O_CREAT | O_RDWR | 0o644).unwrap();
+        let lock = |l_type, l_start, l_len| Flock {
+            l_type,
+            l_whence: SEEK_SET as i16,
+            l_start,
+            l_len,
+            l_pid: 0,
+        };
+        let meta = |kind, arg1| StdFsCallMeta::new(kind, arg1, 0);
+        let mut flock = lock(F_WRLCK, 0, 10);
+        fs.std_fs_call(fd, &mut flock, &meta(StdFsCallKind::Lock, 0))
+            .unwrap();
+
+        fs.set_ctx(ctx(2, 1000));
+        let other = fs.open("file", O_RDWR).unwrap();
+        let mut flock = lock(F_WRLCK, 5, 0);
+        assert_eq!(
+            fs.std_fs_call(other, &mut flock, &meta(StdFsCallKind::Lock, 1)),
+            Err(Error::new(EAGAIN))
+        );
+        fs.std_fs_call(other, &mut flock, &meta(StdFsCallKind::GetLock, 0))
+            .unwrap();
+        assert_eq!((flock.l_pid, flock.l_start, flock.l_len), (1, 0, 10));
+        let mut flock = lock(F_WRLCK, 10, 0);
+        fs.std_fs_call(other, &mut flock, &meta(StdFsCallKind::GetLock, 0))
+            .unwrap();
+        assert_eq!(flock.l_type, F_UNLCK);
+
+        // Closing the fd that took the lock releases it.
+        fs.close(fd).unwrap();
+        let mut flock = lock(F_WRLCK, 0, 0);
+        fs.std_fs_call(other, &mut flock, &meta(StdFsCallKind::Lock, 0))
+            .unwrap();
+
+        fs.std_fs_call(other, &mut [], &meta(StdFsCallKind::Fchmod, 0o600))
+            .unwrap();
+        let mut stat = Stat::default();
+        fs.std_fs_call(other, &mut stat, &meta(StdFsCallKind::Fstat, 0))
+            .unwrap();
+        assert_eq!(stat.st_mode, MODE_FILE | 0o600);
+        assert_eq!(
+            fs.std_fs_call(other, &mut [], &meta(StdFsCallKind::Relpathat, 0)),
+            Err(Error::new(EOPNOTSUPP))
+        );
+    }
+
+    #[test]
+    fn shared_lock_owners() {
+        let mut fs = tmpfs();
+        let meta = |kind| StdFsCallMeta::new(kind, 0, 0);
+        let lock = |fs: &mut Loopback<Tmpfs>, fd| {
+            let mut flock = Flock {
+                l_type: F_WRLCK,
+                l_whence: SEEK_SET as i16,
+                ..Flock::default()
+            };
+            fs.std_fs_call(fd, &mut flock, &meta(StdFsCallKind::Lock))
+        };
+        let fd = fs.open("file", O_CREAT | O_RDWR | 0o644).unwrap();
+
+        // Another process locks through the inherited fd, and the opener closes it.
+        fs.set_ctx(ctx(2, 1000));
+        lock(&mut fs, fd).unwrap();
+        fs.set_ctx(ctx(3, 1000));
+        let other = fs.open("file", O_RDWR).unwrap();
+        assert_eq!(lock(&mut fs, other), Err(Error::new(EAGAIN)));
+        fs.set_ctx(ctx(1, 1000));
+        fs.close(fd).unwrap();
+        fs.set_ctx(ctx(3, 1000));
+        lock(&mut fs, other).unwrap();
+
+        // Closing any fd for the file releases the locks of the process closing it.
+        let second = fs.open("file", O_RDONLY).unwrap();
+        fs.close(second).unwrap();
+        fs.set_ctx(ctx(4, 1000));
+        let fourth = fs.open("file", O_RDWR).unwrap();
+        lock(&mut fs, fourth).unwrap();
+    }
+}
@@ -0,0 +1,635 @@
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::{
+    call,
+    data::TimeSpec,
+    error::{Error, Result, ETIMEDOUT},
+};
+
+/// The futex operations the primitives in this module are built on.
+///
+/// [`SysFutex`] uses the kernel, other implementations allow testing the primitives on other
+/// hosts.
+pub trait Futex {
+    /// Block while `word` contains `expected`, for at most `timeout` if set. May wake up
+    /// spuriously, and must return `ETIMEDOUT` if and only if the timeout expired.
+    fn wait(word: &AtomicU32, expected: u32, timeout: Option<&TimeSpec>) -> Result<()>;
+    /// Wake up to `count` threads blocked on `word`.
+    fn wake(word: &AtomicU32, count: u32);
+}
+
+/// [`Futex`] implemented with `SYS_FUTEX`.
+#[derive(Debug)]
+pub struct SysFutex;
+
+impl Futex for SysFutex {
+    fn wait(word: &AtomicU32, expected: u32, timeout: Option<&TimeSpec>) -> Result<()> {
+        call::futex_wait(word, expected, timeout)
+    }
+    fn wake(word: &AtomicU32, count: u32) {
+        let _ = call::futex_wake(word, count);
+    }
+}
+
+// Mutex states
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+const CONTENDED: u32 = 2;
+
+/// A mutual exclusion lock.
+pub struct Mutex<T: ?Sized, F: Futex = SysFutex> {
+    state: AtomicU32,
+    _futex: PhantomData<fn() -> F>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send, F: Futex> Send for Mutex<T, F> {}
+unsafe impl<T: ?Sized + Send, F: Futex> Sync for Mutex<T, F> {}
+
+impl<T, F: Futex> Mutex<T, F> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+            _futex: PhantomData,
+            data: UnsafeCell::new(value),
+        }
+    }
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized, F: Futex> Mutex<T, F> {
+    pub fn lock(&self) -> MutexGuard<'_, T, F> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // Once contended, keep the state at CONTENDED until unlocked, so that the thread
+            // unlocking knows it needs to wake someone up.
+            while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+                let _ = F::wait(&self.state, CONTENDED, None);
+            }
+        }
+        MutexGuard { mutex: self }
+    }
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T, F>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            F::wake(&self.state, 1);
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, F: Futex> fmt::Debug for Mutex<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut d = f.debug_struct("Mutex");
+        match self.try_lock() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
+impl<T: Default, F: Futex> Default for Mutex<T, F> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized, F: Futex = SysFutex> {
+    mutex: &'a Mutex<T, F>,
+}
+
+impl<T: ?Sized, F: Futex> Deref for MutexGuard<'_, T, F> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+impl<T: ?Sized, F: Futex> DerefMut for MutexGuard<'_, T, F> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+impl<T: ?Sized, F: Futex> Drop for MutexGuard<'_, T, F> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// A condition variable, used together with a [`Mutex`].
+pub struct Condvar<F: Futex = SysFutex> {
+    /// Incremented on every notification, so that waiters can tell if they missed one between
+    /// unlocking the mutex and blocking.
+    seq: AtomicU32,
+    _futex: PhantomData<fn() -> F>,
+}
+
+impl<F: Futex> Condvar<F> {
+    pub const fn new() -> Self {
+        Self {
+            seq: AtomicU32::new(0),
+            _futex: PhantomData,
+        }
+    }
+    /// Unlock the mutex, block until notified (or spuriously woken), and lock it again.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T, F>) -> MutexGuard<'a, T, F> {
+        self.wait_inner(guard, None).0
+    }
+    /// Like [`Condvar::wait`], but for at most `timeout`. Returns `true` if the timeout expired.
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T, F>,
+        timeout: &TimeSpec,
+    ) -> (MutexGuard<'a, T, F>, bool) {
+        self.wait_inner(guard, Some(timeout))
+    }
+    fn wait_inner<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T, F>,
+        timeout: Option<&TimeSpec>,
+    ) -> (MutexGuard<'a, T, F>, bool) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        let mutex = guard.mutex;
+        drop(guard);
+        let timed_out = F::wait(&self.seq, seq, timeout) == Err(Error::new(ETIMEDOUT));
+        (mutex.lock(), timed_out)
+    }
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        F::wake(&self.seq, 1);
+    }
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        F::wake(&self.seq, u32::MAX);
+    }
+}
+
+impl<F: Futex> Default for Condvar<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: Futex> fmt::Debug for Condvar<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Condvar").finish_non_exhaustive()
+    }
+}
+
+/// RwLock state meaning write locked, any other value is the number of readers.
+const WRITER: u32 = u32::MAX;
+
+/// A reader-writer lock.
+///
+/// This lock is not fair, and writers can be starved by a continuous stream of readers.
+pub struct RwLock<T: ?Sized, F: Futex = SysFutex> {
+    state: AtomicU32,
+    /// Number of threads blocked on `state`, so that unlocking can skip the wakeup otherwise.
+    waiters: AtomicU32,
+    _futex: PhantomData<fn() -> F>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send, F: Futex> Send for RwLock<T, F> {}
+unsafe impl<T: ?Sized + Send + Sync, F: Futex> Sync for RwLock<T, F> {}
+
+impl<T, F: Futex> RwLock<T, F> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            waiters: AtomicU32::new(0),
+            _futex: PhantomData,
+            data: UnsafeCell::new(value),
+        }
+    }
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized, F: Futex> RwLock<T, F> {
+    pub fn read(&self) -> RwLockReadGuard<'_, T, F> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            self.wait_while(WRITER);
+        }
+    }
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T, F>> {
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |readers| {
+                // WRITER - 1 readers would make the next one look like a writer
+                (readers < WRITER - 1).then(|| readers + 1)
+            })
+            .ok()
+            .map(|_| RwLockReadGuard { lock: self })
+    }
+    pub fn write(&self) -> RwLockWriteGuard<'_, T, F> {
+        loop {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+            match self.state.load(Ordering::Relaxed) {
+                0 => continue,
+                state => self.wait_while(state),
+            }
+        }
+    }
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T, F>> {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| RwLockWriteGuard { lock: self })
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn wait_while(&self, state: u32) {
+        // SeqCst pairs with `wake_waiters`: either the unlocking thread sees this waiter, or the
+        // futex sees the new state and returns immediately.
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        let _ = F::wait(&self.state, state, None);
+        self.waiters.fetch_sub(1, Ordering::Relaxed);
+    }
+    fn wake_waiters(&self) {
+        if self.waiters.load(Ordering::SeqCst) != 0 {
+            F::wake(&self.state, u32::MAX);
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, F: Futex> fmt::Debug for RwLock<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut d = f.debug_struct("RwLock");
+        match self.try_read() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
+impl<T: Default, F: Futex> Default for RwLock<T, F> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized, F: Futex = SysFutex> {
+    lock: &'a RwLock<T, F>,
+}
+
+impl<T: ?Sized, F: Futex> Deref for RwLockReadGuard<'_, T, F> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+impl<T: ?Sized, F: Futex> Drop for RwLockReadGuard<'_, T, F> {
+    fn drop(&mut self) {
+        if self.lock.state.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.lock.wake_waiters();
+        }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized, F: Futex = SysFutex> {
+    lock: &'a RwLock<T, F>,
+}
+
+impl<T: ?Sized, F: Futex> Deref for RwLockWriteGuard<'_, T, F> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+impl<T: ?Sized, F: Futex> DerefMut for RwLockWriteGuard<'_, T, F> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+impl<T: ?Sized, F: Futex> Drop for RwLockWriteGuard<'_, T, F> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::SeqCst);
+        self.lock.wake_waiters();
+    }
+}
+
+// Once states
+const INCOMPLETE: u32 = 0;
+const RUNNING: u32 = 1;
+const RUNNING_WAITERS: u32 = 2;
+const COMPLETE: u32 = 3;
+
+/// Runs an initialization function exactly once.
+///
+/// If the function panics, the `Once` is reset so that a later call can try again.
+pub struct Once<F: Futex = SysFutex> {
+    state: AtomicU32,
+    _futex: PhantomData<fn() -> F>,
+}
+
+impl<F: Futex> Once<F> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(INCOMPLETE),
+            _futex: PhantomData,
+        }
+    }
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+    /// Call `f` if no other call has completed yet, and otherwise block until it has.
+    pub fn call_once(&self, f: impl FnOnce()) {
+        let mut f = Some(f);
+        loop {
+            match self.state.compare_exchange(
+                INCOMPLETE,
+                RUNNING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    let reset = ResetOnUnwind(self);
+                    (f.take().expect("only runs once"))();
+                    core::mem::forget(reset);
+                    self.finish(COMPLETE);
+                    return;
+                }
+                Err(COMPLETE) => return,
+                Err(RUNNING) => {
+                    // Tell the running thread it has to wake us, unless it just finished.
+                    let _ = self.state.compare_exchange(
+                        RUNNING,
+                        RUNNING_WAITERS,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    );
+                }
+                Err(RUNNING_WAITERS) => {
+                    let _ = F::wait(&self.state, RUNNING_WAITERS, None);
+                }
+                Err(_) => unreachable!(),
+            }
+        }
+    }
+    fn finish(&self, state: u32) {
+        if self.state.swap(state, Ordering::Release) == RUNNING_WAITERS {
+            F::wake(&self.state, u32::MAX);
+        }
+    }
+}
+
+struct ResetOnUnwind<'a, F: Futex>(&'a Once<F>);
+
+impl<F: Futex> Drop for ResetOnUnwind<'_, F> {
+    fn drop(&mut self) {
+        self.0.finish(INCOMPLETE);
+    }
+}
+
+impl<F: Futex> Default for Once<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: Futex> fmt::Debug for Once<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Once")
+            .field("completed", &self.is_completed())
+            .finish()
+    }
+}
+
+/// A counting semaphore.
+pub struct Semaphore<F: Futex = SysFutex> {
+    count: AtomicU32,
+    waiters: AtomicU32,
+    _futex: PhantomData<fn() -> F>,
+}
+
+impl<F: Futex> Semaphore<F> {
+    pub const fn new(count: u32) -> Self {
+        Self {
+            count: AtomicU32::new(count),
+            waiters: AtomicU32::new(0),
+            _futex: PhantomData,
+        }
+    }
+    /// Decrement the count, blocking while it is zero.
+    pub fn acquire(&self) {
+        while !self.try_acquire() {
+            // SeqCst pairs with `release`, see RwLock::wait_while.
+            self.waiters.fetch_add(1, Ordering::SeqCst);
+            let _ = F::wait(&self.count, 0, None);
+            self.waiters.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+    /// Increment the count, waking up a blocked thread if there is one.
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) != 0 {
+            F::wake(&self.count, 1);
+        }
+    }
+    pub fn count(&self) -> u32 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+impl<F: Futex> fmt::Debug for Semaphore<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("count", &self.count())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{atomic::Ordering, Arc},
+        thread,
+        time::{Duration, Instant},
+        vec::Vec,
+    };
+
+    use super::{Futex, Mutex};
+    use crate::{
+        data::TimeSpec,
+        error::{Error, Result, ETIMEDOUT},
+    };
+    use core::sync::atomic::{AtomicU32, AtomicUsize};
+
+    /// Wait queues keyed by futex word address, emulating futexes well enough that lost wakeups
+    /// hang the tests. Waiters only return early when their own word is woken, so tests running
+    /// in parallel don't wake each other.
+    struct FakeFutex;
+
+    /// The number of wakes of each word so far.
+    static WAKES: std::sync::Mutex<BTreeMap<usize, u64>> = std::sync::Mutex::new(BTreeMap::new());
+    static WOKEN: std::sync::Condvar = std::sync::Condvar::new();
+
+    impl Futex for FakeFutex {
+        fn wait(word: &AtomicU32, expected: u32, timeout: Option<&TimeSpec>) -> Result<()> {
+            let address = word as *const AtomicU32 as usize;
+            let mut wakes = WAKES.lock().unwrap();
+            if word.load(Ordering::SeqCst) != expected {
+                return Ok(());
+            }
+            let seen = wakes.get(&address).copied().unwrap_or(0);
+            let deadline = timeout
+                .map(|timeout| Instant::now() + Duration::from_nanos(timeout.to_nanos() as u64));
+            while wakes.get(&address).copied().unwrap_or(0) == seen {
+                wakes = match deadline {
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return Err(Error::new(ETIMEDOUT));
+                        }
+                        WOKEN.wait_timeout(wakes, deadline - now).unwrap().0
+                    }
+                    None => WOKEN.wait(wakes).unwrap(),
+                };
+            }
+            Ok(())
+        }
+        fn wake(word: &AtomicU32, _count: u32) {
+            let address = word as *const AtomicU32 as usize;
+            *WAKES.lock().unwrap().entry(address).or_default() += 1;
+            WOKEN.notify_all();
+        }
+    }
+
+    type Condvar = super::Condvar<FakeFutex>;
+    type Once = super::Once<FakeFutex>;
+    type RwLock<T> = super::RwLock<T, FakeFutex>;
+    type Semaphore = super::Semaphore<FakeFutex>;
+
+    fn spawn_n(n: usize, f: impl Fn(usize) + Send + Sync + 'static) {
+        let f = Arc::new(f);
+        let threads: Vec<_> = (0..n)
+            .map(|i| {
+                let f = Arc::clone(&f);
+                thread::spawn(move || f(i))
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn mutex() {
+        let counter = Arc::new(Mutex::<usize, FakeFutex>::new(0));
+        let c = Arc::clone(&counter);
+        spawn_n(4, move |_| {
+            for _ in 0..1000 {
+                *c.lock() += 1;
+            }
+        });
+        assert_eq!(*counter.lock(), 4000);
+        let _guard = counter.lock();
+        assert!(counter.try_lock().is_none());
+    }
+
+    #[test]
+    fn condvar() {
+        let pair = Arc::new((Mutex::<bool, FakeFutex>::new(false), Condvar::new()));
+        let p = Arc::clone(&pair);
+        let waiter = thread::spawn(move || {
+            let mut ready = p.0.lock();
+            while !*ready {
+                ready = p.1.wait(ready);
+            }
+        });
+        *pair.0.lock() = true;
+        pair.1.notify_all();
+        waiter.join().unwrap();
+
+        let guard = pair.0.lock();
+        let (_guard, timed_out) = pair.1.wait_timeout(guard, &TimeSpec::from_nanos(1_000_000));
+        assert!(timed_out);
+    }
+
+    #[test]
+    fn rwlock() {
+        let lock = Arc::new(RwLock::new(0_usize));
+        let l = Arc::clone(&lock);
+        spawn_n(4, move |i| {
+            for _ in 0..500 {
+                if i % 2 == 0 {
+                    *l.write() += 1;
+                } else {
+                    let value = *l.read();
+                    assert!(value <= 1000);
+                }
+            }
+        });
+        assert_eq!(*lock.read(), 1000);
+        let _read = lock.read();
+        assert!(lock.try_read().is_some());
+        assert!(lock.try_write().is_none());
+    }
+
+    #[test]
+    fn once() {
+        let once = Arc::new(Once::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (o, c) = (Arc::clone(&once), Arc::clone(&calls));
+        spawn_n(8, move |_| {
+            o.call_once(|| {
+                thread::sleep(Duration::from_millis(10));
+                c.fetch_add(1, Ordering::Relaxed);
+            });
+            assert!(o.is_completed());
+        });
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn semaphore() {
+        let sem = Arc::new(Semaphore::new(2));
+        let inside = Arc::new(AtomicUsize::new(0));
+        let (s, n) = (Arc::clone(&sem), Arc::clone(&inside));
+        spawn_n(6, move |_| {
+            for _ in 0..50 {
+                s.acquire();
+                assert!(n.fetch_add(1, Ordering::SeqCst) < 2);
+                thread::yield_now();
+                n.fetch_sub(1, Ordering::SeqCst);
+                s.release();
+            }
+        });
+        assert_eq!(sem.count(), 2);
+    }
+}
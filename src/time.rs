@@ -0,0 +1,182 @@
+use core::{
+    ops::{Add, AddAssign, Sub, SubAssign},
+    time::Duration,
+};
+
+use crate::{
+    call,
+    data::TimeSpec,
+    error::{Error, Result, EINTR},
+    flag::{CLOCK_MONOTONIC, CLOCK_REALTIME},
+};
+
+/// A point in time on `CLOCK_MONOTONIC`, which never goes backwards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+
+impl Instant {
+    pub fn now() -> Result<Self> {
+        clock_gettime(CLOCK_MONOTONIC).map(Self)
+    }
+    /// Time elapsed since `self`, or zero if `self` is in the future.
+    pub fn elapsed(&self) -> Result<Duration> {
+        Ok(Self::now()?.duration_since(*self))
+    }
+    /// Time elapsed from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration).map(Self)
+    }
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration).map(Self)
+    }
+    /// The value `CLOCK_MONOTONIC` had at this instant.
+    pub fn to_timespec(&self) -> TimeSpec {
+        TimeSpec::try_from(self.0).unwrap_or(TimeSpec::MAX)
+    }
+}
+
+/// A point in time on `CLOCK_REALTIME`, which can jump when the system time is changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(Duration);
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::ZERO);
+
+    pub fn now() -> Result<Self> {
+        clock_gettime(CLOCK_REALTIME).map(Self)
+    }
+    /// Time elapsed since `self`, or zero if the clock is now before `self`.
+    pub fn elapsed(&self) -> Result<Duration> {
+        Ok(Self::now()?.0.saturating_sub(self.0))
+    }
+    /// Time elapsed from `earlier` to `self`, or `None` if `earlier` is later.
+    pub fn duration_since(&self, earlier: SystemTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+    pub fn checked_add(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_add(duration).map(Self)
+    }
+    pub fn checked_sub(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_sub(duration).map(Self)
+    }
+    /// The value `CLOCK_REALTIME` had at this time.
+    pub fn to_timespec(&self) -> TimeSpec {
+        TimeSpec::try_from(self.0).unwrap_or(TimeSpec::MAX)
+    }
+}
+
+macro_rules! ops {
+    ($($ty:ident),*) => {$(
+        impl Add<Duration> for $ty {
+            type Output = $ty;
+            fn add(self, rhs: Duration) -> $ty {
+                self.checked_add(rhs).expect("overflow when adding duration to time")
+            }
+        }
+        impl AddAssign<Duration> for $ty {
+            fn add_assign(&mut self, rhs: Duration) {
+                *self = *self + rhs;
+            }
+        }
+        impl Sub<Duration> for $ty {
+            type Output = $ty;
+            fn sub(self, rhs: Duration) -> $ty {
+                self.checked_sub(rhs).expect("overflow when subtracting duration from time")
+            }
+        }
+        impl SubAssign<Duration> for $ty {
+            fn sub_assign(&mut self, rhs: Duration) {
+                *self = *self - rhs;
+            }
+        }
+    )*};
+}
+ops!(Instant, SystemTime);
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Sleep for `duration`, restarting with the remaining time when interrupted with `EINTR`.
+pub fn sleep(duration: Duration) -> Result<()> {
+    let mut req = TimeSpec::try_from(duration).unwrap_or(TimeSpec::MAX);
+    loop {
+        let mut rem = TimeSpec::default();
+        match call::nanosleep(&req, &mut rem) {
+            Ok(_) => return Ok(()),
+            Err(Error { errno: EINTR }) => req = rem,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Sleep until `deadline`, returning immediately if it has already passed.
+pub fn sleep_until(deadline: Instant) -> Result<()> {
+    match deadline.checked_duration_since(Instant::now()?) {
+        Some(duration) if !duration.is_zero() => sleep(duration),
+        _ => Ok(()),
+    }
+}
+
+fn clock_gettime(clock: usize) -> Result<Duration> {
+    let mut tp = TimeSpec::default();
+    call::clock_gettime(clock, &mut tp)?;
+    // Neither clock can be before its epoch.
+    Ok(Duration::try_from(tp).unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+    use std::rc::Rc;
+
+    use super::{sleep, sleep_until, Instant, SystemTime};
+    use crate::{backend::*, data::TimeSpec, number::SYS_NANOSLEEP};
+
+    #[test]
+    fn clocks_and_sleep() {
+        let kernel = Rc::new(FakeKernel::new());
+        kernel.set_realtime(TimeSpec {
+            tv_sec: 1_700_000_000,
+            tv_nsec: 0,
+        });
+
+        with_backend(kernel.clone(), || {
+            let start = Instant::now().unwrap();
+            let wall = SystemTime::now().unwrap();
+            assert_eq!(
+                wall.duration_since(SystemTime::UNIX_EPOCH),
+                Some(Duration::from_secs(1_700_000_000))
+            );
+
+            sleep(Duration::from_millis(1500)).unwrap();
+            assert_eq!(start.elapsed(), Ok(Duration::from_millis(1500)));
+            assert_eq!(wall.elapsed(), Ok(Duration::from_millis(1500)));
+
+            // Interrupted sleeps continue with the remaining time.
+            kernel.interrupt_sleeps(2);
+            let deadline = start + Duration::from_secs(3);
+            sleep_until(deadline).unwrap();
+            assert_eq!(Instant::now(), Ok(deadline));
+            sleep_until(start).unwrap();
+
+            assert_eq!(deadline - start, Duration::from_secs(3));
+            assert_eq!(start - deadline, Duration::ZERO);
+            assert_eq!(start.checked_sub(Duration::from_nanos(1)), None);
+            assert_eq!(wall.duration_since(wall + Duration::from_secs(1)), None);
+        });
+
+        let sleeps = kernel.records();
+        let sleeps = sleeps.iter().filter(|r| r.number == SYS_NANOSLEEP);
+        assert_eq!(sleeps.count(), 4);
+    }
+}
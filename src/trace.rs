@@ -0,0 +1,525 @@
+use core::{fmt, mem, ptr};
+
+use bitflags::Flags;
+
+use crate::{
+    data::{Map, TimeSpec},
+    error::{Error, Result},
+    flag::*,
+    number::SyscallNumber,
+};
+
+/// Access to the memory of the traced process, used to show arguments passed by pointer.
+pub trait TraceMemory {
+    /// Fill `buf` with the bytes at `addr`, returning `false` if they could not be read.
+    fn read(&self, addr: usize, buf: &mut [u8]) -> bool;
+}
+
+/// Reads the memory of the current process.
+pub struct LocalMemory(());
+
+impl LocalMemory {
+    /// # Safety
+    ///
+    /// This must only be used to format system calls whose pointer arguments are valid.
+    pub unsafe fn new() -> Self {
+        Self(())
+    }
+}
+
+impl TraceMemory for LocalMemory {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> bool {
+        if addr == 0 {
+            return false;
+        }
+        unsafe { ptr::copy_nonoverlapping(addr as *const u8, buf.as_mut_ptr(), buf.len()) };
+        true
+    }
+}
+
+/// Paths and buffers longer than this are truncated.
+const MAX_STRING: usize = 128;
+
+/// A system call, displayed like `openat_into(3, "file:/etc/passwd", O_RDONLY, 0, 7) = 7`.
+///
+/// Without a result (for example at a pre-syscall ptrace stop), the `= ...` part is left out.
+/// Without a [`TraceMemory`], arguments passed by pointer are shown as addresses.
+#[derive(Clone, Copy)]
+pub struct SyscallDisplay<'a> {
+    number: usize,
+    args: [usize; 6],
+    result: Option<Result<usize>>,
+    memory: Option<&'a dyn TraceMemory>,
+}
+
+impl<'a> SyscallDisplay<'a> {
+    /// `result` is the return value after [`Error::demux`], if the call has returned.
+    pub fn new(number: usize, args: [usize; 6], result: Option<Result<usize>>) -> Self {
+        Self {
+            number,
+            args,
+            result,
+            memory: None,
+        }
+    }
+    pub fn with_memory(self, memory: &'a dyn TraceMemory) -> Self {
+        Self {
+            memory: Some(memory),
+            ..self
+        }
+    }
+
+    fn read<T: Copy>(&self, addr: usize) -> Option<T> {
+        let mut buf = [0_u8; 64];
+        let buf = buf.get_mut(..mem::size_of::<T>())?;
+        if !self.memory?.read(addr, buf) {
+            return None;
+        }
+        // Only used for plain structs where any bit pattern is valid.
+        Some(unsafe { ptr::read_unaligned(buf.as_ptr().cast::<T>()) })
+    }
+}
+
+struct Args<'f, 'b> {
+    f: &'f mut fmt::Formatter<'b>,
+    first: bool,
+}
+
+impl Args<'_, '_> {
+    fn arg(&mut self, arg: impl fmt::Display) -> fmt::Result {
+        if !self.first {
+            self.f.write_str(", ")?;
+        }
+        self.first = false;
+        write!(self.f, "{}", arg)
+    }
+}
+
+/// Formats with a closure, since most arguments need more than one `write!`.
+struct Show<F: Fn(&mut fmt::Formatter) -> fmt::Result>(F);
+
+impl<F: Fn(&mut fmt::Formatter) -> fmt::Result> fmt::Display for Show<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (self.0)(f)
+    }
+}
+
+fn fd(raw: usize) -> impl fmt::Display {
+    Show(move |f| {
+        if raw & UPPER_FDTBL_TAG != 0 {
+            write!(f, "upper({})", raw & !UPPER_FDTBL_TAG)
+        } else {
+            write!(f, "{}", raw)
+        }
+    })
+}
+
+fn hex(raw: usize) -> impl fmt::Display {
+    Show(move |f| write!(f, "{:#x}", raw))
+}
+
+/// Write `names` joined by `|`, followed by any bits they did not cover, or `0` if empty.
+fn write_names<'n>(
+    f: &mut fmt::Formatter,
+    names: impl Iterator<Item = &'n str>,
+    rest: usize,
+) -> fmt::Result {
+    let mut first = true;
+    for name in names {
+        if !first {
+            f.write_str("|")?;
+        }
+        first = false;
+        f.write_str(name)?;
+    }
+    match (first, rest) {
+        (true, 0) => f.write_str("0"),
+        (_, 0) => Ok(()),
+        (true, rest) => write!(f, "{:#x}", rest),
+        (false, rest) => write!(f, "|{:#x}", rest),
+    }
+}
+
+fn flags<F: Flags<Bits = usize>>(bits: usize) -> impl fmt::Display {
+    Show(move |f| {
+        let flags = F::from_bits_retain(bits);
+        let covered = flags
+            .iter_names()
+            .fold(0, |covered, (_, flag)| covered | flag.bits());
+        write_names(f, flags.iter_names().map(|(name, _)| name), bits & !covered)
+    })
+}
+
+fn oflags(bits: usize) -> impl fmt::Display {
+    const FLAGS: &[(&str, usize)] = &[
+        ("O_NONBLOCK", O_NONBLOCK),
+        ("O_APPEND", O_APPEND),
+        ("O_SHLOCK", O_SHLOCK),
+        ("O_EXLOCK", O_EXLOCK),
+        ("O_ASYNC", O_ASYNC),
+        ("O_FSYNC", O_FSYNC),
+        ("O_CREAT", O_CREAT),
+        ("O_TRUNC", O_TRUNC),
+        ("O_EXCL", O_EXCL),
+        ("O_DIRECTORY", O_DIRECTORY),
+        ("O_STAT", O_STAT),
+        ("O_SYMLINK", O_SYMLINK),
+        ("O_NOFOLLOW", O_NOFOLLOW),
+    ];
+    Show(move |f| {
+        let accmode = match bits & O_ACCMODE {
+            O_RDONLY => Some("O_RDONLY"),
+            O_WRONLY => Some("O_WRONLY"),
+            O_RDWR => Some("O_RDWR"),
+            _ => None,
+        };
+        let names = FLAGS
+            .iter()
+            .filter(|&&(_, flag)| bits & flag == flag)
+            .map(|&(name, _)| name);
+        let rest = FLAGS
+            .iter()
+            .fold(bits & !O_ACCMODE, |rest, &(_, flag)| rest & !flag);
+        write_names(f, accmode.into_iter().chain(names), rest)
+    })
+}
+
+fn constant(bits: usize, names: &'static [(&'static str, usize)]) -> impl fmt::Display {
+    Show(
+        move |f| match names.iter().find(|&&(_, value)| value == bits) {
+            Some((name, _)) => f.write_str(name),
+            None => write!(f, "{}", bits),
+        },
+    )
+}
+
+impl SyscallDisplay<'_> {
+    /// A string or byte buffer, shown escaped if it can be read, and as `addr[len]` otherwise.
+    fn string(&self, addr: usize, len: usize) -> impl fmt::Display + '_ {
+        Show(move |f| {
+            let mut buf = [0_u8; MAX_STRING];
+            let shown = len.min(MAX_STRING);
+            match self.memory {
+                Some(memory) if memory.read(addr, &mut buf[..shown]) => {
+                    f.write_str("\"")?;
+                    for chunk in buf[..shown].utf8_chunks() {
+                        write!(f, "{}", chunk.valid().escape_debug())?;
+                        for byte in chunk.invalid() {
+                            write!(f, "\\x{:02x}", byte)?;
+                        }
+                    }
+                    f.write_str("\"")?;
+                    if shown < len {
+                        f.write_str("...")?;
+                    }
+                    Ok(())
+                }
+                _ => write!(f, "{:#x}[{}]", addr, len),
+            }
+        })
+    }
+    fn map(&self, addr: usize) -> impl fmt::Display + '_ {
+        Show(move |f| match self.read::<Map>(addr) {
+            Some(map) => write!(
+                f,
+                "{{offset: {:#x}, size: {:#x}, flags: {}, address: {:#x}}}",
+                map.offset,
+                map.size,
+                flags::<MapFlags>(map.flags.bits()),
+                map.address
+            ),
+            None => write!(f, "{:#x}", addr),
+        })
+    }
+    fn timespec(&self, addr: usize) -> impl fmt::Display + '_ {
+        Show(move |f| match self.read::<TimeSpec>(addr) {
+            Some(ts) => write!(f, "{{tv_sec: {}, tv_nsec: {}}}", ts.tv_sec, ts.tv_nsec),
+            None => write!(f, "{:#x}", addr),
+        })
+    }
+
+    fn write_args(&self, number: SyscallNumber, a: &mut Args) -> fmt::Result {
+        use SyscallNumber::*;
+
+        let [b, c, d, e, f, g] = self.args;
+        match number {
+            OpenatInto => {
+                a.arg(fd(b))?;
+                a.arg(self.string(c, d))?;
+                a.arg(oflags(e))?;
+                a.arg(oflags(f))?;
+                a.arg(fd(g))
+            }
+            Unlinkat => {
+                a.arg(fd(b))?;
+                a.arg(self.string(c, d))?;
+                a.arg(constant(e, &[("AT_REMOVEDIR", AT_REMOVEDIR)]))
+            }
+            Close | Fsync => a.arg(fd(b)),
+            DupInto => {
+                a.arg(fd(b))?;
+                a.arg(self.string(c, d))?;
+                a.arg(fd(e))
+            }
+            Dup2 => {
+                a.arg(fd(b))?;
+                a.arg(fd(c))?;
+                a.arg(self.string(d, e))
+            }
+            Read | Fpath | Fstat => {
+                a.arg(fd(b))?;
+                a.arg(hex(c))?;
+                a.arg(d)
+            }
+            Write => {
+                a.arg(fd(b))?;
+                a.arg(self.string(c, d))?;
+                a.arg(d)
+            }
+            Read2 | Write2 => {
+                a.arg(fd(b))?;
+                if number == Write2 {
+                    a.arg(self.string(c, d))?;
+                } else {
+                    a.arg(hex(c))?;
+                }
+                a.arg(d)?;
+                a.arg(e)?;
+                a.arg(Show(move |fmt| {
+                    let flags = RwFlags::from_bits_retain(f as u32);
+                    let rest = flags.bits() & !RwFlags::all().bits();
+                    write_names(fmt, flags.iter_names().map(|(name, _)| name), rest as usize)
+                }))
+            }
+            Lseek => {
+                a.arg(fd(b))?;
+                a.arg(c as isize)?;
+                a.arg(constant(
+                    d,
+                    &[
+                        ("SEEK_SET", SEEK_SET),
+                        ("SEEK_CUR", SEEK_CUR),
+                        ("SEEK_END", SEEK_END),
+                    ],
+                ))
+            }
+            Fchown => {
+                a.arg(fd(b))?;
+                a.arg(c as u32)?;
+                a.arg(d as u32)
+            }
+            Fcntl => {
+                a.arg(fd(b))?;
+                a.arg(constant(
+                    c,
+                    &[
+                        ("F_DUPFD", F_DUPFD),
+                        ("F_GETFD", F_GETFD),
+                        ("F_SETFD", F_SETFD),
+                        ("F_GETFL", F_GETFL),
+                        ("F_SETFL", F_SETFL),
+                    ],
+                ))?;
+                if c == F_SETFL {
+                    a.arg(oflags(d))
+                } else {
+                    a.arg(d)
+                }
+            }
+            Fevent => {
+                a.arg(fd(b))?;
+                a.arg(flags::<EventFlags>(c))
+            }
+            Call => {
+                // The low bits of the flags argument hold the metadata length.
+                let call_flags = CallFlags::from_bits_retain(e & !0xFF);
+                if call_flags.contains(CallFlags::MULTIPLE_FDS) {
+                    // With several fds, the last argument is the byte length of the fd array.
+                    let count = g / mem::size_of::<usize>();
+                    a.arg(Show(move |fmt| write!(fmt, "{:#x}[{}]", b, count)))?;
+                } else {
+                    a.arg(fd(b))?;
+                }
+                a.arg(hex(c))?;
+                a.arg(d)?;
+                a.arg(flags::<CallFlags>(call_flags.bits()))?;
+                a.arg(Show(move |fmt| write!(fmt, "{:#x}[{}]", f, e & 0xFF)))
+            }
+            Fmap => {
+                a.arg(fd(b))?;
+                a.arg(self.map(c))
+            }
+            Funmap => {
+                a.arg(hex(b))?;
+                a.arg(hex(c))
+            }
+            Mremap => {
+                a.arg(hex(b))?;
+                a.arg(hex(c))?;
+                a.arg(hex(d))?;
+                a.arg(hex(e))?;
+                a.arg(flags::<MremapFlags>(f))
+            }
+            Flink | Frename => {
+                a.arg(fd(b))?;
+                a.arg(self.string(c, d))
+            }
+            ClockGettime => {
+                a.arg(constant(
+                    b,
+                    &[
+                        ("CLOCK_REALTIME", CLOCK_REALTIME),
+                        ("CLOCK_MONOTONIC", CLOCK_MONOTONIC),
+                    ],
+                ))?;
+                a.arg(hex(c))
+            }
+            Futex => {
+                a.arg(hex(b))?;
+                a.arg(constant(
+                    c,
+                    &[
+                        ("FUTEX_WAIT", FUTEX_WAIT),
+                        ("FUTEX_WAKE", FUTEX_WAKE),
+                        ("FUTEX_REQUEUE", FUTEX_REQUEUE),
+                        ("FUTEX_WAIT64", FUTEX_WAIT64),
+                    ],
+                ))?;
+                a.arg(d as i32)?;
+                a.arg(hex(e))?;
+                a.arg(hex(f))
+            }
+            Mprotect => {
+                a.arg(hex(b))?;
+                a.arg(hex(c))?;
+                a.arg(flags::<MapFlags>(d))
+            }
+            Nanosleep => {
+                a.arg(self.timespec(b))?;
+                a.arg(hex(c))
+            }
+            Yield => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for SyscallDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let number = SyscallNumber::try_from_raw(self.number);
+        match number {
+            Some(number) => write!(f, "{}(", number.name())?,
+            None => write!(f, "syscall_{:#x}(", self.number)?,
+        }
+        let mut args = Args { f, first: true };
+        match number {
+            Some(number) => self.write_args(number, &mut args)?,
+            None => {
+                for arg in self.args {
+                    args.arg(hex(arg))?;
+                }
+            }
+        }
+        f.write_str(")")?;
+
+        match self.result {
+            None => Ok(()),
+            Some(Err(Error { errno })) => match (Error { errno }).name() {
+                Some(name) => write!(f, " = Err({})", name),
+                None => write!(f, " = Err({})", errno),
+            },
+            Some(Ok(value)) => match number {
+                Some(SyscallNumber::Fmap | SyscallNumber::Mremap) => write!(f, " = {:#x}", value),
+                _ => write!(f, " = {}", value),
+            },
+        }
+    }
+}
+
+impl fmt::Debug for SyscallDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::format;
+
+    use super::{SyscallDisplay, TraceMemory};
+    use crate::{error::*, flag::*, number::*};
+
+    /// Memory starting at address 0x1000.
+    struct Slice<'a>(&'a [u8]);
+
+    impl TraceMemory for Slice<'_> {
+        fn read(&self, addr: usize, buf: &mut [u8]) -> bool {
+            let Some(src) = addr
+                .checked_sub(0x1000)
+                .and_then(|start| self.0.get(start..start + buf.len()))
+            else {
+                return false;
+            };
+            buf.copy_from_slice(src);
+            true
+        }
+    }
+
+    #[test]
+    fn format() {
+        let memory = Slice(b"file:/etc/passwd");
+        let open = SyscallDisplay::new(
+            SYS_OPENAT_INTO,
+            [3, 0x1000, 16, O_RDONLY | O_NOFOLLOW, 0, 7],
+            Some(Ok(7)),
+        );
+        assert_eq!(
+            format!("{}", open.with_memory(&memory)),
+            r#"openat_into(3, "file:/etc/passwd", O_RDONLY|O_NOFOLLOW, 0, 7) = 7"#
+        );
+        assert_eq!(
+            format!("{}", open),
+            "openat_into(3, 0x1000[16], O_RDONLY|O_NOFOLLOW, 0, 7) = 7"
+        );
+
+        let read = SyscallDisplay::new(
+            SYS_READ,
+            [4, 0x2000, 512, 0, 0, 0],
+            Some(Err(Error::new(EAGAIN))),
+        );
+        assert_eq!(format!("{}", read), "read(4, 0x2000, 512) = Err(EAGAIN)");
+
+        let mprotect = SyscallDisplay::new(
+            SYS_MPROTECT,
+            [
+                0x4000,
+                0x1000,
+                (PROT_READ | PROT_WRITE).bits() | 0x100,
+                0,
+                0,
+                0,
+            ],
+            None,
+        );
+        assert_eq!(
+            format!("{}", mprotect),
+            "mprotect(0x4000, 0x1000, PROT_WRITE|PROT_READ|0x100)"
+        );
+
+        let call = SyscallDisplay::new(
+            SYS_CALL,
+            [
+                0x3000,
+                0x5000,
+                8,
+                CallFlags::MULTIPLE_FDS.bits() | 2,
+                0x6000,
+                3 * size_of::<usize>(),
+            ],
+            Some(Ok(0)),
+        );
+        assert_eq!(
+            format!("{}", call),
+            "call(0x3000[3], 0x5000, 8, MULTIPLE_FDS, 0x6000[2]) = 0"
+        );
+    }
+}